@@ -14,6 +14,8 @@ use crossterm::{
 
 #[cfg(target_os = "windows")]
 use crossterm::event::KeyEventKind;
+#[cfg(not(target_os = "windows"))]
+use crossterm::event::{DisableBracketedPaste, EnableBracketedPaste};
 use ratatui::{
     backend::{Backend, CrosstermBackend},
     layout::{Alignment, Constraint, Direction, Layout, Rect},
@@ -24,13 +26,15 @@ use ratatui::{
 };
 use ropey::Rope;
 use std::{
+    collections::BTreeMap,
     env,
     error::Error,
     fs,
     io,
-    path::PathBuf,
-    time::{Duration, Instant},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use unicode_segmentation::UnicodeSegmentation;
 use unicode_width::UnicodeWidthStr;
 
 #[derive(Debug, Clone, Copy)]
@@ -40,6 +44,1059 @@ struct VisualLine {
     is_continuation: bool,
     indent: usize,
     logical_line: usize,
+    /// Lines folded away under this row, set on the last row of a fold's
+    /// header line.
+    folded_lines: usize,
+}
+
+/// A folded region: the header line stays visible as a summary row and the
+/// lines after it, through `end`, are hidden. All positions are bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Fold {
+    start: usize,
+    header_end: usize,
+    end: usize,
+}
+
+/// A secondary caret with its own selection, used for multi-cursor editing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Cursor {
+    caret: usize,
+    anchor: Option<usize>,
+    preferred_col: usize,
+}
+
+impl Cursor {
+    fn start(&self) -> usize {
+        self.anchor.map_or(self.caret, |anchor| anchor.min(self.caret))
+    }
+
+    fn end(&self) -> usize {
+        self.anchor.map_or(self.caret, |anchor| anchor.max(self.caret))
+    }
+}
+
+/// Display width of the grapheme cluster `g` when it starts at visual column
+/// `col`. Tabs advance to the next tab stop, invisible characters take the
+/// width of the marker drawn for them, and everything else uses its Unicode
+/// width.
+fn grapheme_display_width(g: &str, col: usize, tab_width: usize) -> usize {
+    if g == "\t" {
+        let tab_width = tab_width.max(1);
+        tab_width - (col % tab_width)
+    } else if let Some(symbol) = invisible_char_symbol(g) {
+        symbol.width()
+    } else {
+        g.width()
+    }
+}
+
+/// Marker drawn in place of a zero-width or bidi control character, which
+/// would otherwise be invisible or silently reorder the text around it.
+fn invisible_char_symbol(g: &str) -> Option<&'static str> {
+    match g.chars().next()? {
+        '\u{200e}' | '\u{200f}' | '\u{061c}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}' => Some("⇄"),
+        '\u{feff}' | '\u{200b}' | '\u{200c}' | '\u{200d}' | '\u{2060}' | '\u{00ad}' | '\u{180e}' => Some("▫"),
+        _ => None,
+    }
+}
+
+/// Spaces that look like a plain space but are not one.
+fn is_confusable_space(g: &str) -> bool {
+    matches!(
+        g.chars().next(),
+        Some('\u{00a0}' | '\u{1680}' | '\u{2000}'..='\u{200a}' | '\u{202f}' | '\u{205f}' | '\u{3000}')
+    )
+}
+
+/// Byte offset of the grapheme cluster boundary before `pos` in `text`.
+fn prev_grapheme_boundary(text: &str, pos: usize) -> usize {
+    text[..pos].grapheme_indices(true).next_back().map_or(0, |(idx, _)| idx)
+}
+
+/// Byte offset of the grapheme cluster boundary after `pos` in `text`.
+fn next_grapheme_boundary(text: &str, pos: usize) -> usize {
+    text[pos..].graphemes(true).next().map_or(text.len(), |g| pos + g.len())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CharClass {
+    Whitespace,
+    Word,
+    Punctuation,
+}
+
+fn grapheme_class(g: &str) -> CharClass {
+    match g.chars().next() {
+        Some(ch) if ch.is_whitespace() => CharClass::Whitespace,
+        Some(ch) if ch.is_alphanumeric() || ch == '_' => CharClass::Word,
+        Some(_) => CharClass::Punctuation,
+        None => CharClass::Whitespace,
+    }
+}
+
+fn is_upper_grapheme(g: &str) -> bool {
+    g.chars().next().is_some_and(char::is_uppercase)
+}
+
+/// Lowercase letters and digits that continue a camelCase hump.
+fn is_subword_tail(g: &str) -> bool {
+    grapheme_class(g) == CharClass::Word && g != "_" && !is_upper_grapheme(g)
+}
+
+/// Gaps skipped before a word motion; subword motion also treats `_` as a gap.
+fn is_word_gap(g: &str, subword: bool) -> bool {
+    grapheme_class(g) == CharClass::Whitespace || (subword && g == "_")
+}
+
+/// Start of the word (or camelCase/snake_case subword) before `pos` in `text`.
+fn prev_word_boundary(text: &str, pos: usize, subword: bool) -> usize {
+    let graphemes: Vec<(usize, &str)> = text[..pos].grapheme_indices(true).collect();
+    let mut i = graphemes.len();
+    
+    while i > 0 && is_word_gap(graphemes[i - 1].1, subword) {
+        i -= 1;
+    }
+    if i == 0 {
+        return 0;
+    }
+    
+    let class = grapheme_class(graphemes[i - 1].1);
+    if subword && class == CharClass::Word {
+        let tail_end = i;
+        while i > 0 && is_subword_tail(graphemes[i - 1].1) {
+            i -= 1;
+        }
+        if i < tail_end {
+            // Include the capital that starts a camelCase hump
+            if i > 0 && is_upper_grapheme(graphemes[i - 1].1) {
+                i -= 1;
+            }
+        } else {
+            while i > 0 && is_upper_grapheme(graphemes[i - 1].1) {
+                i -= 1;
+            }
+        }
+    } else {
+        while i > 0 && grapheme_class(graphemes[i - 1].1) == class {
+            i -= 1;
+        }
+    }
+    
+    graphemes.get(i).map_or(pos, |(idx, _)| *idx)
+}
+
+/// End of the word (or camelCase/snake_case subword) after `pos` in `text`.
+fn next_word_boundary(text: &str, pos: usize, subword: bool) -> usize {
+    let graphemes: Vec<(usize, &str)> = text[pos..].grapheme_indices(true).collect();
+    let mut i = 0;
+    
+    while i < graphemes.len() && is_word_gap(graphemes[i].1, subword) {
+        i += 1;
+    }
+    if i == graphemes.len() {
+        return text.len();
+    }
+    
+    let class = grapheme_class(graphemes[i].1);
+    if subword && class == CharClass::Word {
+        let upper_start = i;
+        while i < graphemes.len() && is_upper_grapheme(graphemes[i].1) {
+            i += 1;
+        }
+        if i - upper_start > 1 && i < graphemes.len() && is_subword_tail(graphemes[i].1) {
+            // Acronym followed by a word ("HTMLParser"): stop before the last capital
+            i -= 1;
+        } else {
+            while i < graphemes.len() && is_subword_tail(graphemes[i].1) {
+                i += 1;
+            }
+        }
+    } else {
+        while i < graphemes.len() && grapheme_class(graphemes[i].1) == class {
+            i += 1;
+        }
+    }
+    
+    graphemes.get(i).map_or(text.len(), |(idx, _)| pos + idx)
+}
+
+/// Byte range of the run of same-class graphemes around `pos` in `text`.
+fn word_range_at(text: &str, pos: usize) -> (usize, usize) {
+    let graphemes: Vec<(usize, &str)> = text.grapheme_indices(true).collect();
+    let hit = graphemes
+        .iter()
+        .position(|(idx, g)| pos < idx + g.len())
+        .or_else(|| graphemes.len().checked_sub(1));
+    
+    match hit {
+        Some(hit) => {
+            let class = grapheme_class(graphemes[hit].1);
+            let mut start = hit;
+            while start > 0 && grapheme_class(graphemes[start - 1].1) == class {
+                start -= 1;
+            }
+            let mut end = hit + 1;
+            while end < graphemes.len() && grapheme_class(graphemes[end].1) == class {
+                end += 1;
+            }
+            let (last_idx, last) = graphemes[end - 1];
+            (graphemes[start].0, last_idx + last.len())
+        }
+        None => (pos, pos),
+    }
+}
+
+/// Display width of a line's indentation.
+fn indent_width(text: &str, tab_width: usize) -> usize {
+    text[..text.len() - text.trim_start().len()]
+        .graphemes(true)
+        .fold(0, |col, g| col + grapheme_display_width(g, col, tab_width))
+}
+
+/// Byte offset where a line's indentation reaches display column `col`,
+/// or where it ends if it is narrower. A tab across the column stays
+/// after the offset.
+fn indent_byte_at_col(text: &str, col: usize, tab_width: usize) -> usize {
+    let indent = &text[..text.len() - text.trim_start().len()];
+    let mut width = 0;
+    for (idx, g) in indent.grapheme_indices(true) {
+        width += grapheme_display_width(g, width, tab_width);
+        if width > col {
+            return idx;
+        }
+    }
+    indent.len()
+}
+
+/// Length of the label of an item like "3. ", "b) " or "Note. ": letters
+/// or digits followed by `.` or `)` and a space.
+fn list_label_len(trimmed: &str) -> Option<usize> {
+    let label_len = trimmed.chars().take_while(|ch| ch.is_alphanumeric()).map(char::len_utf8).sum::<usize>();
+    let after = &trimmed[label_len..];
+    (label_len > 0 && (after.starts_with(". ") || after.starts_with(") "))).then_some(label_len)
+}
+
+/// Splits a list item (with its leading indentation already trimmed) into
+/// its marker, including the trailing space, and the marker the next item
+/// should start with. Numbered and lettered items are incremented and
+/// checked boxes come back unchecked.
+fn list_marker(trimmed: &str) -> Option<(&str, String)> {
+    for bullet in ["- ", "* ", "+ "] {
+        if let Some(rest) = trimmed.strip_prefix(bullet) {
+            for checkbox in ["[ ] ", "[x] ", "[X] "] {
+                if rest.starts_with(checkbox) {
+                    return Some((&trimmed[..bullet.len() + checkbox.len()], format!("{}[ ] ", bullet)));
+                }
+            }
+            return Some((bullet, bullet.to_string()));
+        }
+    }
+    for checkbox in ["▢ ", "◪ ", "■ "] {
+        if trimmed.starts_with(checkbox) {
+            return Some((checkbox, "▢ ".to_string()));
+        }
+    }
+    
+    let label_len = list_label_len(trimmed)?;
+    let label = &trimmed[..label_len];
+    let delimiter = trimmed[label_len..].chars().next()?;
+    let next_label = if !label.is_empty() && label.len() <= 9 && label.bytes().all(|b| b.is_ascii_digit()) {
+        (label.parse::<u64>().ok()? + 1).to_string()
+    } else if label.len() == 1 && label.as_bytes()[0].is_ascii_alphabetic() {
+        let letter = label.as_bytes()[0];
+        if letter.eq_ignore_ascii_case(&b'z') { label.to_string() } else { ((letter + 1) as char).to_string() }
+    } else {
+        return None;
+    };
+    Some((&trimmed[..label_len + 2], format!("{}{} ", next_label, delimiter)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TaskState {
+    Open,
+    InProgress,
+    Done,
+}
+
+/// Locates the checkbox on a task-list line: the byte range of the box
+/// itself (`▢`, `◪`, `■`, or the `[ ]` of a Markdown task) and its state.
+fn task_checkbox(line: &str) -> Option<(usize, usize, TaskState)> {
+    let indent = line.len() - line.trim_start().len();
+    let trimmed = &line[indent..];
+    let ends_box = |rest: &str| rest.is_empty() || rest.starts_with(' ');
+    
+    for (glyph, state) in [("▢", TaskState::Open), ("◪", TaskState::InProgress), ("■", TaskState::Done)] {
+        if trimmed.starts_with(glyph) && ends_box(&trimmed[glyph.len()..]) {
+            return Some((indent, indent + glyph.len(), state));
+        }
+    }
+    for bullet in ["- ", "* ", "+ "] {
+        if let Some(rest) = trimmed.strip_prefix(bullet) {
+            let state = match rest.get(..3) {
+                Some("[ ]") => TaskState::Open,
+                Some("[x]") | Some("[X]") => TaskState::Done,
+                _ => return None,
+            };
+            if !ends_box(&rest[3..]) {
+                return None;
+            }
+            let start = indent + bullet.len();
+            return Some((start, start + 3, state));
+        }
+    }
+    None
+}
+
+/// Line endings that open a nested block in most languages.
+const BRACKET_OPENERS: &[&str] = &["{", "[", "("];
+
+/// Pairs typed together in most languages.
+const ALL_AUTO_PAIRS: &[(char, char)] = &[('(', ')'), ('[', ']'), ('{', '}'), ('"', '"'), ('\'', '\''), ('`', '`')];
+
+/// Pairs for languages where single quotes are lifetimes or quoting and
+/// backticks mean nothing.
+const CODE_AUTO_PAIRS: &[(char, char)] = &[('(', ')'), ('[', ']'), ('{', '}'), ('"', '"')];
+
+/// Line endings that open a nested block for the given file extension, so
+/// pressing Enter after them indents one level deeper.
+fn indent_openers(extension: &str) -> &'static [&'static str] {
+    match language_for(extension) {
+        Some(language) => language.indent_openers,
+        None if matches!(extension, "md" | "markdown" | "txt" | "text") => &[],
+        None => BRACKET_OPENERS,
+    }
+}
+
+/// Characters that get their closing partner typed automatically for the
+/// given file extension. Prose leaves single quotes alone, as they are
+/// mostly apostrophes.
+fn auto_pairs(extension: &str) -> &'static [(char, char)] {
+    match language_for(extension) {
+        Some(language) => language.auto_pairs,
+        None if matches!(extension, "md" | "markdown" | "txt" | "text" | "") => &[('(', ')'), ('[', ']'), ('{', '}'), ('"', '"'), ('`', '`')],
+        None => ALL_AUTO_PAIRS,
+    }
+}
+
+/// Syntax of a file type, as far as bracket matching, commenting, auto
+/// pairs and indentation need to know it.
+#[derive(Debug, Clone, Copy)]
+struct Language {
+    line_comment: Option<&'static str>,
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+    /// `'` starts a character literal only when one closes it right after
+    /// the character, and a lifetime or label otherwise
+    char_literals: bool,
+    /// `<` and `>` delimit tags rather than compare values
+    angle_brackets: bool,
+    auto_pairs: &'static [(char, char)],
+    /// Line endings after which Enter indents one level deeper
+    indent_openers: &'static [&'static str],
+}
+
+fn language_for(extension: &str) -> Option<Language> {
+    let c_like = Language {
+        line_comment: Some("//"),
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\''],
+        char_literals: false,
+        angle_brackets: false,
+        auto_pairs: ALL_AUTO_PAIRS,
+        indent_openers: BRACKET_OPENERS,
+    };
+    let hash = Language {
+        line_comment: Some("#"),
+        block_comment: None,
+        quotes: &['"', '\''],
+        char_literals: false,
+        angle_brackets: false,
+        auto_pairs: ALL_AUTO_PAIRS,
+        indent_openers: BRACKET_OPENERS,
+    };
+    let markup = Language {
+        line_comment: None,
+        block_comment: Some(("<!--", "-->")),
+        quotes: &[],
+        char_literals: false,
+        angle_brackets: true,
+        auto_pairs: &[('(', ')'), ('[', ']'), ('{', '}'), ('"', '"'), ('\'', '\'')],
+        indent_openers: BRACKET_OPENERS,
+    };
+    let language = match extension {
+        "c" | "h" | "cc" | "cpp" | "cxx" | "hh" | "hpp" | "java" | "cs" | "go" | "kt" | "kts" | "swift" | "scala" | "dart" => c_like,
+        "rs" => Language { quotes: &['"'], char_literals: true, auto_pairs: CODE_AUTO_PAIRS, ..c_like },
+        "js" | "jsx" | "mjs" | "cjs" | "ts" | "tsx" => Language { quotes: &['"', '\'', '`'], ..c_like },
+        "css" | "scss" | "less" => Language { line_comment: None, ..c_like },
+        "py" | "pyw" | "pyi" => Language { indent_openers: &[":", "(", "[", "{"], ..hash },
+        "yaml" | "yml" => Language { indent_openers: &[":", "[", "{"], ..hash },
+        "rb" => Language { indent_openers: &["do", "(", "[", "{"], ..hash },
+        "sh" | "bash" | "zsh" => Language { indent_openers: &["then", "do", "{", "("], ..hash },
+        "pl" | "r" | "fish" | "toml" | "nix" => hash,
+        "lua" => Language { line_comment: Some("--"), block_comment: Some(("--[[", "]]")), indent_openers: &["then", "do", "(", "{"], ..hash },
+        "sql" => Language { line_comment: Some("--"), block_comment: Some(("/*", "*/")), quotes: &['\''], ..hash },
+        "hs" => Language { line_comment: Some("--"), block_comment: Some(("{-", "-}")), quotes: &['"'], ..hash },
+        "clj" | "el" | "lisp" | "scm" => Language { line_comment: Some(";"), quotes: &['"'], auto_pairs: CODE_AUTO_PAIRS, ..hash },
+        "tex" => Language { line_comment: Some("%"), quotes: &[], ..hash },
+        "ini" => Language { line_comment: Some(";"), quotes: &[], ..hash },
+        "html" | "htm" | "xhtml" | "xml" | "svg" => markup,
+        "vue" => Language { auto_pairs: ALL_AUTO_PAIRS, ..markup },
+        _ => return None,
+    };
+    Some(language)
+}
+
+/// A bracket outside strings and comments, with the byte position of its
+/// partner if it has one.
+#[derive(Debug, Clone, Copy)]
+struct BracketMark {
+    pos: usize,
+    ch: char,
+    partner: Option<usize>,
+    /// Whether a missing partner is worth pointing out: only in known
+    /// languages, where angle brackets outside markup are comparisons.
+    structural: bool,
+}
+
+fn bracket_partner(ch: char) -> Option<(char, bool)> {
+    match ch {
+        '(' => Some((')', true)),
+        '[' => Some((']', true)),
+        '{' => Some(('}', true)),
+        '<' => Some(('>', true)),
+        ')' => Some(('(', false)),
+        ']' => Some(('[', false)),
+        '}' => Some(('{', false)),
+        '>' => Some(('<', false)),
+        _ => None,
+    }
+}
+
+/// Finds every bracket in `text` and pairs them up, skipping strings and
+/// comments when the language is known. Angle brackets are paired among
+/// themselves so a stray comparison cannot unbalance the others.
+/// Length of the character literal at the start of `rest`, such as `'{'`
+/// or `'\u{7d}'`, or `None` for a lifetime like `'a`.
+fn char_literal_len(rest: &str) -> Option<usize> {
+    let mut chars = rest.char_indices().skip(1);
+    let (_, first) = chars.next()?;
+    if first == '\\' {
+        chars.next();
+        let end = rest.find('\n').unwrap_or(rest.len());
+        return chars.take_while(|(offset, _)| *offset < end).find(|(_, ch)| *ch == '\'').map(|(offset, _)| offset + 1);
+    }
+    let (offset, close) = chars.next()?;
+    (first != '\n' && first != '\'' && close == '\'').then_some(offset + 1)
+}
+
+fn scan_brackets(text: &str, language: Option<&Language>) -> Vec<BracketMark> {
+    let mut marks: Vec<BracketMark> = Vec::new();
+    let mut stack: Vec<usize> = Vec::new();
+    let mut angle_stack: Vec<usize> = Vec::new();
+    let mut idx = 0;
+    while idx < text.len() {
+        let rest = &text[idx..];
+        let ch = rest.chars().next().unwrap_or_default();
+        if let Some(language) = language {
+            if let Some((open, close)) = language.block_comment.filter(|(open, _)| rest.starts_with(open)) {
+                idx += open.len() + rest[open.len()..].find(close).map_or(rest.len() - open.len(), |end| end + close.len());
+                continue;
+            }
+            if language.line_comment.is_some_and(|token| rest.starts_with(token)) {
+                idx += rest.find('\n').unwrap_or(rest.len());
+                continue;
+            }
+            if language.char_literals && ch == '\'' {
+                idx += char_literal_len(rest).unwrap_or(1);
+                continue;
+            }
+            if language.quotes.contains(&ch) {
+                // Strings end at the closing quote or, unterminated, at the line end
+                let mut chars = rest.char_indices().skip(1);
+                let mut end = rest.len();
+                while let Some((offset, next)) = chars.next() {
+                    if next == '\\' {
+                        chars.next();
+                    } else if next == ch || next == '\n' {
+                        end = offset + next.len_utf8();
+                        break;
+                    }
+                }
+                idx += end;
+                continue;
+            }
+        }
+        
+        if let Some((_, is_open)) = bracket_partner(ch) {
+            let structural = language.is_some_and(|language| language.angle_brackets || (ch != '<' && ch != '>'));
+            let stack = if ch == '<' || ch == '>' { &mut angle_stack } else { &mut stack };
+            if is_open {
+                stack.push(marks.len());
+            } else if let Some(&open) = stack.last().filter(|&&open| bracket_partner(marks[open].ch).map(|(close, _)| close) == Some(ch)) {
+                stack.pop();
+                marks[open].partner = Some(idx);
+                marks.push(BracketMark { pos: idx, ch, partner: Some(marks[open].pos), structural });
+                idx += 1;
+                continue;
+            }
+            marks.push(BracketMark { pos: idx, ch, partner: None, structural });
+        }
+        idx += ch.len_utf8();
+    }
+    marks
+}
+
+/// Orders the line commands can put lines in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LineOrder {
+    Sorted,
+    Numeric,
+    CaseInsensitive,
+    Natural,
+    Unique,
+    Reversed,
+    Shuffled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LineCommand {
+    Duplicate,
+    Delete,
+    Join,
+    StripTrailingWhitespace,
+    Reorder(LineOrder),
+}
+
+/// Entries of the line command panel, in display order.
+const LINE_COMMANDS: [(LineCommand, &str); 11] = [
+    (LineCommand::Reorder(LineOrder::Sorted), "Sort lines"),
+    (LineCommand::Reorder(LineOrder::Numeric), "Sort lines by number"),
+    (LineCommand::Reorder(LineOrder::CaseInsensitive), "Sort lines ignoring case"),
+    (LineCommand::Reorder(LineOrder::Natural), "Sort lines naturally (file2 before file10)"),
+    (LineCommand::Reorder(LineOrder::Unique), "Remove duplicate lines"),
+    (LineCommand::Reorder(LineOrder::Reversed), "Reverse lines"),
+    (LineCommand::Reorder(LineOrder::Shuffled), "Shuffle lines"),
+    (LineCommand::Duplicate, "Duplicate lines or selection (Ctrl+Shift+D)"),
+    (LineCommand::Delete, "Delete lines (Alt+D)"),
+    (LineCommand::Join, "Join lines (Ctrl+J)"),
+    (LineCommand::StripTrailingWhitespace, "Strip trailing whitespace (whole file without a selection)"),
+];
+
+/// Parses the number a line starts with, after its indentation, for
+/// numeric sorting.
+fn leading_number(line: &str) -> Option<f64> {
+    let text = line.trim_start();
+    let mut end = 0;
+    for (idx, ch) in text.char_indices() {
+        let numeric = ch.is_ascii_digit()
+            || (idx == 0 && (ch == '-' || ch == '+'))
+            || (ch == '.' && !text[..idx].contains('.'));
+        if !numeric {
+            break;
+        }
+        end = idx + ch.len_utf8();
+    }
+    // Back off a dangling sign or point
+    (1..=end).rev().find_map(|len| text[..len].parse().ok())
+}
+
+/// Compares runs of digits by value and everything else ignoring case,
+/// so that `file2` sorts before `file10`.
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+    use std::iter::Peekable;
+    use std::str::Chars;
+    
+    fn digit_run(chars: &mut Peekable<Chars>) -> String {
+        let mut run = String::new();
+        while let Some(&ch) = chars.peek() {
+            if !ch.is_ascii_digit() {
+                break;
+            }
+            run.push(ch);
+            chars.next();
+        }
+        run
+    }
+    
+    let (mut a_chars, mut b_chars) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        let order = match (a_chars.peek().copied(), b_chars.peek().copied()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x_run, y_run) = (digit_run(&mut a_chars), digit_run(&mut b_chars));
+                let (x_digits, y_digits) = (x_run.trim_start_matches('0'), y_run.trim_start_matches('0'));
+                x_digits.len().cmp(&y_digits.len()).then_with(|| x_digits.cmp(y_digits))
+            }
+            (Some(x), Some(y)) => {
+                a_chars.next();
+                b_chars.next();
+                x.to_lowercase().cmp(y.to_lowercase())
+            }
+        };
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+}
+
+/// Puts lines in the given order. Sorts are stable; lines without a
+/// number sort after the numbered ones.
+fn reorder_lines(lines: &mut Vec<String>, order: LineOrder) {
+    match order {
+        LineOrder::Sorted => lines.sort(),
+        LineOrder::Numeric => lines.sort_by(|a, b| match (leading_number(a), leading_number(b)) {
+            (Some(x), Some(y)) => x.partial_cmp(&y).unwrap_or(std::cmp::Ordering::Equal),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        }),
+        LineOrder::CaseInsensitive => lines.sort_by_cached_key(|line| line.to_lowercase()),
+        LineOrder::Natural => lines.sort_by(|a, b| natural_cmp(a, b)),
+        LineOrder::Unique => {
+            // Keep the first of each, wherever the repeats are
+            let mut seen = std::collections::HashSet::new();
+            lines.retain(|line| seen.insert(line.clone()));
+        }
+        LineOrder::Reversed => lines.reverse(),
+        LineOrder::Shuffled => {
+            let mut state = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+                | 1;
+            for idx in (1..lines.len()).rev() {
+                // xorshift64 is plenty for reordering lines
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                lines.swap(idx, (state % (idx as u64 + 1)) as usize);
+            }
+        }
+    }
+}
+
+/// Joins lines into one, replacing the trailing whitespace and
+/// indentation between them with a single space. Blank lines drop out.
+fn join_line_texts(lines: &[String]) -> String {
+    let mut joined = lines.first().map_or(String::new(), |line| line.trim_end().to_string());
+    for line in &lines[1..] {
+        let text = line.trim();
+        if text.is_empty() {
+            continue;
+        }
+        if !joined.trim_start().is_empty() {
+            joined.push(' ');
+        }
+        joined.push_str(text);
+    }
+    joined
+}
+
+/// Columns offered by the reflow column panel.
+const REFLOW_COLUMNS: [usize; 5] = [60, 72, 80, 100, 120];
+
+/// Markers reflow keeps in front of each line for a file type: the line
+/// comment of its language, or any common comment or quote marker when the
+/// language is unknown. Markdown leaves `#` out, as it starts headings.
+fn reflow_markers(extension: &str) -> Vec<&'static str> {
+    match language_for(extension) {
+        Some(language) => match language.line_comment {
+            Some("//") => vec!["///", "//!", "//"],
+            Some(token) => vec![token],
+            None => Vec::new(),
+        },
+        None if is_markdown(extension) => vec!["///", "//!", "//", ">"],
+        None => vec!["///", "//!", "//", "#", ">"],
+    }
+}
+
+fn is_markdown(extension: &str) -> bool {
+    matches!(extension, "md" | "markdown")
+}
+
+/// Marks the lines of Markdown fenced code blocks, fences included, which
+/// reflow leaves alone. A block opened with ``` or ~~~ runs to a fence of
+/// the same character at least as long, or to the end of the text.
+fn fenced_code_lines<S: AsRef<str>>(lines: impl IntoIterator<Item = S>) -> Vec<bool> {
+    let mut open: Option<(char, usize)> = None;
+    lines
+        .into_iter()
+        .map(|line| {
+            let line = line.as_ref().trim();
+            let fence_char = line.chars().next().filter(|ch| *ch == '`' || *ch == '~');
+            let fence_len = fence_char.map_or(0, |ch| line.chars().take_while(|c| *c == ch).count());
+            match open {
+                Some((ch, len)) => {
+                    if fence_char == Some(ch) && fence_len >= len && line.trim_start_matches(ch).is_empty() {
+                        open = None;
+                    }
+                }
+                None if fence_len >= 3 => open = fence_char.map(|ch| (ch, fence_len)),
+                None => return false,
+            }
+            true
+        })
+        .collect()
+}
+
+/// Splits a line into its reflow prefix (the indentation, then any of
+/// `markers` with the spaces after them) and the text after it.
+fn reflow_prefix<'a>(line: &'a str, markers: &[&str]) -> (&'a str, &'a str) {
+    let mut end = line.len() - line.trim_start().len();
+    loop {
+        let rest = &line[end..];
+        let marker = markers.iter().find(|marker| rest.starts_with(**marker));
+        let Some(marker) = marker else {
+            break;
+        };
+        let after = &rest[marker.len()..];
+        end += marker.len() + after.len() - after.trim_start().len();
+    }
+    (&line[..end], &line[end..])
+}
+
+/// Rewraps the lines of one paragraph to `column`. The first line keeps
+/// its prefix; the others take the second line's prefix, or the first
+/// one plus the hanging indent of a list item.
+fn reflow_lines(lines: &[String], column: usize, tab_width: usize, markers: &[&str]) -> Vec<String> {
+    let (first_prefix, first_body) = reflow_prefix(&lines[0], markers);
+    let continuation = match lines.get(1) {
+        Some(line) => reflow_prefix(line, markers).0.to_string(),
+        None => format!("{}{}", first_prefix, " ".repeat(Editor::calculate_indent(first_body, tab_width))),
+    };
+    let prefix_width = |prefix: &str| {
+        prefix.graphemes(true).fold(0, |col, g| col + grapheme_display_width(g, col, tab_width))
+    };
+    
+    let mut wrapped = Vec::new();
+    let mut current = first_prefix.to_string();
+    let mut width = prefix_width(first_prefix);
+    let mut has_words = false;
+    for word in lines.iter().flat_map(|line| reflow_prefix(line, markers).1.split_whitespace()) {
+        // A word longer than the column gets a line of its own
+        if has_words && width + 1 + word.width() > column {
+            wrapped.push(std::mem::replace(&mut current, continuation.clone()));
+            width = prefix_width(&continuation);
+            has_words = false;
+        }
+        if has_words {
+            current.push(' ');
+            width += 1;
+        }
+        current.push_str(word);
+        width += word.width();
+        has_words = true;
+    }
+    wrapped.push(current);
+    wrapped
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TextTransform {
+    Upper,
+    Lower,
+    Title,
+    Snake,
+    Camel,
+    Kebab,
+    Screaming,
+    Base64Encode,
+    Base64Decode,
+    UrlEncode,
+    UrlDecode,
+    JsonEscape,
+    JsonUnescape,
+    Decimal,
+    Hex,
+    Binary,
+    Octal,
+}
+
+/// Entries of the transform panel, in display order.
+const TEXT_TRANSFORMS: [(TextTransform, &str); 17] = [
+    (TextTransform::Upper, "UPPER CASE"),
+    (TextTransform::Lower, "lower case"),
+    (TextTransform::Title, "Title Case"),
+    (TextTransform::Snake, "snake_case"),
+    (TextTransform::Camel, "camelCase"),
+    (TextTransform::Kebab, "kebab-case"),
+    (TextTransform::Screaming, "SCREAMING_CASE"),
+    (TextTransform::Base64Encode, "Base64 encode"),
+    (TextTransform::Base64Decode, "Base64 decode"),
+    (TextTransform::UrlEncode, "URL encode"),
+    (TextTransform::UrlDecode, "URL decode"),
+    (TextTransform::JsonEscape, "JSON escape"),
+    (TextTransform::JsonUnescape, "JSON unescape"),
+    (TextTransform::Decimal, "Number to decimal"),
+    (TextTransform::Hex, "Number to hex (0x)"),
+    (TextTransform::Binary, "Number to binary (0b)"),
+    (TextTransform::Octal, "Number to octal (0o)"),
+];
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Splits an identifier or phrase into lowercase words at spaces,
+/// punctuation and camelCase humps: `parseHTTPRequest2` gives `parse`,
+/// `http` and `request2`.
+fn split_words(text: &str) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for (idx, &ch) in chars.iter().enumerate() {
+        if !ch.is_alphanumeric() {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+        let prev = idx.checked_sub(1).map(|prev_idx| chars[prev_idx]);
+        let next = chars.get(idx + 1).copied();
+        let hump = ch.is_uppercase()
+            && prev.is_some_and(|prev| {
+                prev.is_lowercase()
+                    || prev.is_numeric()
+                    || (prev.is_uppercase() && next.is_some_and(char::is_lowercase))
+            });
+        if hump && !word.is_empty() {
+            words.push(std::mem::take(&mut word));
+        }
+        word.extend(ch.to_lowercase());
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars.flat_map(char::to_lowercase)).collect(),
+        None => String::new(),
+    }
+}
+
+/// Rewrites each line as an identifier in the given case, keeping the
+/// line's surrounding whitespace.
+fn convert_identifier_case(text: &str, transform: TextTransform) -> String {
+    text.split('\n')
+        .map(|line| {
+            let body = line.trim();
+            let words = split_words(body);
+            if words.is_empty() {
+                return line.to_string();
+            }
+            let converted = match transform {
+                TextTransform::Snake => words.join("_"),
+                TextTransform::Kebab => words.join("-"),
+                TextTransform::Screaming => words.join("_").to_uppercase(),
+                _ => words
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, word)| if idx == 0 { word.clone() } else { capitalize(word) })
+                    .collect(),
+            };
+            let lead = line.len() - line.trim_start().len();
+            format!("{}{}{}", &line[..lead], converted, &line[lead + body.len()..])
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let triple = chunk.iter().enumerate().fold(0u32, |acc, (idx, &byte)| acc | (byte as u32) << (16 - 8 * idx));
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(triple >> (18 - 6 * idx) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decodes standard or URL-safe Base64, with or without padding.
+/// Whitespace is ignored.
+fn base64_decode(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for ch in text.trim_end_matches(|ch: char| ch == '=' || ch.is_whitespace()).chars() {
+        let value = match ch {
+            'A'..='Z' => ch as u32 - 'A' as u32,
+            'a'..='z' => ch as u32 - 'a' as u32 + 26,
+            '0'..='9' => ch as u32 - '0' as u32 + 52,
+            '+' | '-' => 62,
+            '/' | '_' => 63,
+            _ if ch.is_whitespace() => continue,
+            _ => return Err(format!("Not Base64: unexpected '{}'", ch)),
+        };
+        buffer = (buffer << 6) | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    if bits >= 6 {
+        return Err("Not Base64: truncated input".to_string());
+    }
+    Ok(bytes)
+}
+
+/// Percent-encodes everything but the unreserved characters of RFC 3986.
+fn url_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn url_decode(text: &str) -> Result<String, String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::new();
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'%' {
+            let value = text.get(idx + 1..idx + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            match value {
+                Some(value) => decoded.push(value),
+                None => return Err("Not URL-encoded: bad % escape".to_string()),
+            }
+            idx += 3;
+        } else {
+            decoded.push(bytes[idx]);
+            idx += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| "Decoded text is not UTF-8".to_string())
+}
+
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for ch in text.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\u{8}' => escaped.push_str("\\b"),
+            '\u{c}' => escaped.push_str("\\f"),
+            _ if (ch as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", ch as u32)),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+fn json_unescape(text: &str) -> Result<String, String> {
+    fn code_unit(chars: &mut std::str::Chars) -> Option<u16> {
+        let hex: String = chars.take(4).collect();
+        u16::from_str_radix(&hex, 16).ok().filter(|_| hex.len() == 4)
+    }
+    
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            unescaped.push(ch);
+            continue;
+        }
+        let escape = chars.next().ok_or("Not JSON: dangling backslash")?;
+        unescaped.push(match escape {
+            '"' | '\\' | '/' => escape,
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'b' => '\u{8}',
+            'f' => '\u{c}',
+            'u' => {
+                let high = code_unit(&mut chars).ok_or("Not JSON: bad \\u escape")?;
+                let mut units = vec![high];
+                if (0xd800..0xdc00).contains(&high) {
+                    // A surrogate pair spells out one character
+                    if chars.next() != Some('\\') || chars.next() != Some('u') {
+                        return Err("Not JSON: unpaired surrogate".to_string());
+                    }
+                    units.push(code_unit(&mut chars).ok_or("Not JSON: bad \\u escape")?);
+                }
+                let decoded = String::from_utf16(&units).map_err(|_| "Not JSON: unpaired surrogate".to_string())?;
+                unescaped.push_str(&decoded);
+                continue;
+            }
+            _ => return Err(format!("Not JSON: unknown escape \\{}", escape)),
+        });
+    }
+    Ok(unescaped)
+}
+
+/// Reads an integer written in decimal or with a `0x`, `0b` or `0o`
+/// prefix, ignoring `_` separators.
+fn parse_integer(text: &str) -> Result<i128, String> {
+    let digits: String = text.trim().chars().filter(|&ch| ch != '_').collect();
+    let (negative, digits) = match digits.strip_prefix('-') {
+        Some(rest) => (true, rest.to_string()),
+        None => (false, digits.trim_start_matches('+').to_string()),
+    };
+    let lower = digits.to_lowercase();
+    let (radix, body) = match lower.get(..2) {
+        Some("0x") => (16, &lower[2..]),
+        Some("0b") => (2, &lower[2..]),
+        Some("0o") => (8, &lower[2..]),
+        _ => (10, lower.as_str()),
+    };
+    let value = u128::from_str_radix(body, radix)
+        .ok()
+        .and_then(|value| i128::try_from(value).ok())
+        .ok_or_else(|| format!("Not a number: {}", text.trim()))?;
+    Ok(if negative { -value } else { value })
+}
+
+fn format_integer(value: i128, radix: u32) -> String {
+    let sign = if value < 0 { "-" } else { "" };
+    let magnitude = value.unsigned_abs();
+    match radix {
+        16 => format!("{}0x{:x}", sign, magnitude),
+        2 => format!("{}0b{:b}", sign, magnitude),
+        8 => format!("{}0o{:o}", sign, magnitude),
+        _ => value.to_string(),
+    }
+}
+
+fn apply_text_transform(text: &str, transform: TextTransform) -> Result<String, String> {
+    Ok(match transform {
+        TextTransform::Upper => text.to_uppercase(),
+        TextTransform::Lower => text.to_lowercase(),
+        TextTransform::Title => text
+            .split_inclusive(char::is_whitespace)
+            .map(capitalize)
+            .collect(),
+        TextTransform::Snake | TextTransform::Camel | TextTransform::Kebab | TextTransform::Screaming => {
+            convert_identifier_case(text, transform)
+        }
+        TextTransform::Base64Encode => base64_encode(text.as_bytes()),
+        TextTransform::Base64Decode => {
+            String::from_utf8(base64_decode(text)?).map_err(|_| "Decoded text is not UTF-8".to_string())?
+        }
+        TextTransform::UrlEncode => url_encode(text),
+        TextTransform::UrlDecode => url_decode(text)?,
+        TextTransform::JsonEscape => json_escape(text),
+        TextTransform::JsonUnescape => json_unescape(text)?,
+        TextTransform::Decimal => format_integer(parse_integer(text)?, 10),
+        TextTransform::Hex => format_integer(parse_integer(text)?, 16),
+        TextTransform::Binary => format_integer(parse_integer(text)?, 2),
+        TextTransform::Octal => format_integer(parse_integer(text)?, 8),
+    })
 }
 
 #[derive(Clone, Debug)]
@@ -48,16 +1105,649 @@ enum EditOp {
     Delete { pos: usize, text: String },
 }
 
+/// What an edit looks like for undo grouping: typing or deleting a word
+/// character, whitespace, a line break, or anything larger.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EditClass {
+    Word,
+    Space,
+    Newline,
+    Bulk,
+}
+
+impl EditClass {
+    fn of(text: &str) -> Self {
+        let mut graphemes = text.graphemes(true);
+        match (graphemes.next(), graphemes.next()) {
+            _ if text.contains('\n') => EditClass::Newline,
+            (Some(g), None) if grapheme_class(g) == CharClass::Whitespace => EditClass::Space,
+            (Some(_), None) => EditClass::Word,
+            _ => EditClass::Bulk,
+        }
+    }
+}
+
+/// Whether an edit (insert or delete, and its class) may join the undo
+/// group of the previous one. Line breaks, bulk edits and switching
+/// between inserting and deleting always start a new group, and so does
+/// the first word character after whitespace, so each word plus its
+/// trailing space is undone on its own.
+fn continues_undo_group(previous: Option<(bool, EditClass)>, next: (bool, EditClass)) -> bool {
+    match (previous, next) {
+        (Some((was_insert, _)), (is_insert, _)) if was_insert != is_insert => false,
+        (Some((_, EditClass::Space)), (_, EditClass::Word)) => false,
+        (Some((_, EditClass::Word | EditClass::Space)), (_, EditClass::Word | EditClass::Space)) => true,
+        _ => false,
+    }
+}
+
+#[derive(Clone)]
 struct UndoGroup {
     ops: Vec<(EditOp, usize, usize)>,
-    timestamp: Instant,
+    timestamp: SystemTime,
+}
+
+#[derive(Clone)]
+struct UndoNode {
+    group: UndoGroup,
+    parent: usize,
+    children: Vec<usize>,
+    redo_child: Option<usize>,
+}
+
+/// Edit history kept as a tree: editing after an undo starts a new branch
+/// instead of throwing the undone changes away. Node 0 is the oldest
+/// state still known and holds no edits; nodes are numbered in the order
+/// they were created.
+#[derive(Clone)]
+struct UndoTree {
+    nodes: Vec<UndoNode>,
+    current: usize,
+}
+
+/// Largest undo history file kept per document; older history is dropped
+/// from the root side until it fits.
+const UNDO_FILE_CAP: usize = 1 << 20;
+/// Undo history files untouched for this long are deleted.
+const UNDO_MAX_AGE: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+/// Most undo history files kept in the state directory.
+const UNDO_MAX_FILES: usize = 200;
+/// Largest buffer whose contents are re-hashed on edits to notice that it
+/// matches the saved file again.
+const MODIFIED_HASH_LIMIT: usize = 1 << 20;
+/// Largest buffer scanned for matching brackets and for characters to
+/// flag on the scrollbar.
+const BRACKET_SCAN_LIMIT: usize = 1 << 20;
+/// Most copied or cut texts remembered in the clipboard history.
+const CLIPBOARD_HISTORY_MAX: usize = 50;
+/// Columns a no-wrap view moves per notch of a sideways wheel.
+const HORIZONTAL_SCROLL_STEP: usize = 6;
+
+impl UndoTree {
+    fn new() -> Self {
+        Self {
+            nodes: vec![UndoNode {
+                group: UndoGroup { ops: Vec::new(), timestamp: SystemTime::now() },
+                parent: 0,
+                children: Vec::new(),
+                redo_child: None,
+            }],
+            current: 0,
+        }
+    }
+
+    /// Adds a group as a child of the current state and makes it current.
+    fn push(&mut self, group: UndoGroup) {
+        let idx = self.nodes.len();
+        self.nodes.push(UndoNode {
+            group,
+            parent: self.current,
+            children: Vec::new(),
+            redo_child: None,
+        });
+        let parent = &mut self.nodes[self.current];
+        parent.children.push(idx);
+        parent.redo_child = Some(idx);
+        self.current = idx;
+    }
+
+    /// Node indices from the root down to `target`, both included.
+    fn path_to(&self, target: usize) -> Vec<usize> {
+        let mut path = vec![target];
+        let mut node = target;
+        while node != 0 {
+            node = self.nodes[node].parent;
+            path.push(node);
+        }
+        path.reverse();
+        path
+    }
+
+    /// The newest state created at or before `time`, or the root if every
+    /// edit is younger than that.
+    fn state_at(&self, time: SystemTime) -> usize {
+        self.nodes
+            .iter()
+            .rposition(|node| node.group.timestamp <= time)
+            .unwrap_or(0)
+    }
+
+    /// Every node in depth-first order with its branch depth: the first
+    /// child continues its parent's branch, later children fork off one
+    /// level deeper.
+    fn layout(&self) -> Vec<(usize, usize)> {
+        let mut entries = Vec::with_capacity(self.nodes.len());
+        let mut stack = vec![(0, 0)];
+        while let Some((node, depth)) = stack.pop() {
+            entries.push((node, depth));
+            for (i, &child) in self.nodes[node].children.iter().enumerate().rev() {
+                stack.push((child, if i == 0 { depth } else { depth + 1 }));
+            }
+        }
+        entries
+    }
+
+    /// Makes `new_root`, which must lie on the path to the current state,
+    /// the new origin: everything outside its subtree is dropped and its
+    /// own edits are forgotten.
+    fn rebase(&mut self, new_root: usize) {
+        let mut keep = vec![new_root];
+        let mut i = 0;
+        while i < keep.len() {
+            keep.extend(self.nodes[keep[i]].children.iter().copied());
+            i += 1;
+        }
+        keep.sort_unstable();
+        
+        let remap = |old: usize| keep.binary_search(&old).ok();
+        let mut nodes = Vec::with_capacity(keep.len());
+        for &old in &keep {
+            let node = &mut self.nodes[old];
+            nodes.push(UndoNode {
+                group: UndoGroup {
+                    ops: if old == new_root { Vec::new() } else { std::mem::take(&mut node.group.ops) },
+                    timestamp: node.group.timestamp,
+                },
+                parent: remap(node.parent).unwrap_or(0),
+                children: node.children.iter().filter_map(|&child| remap(child)).collect(),
+                redo_child: node.redo_child.and_then(remap),
+            });
+        }
+        self.current = remap(self.current).unwrap_or(0);
+        self.nodes = nodes;
+    }
+
+    /// Writes the tree in a line-based text format, one `node` line per
+    /// state followed by its `op` lines.
+    fn serialize(&self, path: &Path, hash: u64) -> String {
+        let mut out = format!(
+            "texteditor-undo 1\npath {}\nhash {:016x}\ncurrent {}\n",
+            escape_field(&path.to_string_lossy()),
+            hash,
+            self.current
+        );
+        for node in &self.nodes {
+            let millis = node.group.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+            let redo = node.redo_child.map_or("-".to_string(), |child| child.to_string());
+            out.push_str(&format!("node {} {} {}\n", node.parent, millis, redo));
+            for (op, before, after) in &node.group.ops {
+                let (kind, pos, text) = match op {
+                    EditOp::Insert { pos, text } => ('I', pos, text),
+                    EditOp::Delete { pos, text } => ('D', pos, text),
+                };
+                out.push_str(&format!("op {} {} {} {} {}\n", kind, pos, before, after, escape_field(text)));
+            }
+        }
+        out
+    }
+
+    /// Serializes the tree with as few of the oldest steps on the way to
+    /// the current state left out as it takes to fit in `cap` bytes. The
+    /// tree itself is left alone. Returns `None` when no edits are left
+    /// to store, or when what is left still does not fit.
+    fn serialize_capped(&self, path: &Path, hash: u64, cap: usize) -> Option<String> {
+        let data = self.serialize(path, hash);
+        if data.len() <= cap || self.current == 0 {
+            return (self.nodes.len() > 1 && data.len() <= cap).then_some(data);
+        }
+        
+        // Rebasing further along the path only ever shrinks the output,
+        // so the cut can be searched for instead of walked to
+        let path_to_current = self.path_to(self.current);
+        let rebased = |step: usize| {
+            let mut tree = self.clone();
+            tree.rebase(path_to_current[step]);
+            tree
+        };
+        let (mut lo, mut hi) = (1, path_to_current.len() - 1);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if rebased(mid).serialize(path, hash).len() <= cap {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+        let tree = rebased(lo);
+        let data = tree.serialize(path, hash);
+        (tree.nodes.len() > 1 && data.len() <= cap).then_some(data)
+    }
+
+    /// Parses a tree written by `serialize`, returning it with the stored
+    /// path and content hash. Any malformed line rejects the whole file.
+    fn deserialize(data: &str) -> Option<(Self, String, u64)> {
+        let mut lines = data.lines();
+        if lines.next()? != "texteditor-undo 1" {
+            return None;
+        }
+        let path = unescape_field(lines.next()?.strip_prefix("path ")?);
+        let hash = u64::from_str_radix(lines.next()?.strip_prefix("hash ")?, 16).ok()?;
+        let current: usize = lines.next()?.strip_prefix("current ")?.parse().ok()?;
+        
+        let mut nodes: Vec<UndoNode> = Vec::new();
+        for line in lines {
+            let (kind, rest) = line.split_once(' ')?;
+            match kind {
+                "node" => {
+                    let mut fields = rest.split(' ');
+                    let parent: usize = fields.next()?.parse().ok()?;
+                    let millis: u64 = fields.next()?.parse().ok()?;
+                    let redo_child = match fields.next()? {
+                        "-" => None,
+                        child => Some(child.parse().ok()?),
+                    };
+                    nodes.push(UndoNode {
+                        group: UndoGroup { ops: Vec::new(), timestamp: UNIX_EPOCH + Duration::from_millis(millis) },
+                        parent,
+                        children: Vec::new(),
+                        redo_child,
+                    });
+                }
+                "op" => {
+                    let mut fields = rest.splitn(5, ' ');
+                    let kind = fields.next()?;
+                    let pos: usize = fields.next()?.parse().ok()?;
+                    let before: usize = fields.next()?.parse().ok()?;
+                    let after: usize = fields.next()?.parse().ok()?;
+                    let text = unescape_field(fields.next()?);
+                    let op = match kind {
+                        "I" => EditOp::Insert { pos, text },
+                        "D" => EditOp::Delete { pos, text },
+                        _ => return None,
+                    };
+                    nodes.last_mut()?.group.ops.push((op, before, after));
+                }
+                _ => return None,
+            }
+        }
+        
+        // Children are rebuilt from the parent links
+        for idx in 1..nodes.len() {
+            let parent = nodes[idx].parent;
+            if parent >= idx {
+                return None;
+            }
+            nodes[parent].children.push(idx);
+        }
+        if current >= nodes.len() || nodes.iter().any(|node| node.redo_child.is_some_and(|child| child >= nodes.len())) {
+            return None;
+        }
+        Some((Self { nodes, current }, path, hash))
+    }
+}
+
+/// Escapes backslashes and line breaks so a value fits on one line.
+fn escape_field(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+fn unescape_field(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            out.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// 64-bit FNV-1a hash, stable across builds so stored hashes stay valid.
+fn content_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Directory holding the editor's persistent state, following the XDG
+/// state directory convention (or the local app data folder on Windows).
+fn state_dir() -> Option<PathBuf> {
+    let base = if cfg!(target_os = "windows") {
+        env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state")))
+    };
+    base.map(|dir| dir.join("texteditor"))
+}
+
+fn undo_state_dir() -> Option<PathBuf> {
+    state_dir().map(|dir| dir.join("undo"))
+}
+
+/// Clipboard history file. It only exists while keeping the history
+/// across sessions is switched on.
+fn clipboard_history_path() -> Option<PathBuf> {
+    state_dir().map(|dir| dir.join("clipboard"))
+}
+
+/// Reads the saved clipboard history, newest first. Returns `None` when
+/// the history is not being kept across sessions.
+fn load_clipboard_history() -> Option<Vec<String>> {
+    let text = fs::read_to_string(clipboard_history_path()?).ok()?;
+    let mut lines = text.lines();
+    if lines.next() != Some("texteditor-clipboard 1") {
+        return None;
+    }
+    Some(lines.map(unescape_field).take(CLIPBOARD_HISTORY_MAX).collect())
+}
+
+fn save_clipboard_history(history: &[String]) -> io::Result<()> {
+    let path = clipboard_history_path().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no state directory"))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut out = String::from("texteditor-clipboard 1\n");
+    for entry in history {
+        out.push_str(&escape_field(entry));
+        out.push('\n');
+    }
+    fs::write(path, out)
+}
+
+/// Removes undo history files that are too old, then the least recently
+/// written ones beyond `UNDO_MAX_FILES`.
+fn prune_undo_store(dir: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let now = SystemTime::now();
+    let mut files: Vec<(SystemTime, PathBuf)> = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("undo") {
+            continue;
+        }
+        let modified = entry.metadata().and_then(|meta| meta.modified()).unwrap_or(UNIX_EPOCH);
+        if now.duration_since(modified).unwrap_or_default() > UNDO_MAX_AGE {
+            let _ = fs::remove_file(&path);
+        } else {
+            files.push((modified, path));
+        }
+    }
+    if files.len() > UNDO_MAX_FILES {
+        files.sort();
+        for (_, path) in &files[..files.len() - UNDO_MAX_FILES] {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Short human-readable age of a history entry, e.g. "12s ago".
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+/// Parses an undo tree jump such as "earlier 5 minutes", "later 30s" or
+/// "-2h". Returns whether to move forward in time and by how much.
+fn parse_time_jump(input: &str) -> Option<(bool, Duration)> {
+    let input = input.trim().to_lowercase();
+    let (later, rest) = if let Some(rest) = input.strip_prefix("later") {
+        (true, rest)
+    } else if let Some(rest) = input.strip_prefix('+') {
+        (true, rest)
+    } else if let Some(rest) = input.strip_prefix("earlier") {
+        (false, rest)
+    } else {
+        (false, input.strip_prefix('-').unwrap_or(&input))
+    };
+    
+    let rest = rest.trim();
+    let digits = rest.len() - rest.trim_start_matches(|ch: char| ch.is_ascii_digit()).len();
+    let amount: u64 = rest[..digits].parse().ok()?;
+    let unit = match rest[digits..].trim().trim_end_matches('s') {
+        "" | "sec" | "second" => 1,
+        "m" | "min" | "minute" => 60,
+        "h" | "hour" => 3600,
+        "d" | "day" => 86400,
+        _ => return None,
+    };
+    Some((later, Duration::from_secs(amount * unit)))
+}
+
+/// Names of keys without a printable character, as written in macro text.
+const MACRO_KEY_NAMES: [(&str, KeyCode); 15] = [
+    ("Space", KeyCode::Char(' ')),
+    ("Enter", KeyCode::Enter),
+    ("Tab", KeyCode::Tab),
+    ("BackTab", KeyCode::BackTab),
+    ("Esc", KeyCode::Esc),
+    ("Backspace", KeyCode::Backspace),
+    ("Delete", KeyCode::Delete),
+    ("Left", KeyCode::Left),
+    ("Right", KeyCode::Right),
+    ("Up", KeyCode::Up),
+    ("Down", KeyCode::Down),
+    ("Home", KeyCode::Home),
+    ("End", KeyCode::End),
+    ("PageUp", KeyCode::PageUp),
+    ("PageDown", KeyCode::PageDown),
+];
+
+/// Upper bound on replays of a macro that runs until a find fails.
+const MACRO_MAX_REPEAT: usize = 10_000;
+
+/// Writes a key press as a macro token such as "x", "Ctrl+Shift+K" or
+/// "Enter". Shift is left implicit on plain characters.
+fn format_macro_key(key: &event::KeyEvent) -> Option<String> {
+    let name = match key.code {
+        KeyCode::F(n) => format!("F{}", n),
+        code => match MACRO_KEY_NAMES.iter().find(|(_, named)| *named == code) {
+            Some((name, _)) => name.to_string(),
+            None => match code {
+                KeyCode::Char(ch) => ch.to_string(),
+                _ => return None,
+            },
+        },
+    };
+    let mut token = String::new();
+    if key.modifiers.contains(event::KeyModifiers::CONTROL) {
+        token.push_str("Ctrl+");
+    }
+    if key.modifiers.contains(event::KeyModifiers::ALT) {
+        token.push_str("Alt+");
+    }
+    let plain_char = matches!(key.code, KeyCode::Char(_))
+        && !key.modifiers.intersects(event::KeyModifiers::CONTROL | event::KeyModifiers::ALT);
+    if key.modifiers.contains(event::KeyModifiers::SHIFT) && !plain_char {
+        token.push_str("Shift+");
+    }
+    token.push_str(&name);
+    Some(token)
+}
+
+/// Reads a macro token written by `format_macro_key`.
+fn parse_macro_key(token: &str) -> Option<event::KeyEvent> {
+    let mut modifiers = event::KeyModifiers::NONE;
+    let mut rest = token;
+    loop {
+        if let Some(after) = rest.strip_prefix("Ctrl+").filter(|after| !after.is_empty()) {
+            modifiers |= event::KeyModifiers::CONTROL;
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("Alt+").filter(|after| !after.is_empty()) {
+            modifiers |= event::KeyModifiers::ALT;
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("Shift+").filter(|after| !after.is_empty()) {
+            modifiers |= event::KeyModifiers::SHIFT;
+            rest = after;
+        } else {
+            break;
+        }
+    }
+    
+    let mut chars = rest.chars();
+    let code = if let (Some(mut ch), None) = (chars.next(), chars.next()) {
+        if !modifiers.intersects(event::KeyModifiers::CONTROL | event::KeyModifiers::ALT) {
+            // Typed capitals arrive with Shift held
+            if ch.is_uppercase() {
+                modifiers |= event::KeyModifiers::SHIFT;
+            }
+        } else if !modifiers.contains(event::KeyModifiers::SHIFT) {
+            // Terminals send Ctrl+F as Ctrl and a lowercase f
+            ch = ch.to_ascii_lowercase();
+        }
+        KeyCode::Char(ch)
+    } else if let Some(n) = rest.strip_prefix('F').and_then(|n| n.parse::<u8>().ok()) {
+        KeyCode::F(n)
+    } else {
+        MACRO_KEY_NAMES.iter().find(|(name, _)| *name == rest)?.1
+    };
+    Some(event::KeyEvent::new(code, modifiers))
+}
+
+/// Macro keys as space-separated tokens, the form macros are edited and
+/// stored in.
+fn format_macro_keys(keys: &[event::KeyEvent]) -> String {
+    keys.iter().filter_map(format_macro_key).collect::<Vec<_>>().join(" ")
+}
+
+fn parse_macro_keys(text: &str) -> Result<Vec<event::KeyEvent>, String> {
+    text.split_whitespace()
+        .map(|token| parse_macro_key(token).ok_or_else(|| format!("Unknown key: {}", token)))
+        .collect()
+}
+
+/// File holding saved macros, in the XDG config directory (or the roaming
+/// app data folder on Windows).
+fn macro_file_path() -> Option<PathBuf> {
+    let base = if cfg!(target_os = "windows") {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    };
+    base.map(|dir| dir.join("texteditor").join("macros"))
+}
+
+/// Loads saved macros, one "register = keys" line each. Lines that do not
+/// parse are skipped.
+fn load_macros() -> BTreeMap<char, Vec<event::KeyEvent>> {
+    let mut macros = BTreeMap::new();
+    let Some(text) = macro_file_path().and_then(|path| fs::read_to_string(path).ok()) else {
+        return macros;
+    };
+    for line in text.lines() {
+        if let Some((register, keys)) = parse_macro_definition(line) {
+            if let Ok(keys) = parse_macro_keys(keys) {
+                macros.insert(register, keys);
+            }
+        }
+    }
+    macros
+}
+
+fn save_macros(macros: &BTreeMap<char, Vec<event::KeyEvent>>) -> io::Result<()> {
+    let path = macro_file_path().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no config directory"))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut out = String::new();
+    for (register, keys) in macros {
+        out.push_str(&format!("{} = {}\n", register, format_macro_keys(keys)));
+    }
+    fs::write(path, out)
+}
+
+/// Macro registers are single letters or digits.
+fn is_macro_register(ch: char) -> bool {
+    ch.is_ascii_lowercase() || ch.is_ascii_digit()
+}
+
+/// Splits "a = keys" into its register and key text.
+fn parse_macro_definition(line: &str) -> Option<(char, &str)> {
+    let (register, keys) = line.split_once('=')?;
+    let mut chars = register.trim().chars();
+    match (chars.next(), chars.next()) {
+        (Some(ch), None) if is_macro_register(ch) => Some((ch, keys.trim())),
+        _ => None,
+    }
+}
+
+/// Parses a macro run such as "a", "a 5" or "a *". An empty input runs the
+/// selected register once. A repeat of `None` means until a find fails.
+fn parse_macro_run(input: &str, selected: Option<char>) -> Option<(char, Option<usize>)> {
+    let mut parts = input.split_whitespace();
+    let Some(register) = parts.next() else {
+        return selected.map(|register| (register, Some(1)));
+    };
+    let mut chars = register.chars();
+    let register = match (chars.next(), chars.next()) {
+        (Some(ch), None) if is_macro_register(ch) => ch,
+        _ => return None,
+    };
+    let repeat = match parts.next() {
+        None => Some(1),
+        Some("*") | Some("until") => None,
+        Some(count) => Some(count.parse().ok().filter(|count| *count > 0)?),
+    };
+    parts.next().is_none().then_some((register, repeat))
+}
+
+/// Macro panel entries, keyed by register.
+fn macro_entries(macros: &BTreeMap<char, Vec<event::KeyEvent>>) -> Vec<(usize, String)> {
+    macros
+        .iter()
+        .map(|(register, keys)| (*register as usize, format!("@{}  {}", register, format_macro_keys(keys))))
+        .collect()
 }
 
 #[derive(Debug, Clone)]
 enum PromptType {
     SaveAs,
     ConfirmSave,
+    ConfirmClose,
     FindReplace,
+    TaskList,
+    UndoTree,
+    Macros,
+    ClipboardHistory,
+    LineCommands,
+    Transforms,
+    ReflowColumn,
 }
 
 struct Prompt {
@@ -66,7 +1756,7 @@ struct Prompt {
     input: String,
     cursor_pos: usize,
     selection_anchor: Option<usize>,
-    clipboard: Clipboard,
+    clipboard: Option<Clipboard>,
     replace_input: String,
     replace_cursor_pos: usize,
     replace_selection_anchor: Option<usize>,
@@ -74,6 +1764,11 @@ struct Prompt {
     find_scroll_offset: usize,
     replace_scroll_offset: usize,
     save_as_scroll_offset: usize,
+    items: Vec<(usize, String)>,
+    selected_item: usize,
+    origin_state: usize,
+    /// Save As was asked for on closing a pane, which closes once saved
+    closes_pane: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -123,6 +1818,19 @@ impl Prompt {
         }
     }
 
+    fn set_active_selection_anchor(&mut self, anchor: Option<usize>) {
+        match self.prompt_type {
+            PromptType::FindReplace => {
+                match self.active_field {
+                    FindReplaceField::Find => self.selection_anchor = anchor,
+                    FindReplaceField::Replace => self.replace_selection_anchor = anchor,
+                    FindReplaceField::Buffer => {} // No-op when buffer focused
+                }
+            }
+            _ => self.selection_anchor = anchor,
+        }
+    }
+
     fn new_save_as(default_path: String) -> Self {
         let cursor_pos = default_path.len();
         Self {
@@ -131,7 +1839,7 @@ impl Prompt {
             input: default_path,
             cursor_pos,
             selection_anchor: None,
-            clipboard: Clipboard::new().unwrap(),
+            clipboard: Clipboard::new().ok(),
             replace_input: String::new(),
             replace_cursor_pos: 0,
             replace_selection_anchor: None,
@@ -139,6 +1847,10 @@ impl Prompt {
             find_scroll_offset: 0,
             replace_scroll_offset: 0,
             save_as_scroll_offset: 0,
+            items: Vec::new(),
+            selected_item: 0,
+            origin_state: 0,
+            closes_pane: false,
         }
     }
 
@@ -149,7 +1861,7 @@ impl Prompt {
             input: String::new(),
             cursor_pos: 0,
             selection_anchor: None,
-            clipboard: Clipboard::new().unwrap(),
+            clipboard: Clipboard::new().ok(),
             replace_input: String::new(),
             replace_cursor_pos: 0,
             replace_selection_anchor: None,
@@ -157,9 +1869,28 @@ impl Prompt {
             find_scroll_offset: 0,
             replace_scroll_offset: 0,
             save_as_scroll_offset: 0,
+            items: Vec::new(),
+            selected_item: 0,
+            origin_state: 0,
+            closes_pane: false,
         }
     }
 
+    /// Save As for an untitled buffer whose pane is being closed.
+    fn new_save_as_then_close(default_path: String) -> Self {
+        let mut prompt = Self::new_save_as(default_path);
+        prompt.message = "Save as, then close the pane:".to_string();
+        prompt.closes_pane = true;
+        prompt
+    }
+
+    fn new_confirm_close() -> Self {
+        let mut prompt = Self::new_confirm_save();
+        prompt.prompt_type = PromptType::ConfirmClose;
+        prompt.message = "Save changes before closing the pane? (y/n/c)".to_string();
+        prompt
+    }
+
     fn new_find_replace() -> Self {
         Self {
             prompt_type: PromptType::FindReplace,
@@ -167,7 +1898,7 @@ impl Prompt {
             input: String::new(),
             cursor_pos: 0,
             selection_anchor: None,
-            clipboard: Clipboard::new().unwrap(),
+            clipboard: Clipboard::new().ok(),
             replace_input: String::new(),
             replace_cursor_pos: 0,
             replace_selection_anchor: None,
@@ -175,6 +1906,132 @@ impl Prompt {
             find_scroll_offset: 0,
             replace_scroll_offset: 0,
             save_as_scroll_offset: 0,
+            items: Vec::new(),
+            selected_item: 0,
+            origin_state: 0,
+            closes_pane: false,
+        }
+    }
+
+    fn new_task_list(tasks: Vec<(usize, String)>) -> Self {
+        let mut prompt = Self::new_find_replace();
+        prompt.prompt_type = PromptType::TaskList;
+        prompt.message = "Open tasks (type to filter, Enter to jump):".to_string();
+        prompt.items = tasks;
+        prompt
+    }
+
+    /// Opens the undo tree panel on the given entries, remembering the
+    /// state to return to if the panel is cancelled.
+    fn new_undo_tree(entries: Vec<(usize, String)>, current_state: usize) -> Self {
+        let mut prompt = Self::new_find_replace();
+        prompt.prompt_type = PromptType::UndoTree;
+        prompt.message = "State, or time like \"earlier 5m\":".to_string();
+        prompt.selected_item = entries.iter().position(|(state, _)| *state == current_state).unwrap_or(0);
+        prompt.items = entries;
+        prompt.origin_state = current_state;
+        prompt
+    }
+
+    /// Lists the macro registers. Typing a register runs it, optionally
+    /// followed by a count or `*` to repeat until a find fails.
+    fn new_macros(entries: Vec<(usize, String)>) -> Self {
+        let mut prompt = Self::new_find_replace();
+        prompt.prompt_type = PromptType::Macros;
+        prompt.message = "Register [count|*] to run, or register = keys:".to_string();
+        prompt.items = entries;
+        prompt
+    }
+
+    /// Lists the clipboard history, newest first, for pasting an older
+    /// entry.
+    fn new_clipboard_history(history: &[String], persisted: bool) -> Self {
+        let mut prompt = Self::new_find_replace();
+        prompt.prompt_type = PromptType::ClipboardHistory;
+        prompt.set_clipboard_history_message(persisted);
+        prompt.items = history
+            .iter()
+            .enumerate()
+            .map(|(index, text)| (index, text.replace('\r', "").replace('\n', "⏎").replace('\t', "→")))
+            .collect();
+        prompt
+    }
+
+    /// Lists the line commands, which act on the selected lines.
+    fn new_line_commands() -> Self {
+        let mut prompt = Self::new_find_replace();
+        prompt.prompt_type = PromptType::LineCommands;
+        prompt.message = "Type to filter, Enter to run on the selected lines:".to_string();
+        prompt.items = LINE_COMMANDS
+            .iter()
+            .enumerate()
+            .map(|(index, (_, label))| (index, label.to_string()))
+            .collect();
+        prompt
+    }
+
+    /// Lists the text transforms, which act on the selection or the word
+    /// at the cursor.
+    fn new_transforms() -> Self {
+        let mut prompt = Self::new_find_replace();
+        prompt.prompt_type = PromptType::Transforms;
+        prompt.message = "Type to filter, Enter to transform the selection:".to_string();
+        prompt.items = TEXT_TRANSFORMS
+            .iter()
+            .enumerate()
+            .map(|(index, (_, label))| (index, label.to_string()))
+            .collect();
+        prompt
+    }
+
+    /// Offers common columns to reflow at, starting on the current one.
+    /// Any other column can be typed in.
+    fn new_reflow_column(current: usize) -> Self {
+        let mut prompt = Self::new_find_replace();
+        prompt.prompt_type = PromptType::ReflowColumn;
+        prompt.message = "Wrap at column (pick one or type a number):".to_string();
+        let mut columns = REFLOW_COLUMNS.to_vec();
+        if !columns.contains(&current) {
+            columns.push(current);
+            columns.sort_unstable();
+        }
+        prompt.selected_item = columns.iter().position(|&column| column == current).unwrap_or(0);
+        prompt.items = columns
+            .into_iter()
+            .map(|column| (column, format!("{} columns", column)))
+            .collect();
+        prompt
+    }
+
+    fn set_clipboard_history_message(&mut self, persisted: bool) {
+        self.message = format!(
+            "Type to filter, Enter to paste (Ctrl+P keep across sessions: {}):",
+            if persisted { "on" } else { "off" }
+        );
+    }
+
+    /// List entries shown by the prompt. Task lists and the clipboard
+    /// history are narrowed down to entries containing the typed filter,
+    /// ignoring case.
+    fn visible_items(&self) -> Vec<&(usize, String)> {
+        if !matches!(self.prompt_type, PromptType::TaskList | PromptType::ClipboardHistory | PromptType::LineCommands | PromptType::Transforms) {
+            return self.items.iter().collect();
+        }
+        let filter = self.input.to_lowercase();
+        self.items
+            .iter()
+            .filter(|(_, text)| text.to_lowercase().contains(&filter))
+            .collect()
+    }
+
+    fn move_item_selection(&mut self, down: bool) {
+        let count = self.visible_items().len();
+        if count == 0 {
+            self.selected_item = 0;
+        } else if down {
+            self.selected_item = (self.selected_item + 1).min(count - 1);
+        } else {
+            self.selected_item = self.selected_item.min(count - 1).saturating_sub(1);
         }
     }
 
@@ -306,10 +2163,7 @@ impl Prompt {
                     }
                     _ => self.input[start..end].to_string(),
                 };
-                if let Err(_) = self.clipboard.set_text(text) {
-                    return false;
-                }
-                return true;
+                return matches!(self.clipboard.as_mut().map(|clipboard| clipboard.set_text(text)), Some(Ok(())));
             }
         }
         false
@@ -324,28 +2178,35 @@ impl Prompt {
     }
 
     fn paste(&mut self) {
+        if let Some(Ok(text)) = self.clipboard.as_mut().map(Clipboard::get_text) {
+            self.insert_str(&text);
+        }
+    }
+
+    /// Inserts pasted text into the active field. A trailing line break is
+    /// dropped; other line breaks are kept in find/replace, where they match
+    /// across lines, and become spaces in the single-line prompts.
+    fn insert_str(&mut self, text: &str) {
+        let text = text.trim_end_matches(['\r', '\n']);
         match self.prompt_type {
-            PromptType::SaveAs => {
-                if let Ok(text) = self.clipboard.get_text() {
-                    self.delete_selection();
-                    self.input.insert_str(self.cursor_pos, &text);
-                    self.cursor_pos += text.len();
-                }
+            PromptType::SaveAs | PromptType::TaskList | PromptType::UndoTree | PromptType::Macros | PromptType::ClipboardHistory | PromptType::LineCommands | PromptType::Transforms | PromptType::ReflowColumn => {
+                let text = text.replace("\r\n", " ").replace(['\r', '\n'], " ");
+                self.delete_selection();
+                self.input.insert_str(self.cursor_pos, &text);
+                self.cursor_pos += text.len();
             }
             PromptType::FindReplace => {
-                if let Ok(text) = self.clipboard.get_text() {
-                    self.delete_selection();
-                    match self.active_field {
-                        FindReplaceField::Find => {
-                            self.input.insert_str(self.cursor_pos, &text);
-                            self.cursor_pos += text.len();
-                        }
-                        FindReplaceField::Replace => {
-                            self.replace_input.insert_str(self.replace_cursor_pos, &text);
-                            self.replace_cursor_pos += text.len();
-                        }
-                        _ => {}
+                self.delete_selection();
+                match self.active_field {
+                    FindReplaceField::Find => {
+                        self.input.insert_str(self.cursor_pos, text);
+                        self.cursor_pos += text.len();
+                    }
+                    FindReplaceField::Replace => {
+                        self.replace_input.insert_str(self.replace_cursor_pos, text);
+                        self.replace_cursor_pos += text.len();
                     }
+                    _ => {}
                 }
             }
             _ => {}
@@ -354,7 +2215,7 @@ impl Prompt {
 
     fn insert_char(&mut self, ch: char) {
         match self.prompt_type {
-            PromptType::SaveAs => {
+            PromptType::SaveAs | PromptType::TaskList | PromptType::UndoTree | PromptType::Macros | PromptType::ClipboardHistory | PromptType::LineCommands | PromptType::Transforms | PromptType::ReflowColumn => {
                 self.delete_selection();
                 self.input.insert(self.cursor_pos, ch);
                 self.cursor_pos += ch.len_utf8();
@@ -379,22 +2240,15 @@ impl Prompt {
 
     fn backspace(&mut self) {
         match self.prompt_type {
-            PromptType::SaveAs => {
+            PromptType::SaveAs | PromptType::TaskList | PromptType::UndoTree | PromptType::Macros | PromptType::ClipboardHistory | PromptType::LineCommands | PromptType::Transforms | PromptType::ReflowColumn => {
                 if self.delete_selection() {
                     return;
                 }
                 
                 if self.cursor_pos > 0 {
-                    let char_boundary = self.input
-                        .char_indices()
-                        .rev()
-                        .find(|(idx, _)| *idx < self.cursor_pos)
-                        .map(|(idx, ch)| (idx, ch.len_utf8()));
-                    
-                    if let Some((idx, _len)) = char_boundary {
-                        self.input.remove(idx);
-                        self.cursor_pos = idx;
-                    }
+                    let idx = prev_grapheme_boundary(&self.input, self.cursor_pos);
+                    self.input.drain(idx..self.cursor_pos);
+                    self.cursor_pos = idx;
                 }
             }
             PromptType::FindReplace => {
@@ -405,30 +2259,16 @@ impl Prompt {
                 match self.active_field {
                     FindReplaceField::Find => {
                         if self.cursor_pos > 0 {
-                            let char_boundary = self.input
-                                .char_indices()
-                                .rev()
-                                .find(|(idx, _)| *idx < self.cursor_pos)
-                                .map(|(idx, ch)| (idx, ch.len_utf8()));
-                            
-                            if let Some((idx, _len)) = char_boundary {
-                                self.input.remove(idx);
-                                self.cursor_pos = idx;
-                            }
+                            let idx = prev_grapheme_boundary(&self.input, self.cursor_pos);
+                            self.input.drain(idx..self.cursor_pos);
+                            self.cursor_pos = idx;
                         }
                     }
                     FindReplaceField::Replace => {
                         if self.replace_cursor_pos > 0 {
-                            let char_boundary = self.replace_input
-                                .char_indices()
-                                .rev()
-                                .find(|(idx, _)| *idx < self.replace_cursor_pos)
-                                .map(|(idx, ch)| (idx, ch.len_utf8()));
-                            
-                            if let Some((idx, _len)) = char_boundary {
-                                self.replace_input.remove(idx);
-                                self.replace_cursor_pos = idx;
-                            }
+                            let idx = prev_grapheme_boundary(&self.replace_input, self.replace_cursor_pos);
+                            self.replace_input.drain(idx..self.replace_cursor_pos);
+                            self.replace_cursor_pos = idx;
                         }
                     }
                     FindReplaceField::Buffer => {} // No-op when buffer focused
@@ -440,20 +2280,14 @@ impl Prompt {
 
     fn delete(&mut self) {
         match self.prompt_type {
-            PromptType::SaveAs => {
+            PromptType::SaveAs | PromptType::TaskList | PromptType::UndoTree | PromptType::Macros | PromptType::ClipboardHistory | PromptType::LineCommands | PromptType::Transforms | PromptType::ReflowColumn => {
                 if self.delete_selection() {
                     return;
                 }
                 
                 if self.cursor_pos < self.input.len() {
-                    let char_boundary = self.input
-                        .char_indices()
-                        .find(|(idx, _)| *idx >= self.cursor_pos)
-                        .map(|(idx, ch)| (idx, ch.len_utf8()));
-                    
-                    if let Some((idx, len)) = char_boundary {
-                        self.input.drain(idx..idx + len);
-                    }
+                    let end = next_grapheme_boundary(&self.input, self.cursor_pos);
+                    self.input.drain(self.cursor_pos..end);
                 }
             }
             PromptType::FindReplace => {
@@ -464,26 +2298,14 @@ impl Prompt {
                 match self.active_field {
                     FindReplaceField::Find => {
                         if self.cursor_pos < self.input.len() {
-                            let char_boundary = self.input
-                                .char_indices()
-                                .find(|(idx, _)| *idx >= self.cursor_pos)
-                                .map(|(idx, ch)| (idx, ch.len_utf8()));
-                            
-                            if let Some((idx, len)) = char_boundary {
-                                self.input.drain(idx..idx + len);
-                            }
+                            let end = next_grapheme_boundary(&self.input, self.cursor_pos);
+                            self.input.drain(self.cursor_pos..end);
                         }
                     }
                     FindReplaceField::Replace => {
                         if self.replace_cursor_pos < self.replace_input.len() {
-                            let char_boundary = self.replace_input
-                                .char_indices()
-                                .find(|(idx, _)| *idx >= self.replace_cursor_pos)
-                                .map(|(idx, ch)| (idx, ch.len_utf8()));
-                            
-                            if let Some((idx, len)) = char_boundary {
-                                self.replace_input.drain(idx..idx + len);
-                            }
+                            let end = next_grapheme_boundary(&self.replace_input, self.replace_cursor_pos);
+                            self.replace_input.drain(self.replace_cursor_pos..end);
                         }
                     }
                     FindReplaceField::Buffer => {} // No-op when buffer focused
@@ -511,12 +2333,7 @@ impl Prompt {
                             self.clear_selection();
                         }
                         if self.cursor_pos > 0 {
-                            let new_pos = self.input
-                                .char_indices()
-                                .rev()
-                                .find(|(idx, _)| *idx < self.cursor_pos)
-                                .map(|(idx, _)| idx)
-                                .unwrap_or(0);
+                            let new_pos = prev_grapheme_boundary(&self.input, self.cursor_pos);
                             self.cursor_pos = new_pos;
                         }
                     }
@@ -534,12 +2351,7 @@ impl Prompt {
                             self.clear_selection();
                         }
                         if self.replace_cursor_pos > 0 {
-                            let new_pos = self.replace_input
-                                .char_indices()
-                                .rev()
-                                .find(|(idx, _)| *idx < self.replace_cursor_pos)
-                                .map(|(idx, _)| idx)
-                                .unwrap_or(0);
+                            let new_pos = prev_grapheme_boundary(&self.replace_input, self.replace_cursor_pos);
                             self.replace_cursor_pos = new_pos;
                         }
                     }
@@ -560,12 +2372,7 @@ impl Prompt {
                     self.clear_selection();
                 }
                 if self.cursor_pos > 0 {
-                    let new_pos = self.input
-                        .char_indices()
-                        .rev()
-                        .find(|(idx, _)| *idx < self.cursor_pos)
-                        .map(|(idx, _)| idx)
-                        .unwrap_or(0);
+                    let new_pos = prev_grapheme_boundary(&self.input, self.cursor_pos);
                     self.cursor_pos = new_pos;
                 }
             }
@@ -590,11 +2397,7 @@ impl Prompt {
                             self.clear_selection();
                         }
                         if self.cursor_pos < self.input.len() {
-                            let new_pos = self.input
-                                .char_indices()
-                                .find(|(idx, _)| *idx > self.cursor_pos)
-                                .map(|(idx, _)| idx)
-                                .unwrap_or(self.input.len());
+                            let new_pos = next_grapheme_boundary(&self.input, self.cursor_pos);
                             self.cursor_pos = new_pos;
                         }
                     }
@@ -612,11 +2415,7 @@ impl Prompt {
                             self.clear_selection();
                         }
                         if self.replace_cursor_pos < self.replace_input.len() {
-                            let new_pos = self.replace_input
-                                .char_indices()
-                                .find(|(idx, _)| *idx > self.replace_cursor_pos)
-                                .map(|(idx, _)| idx)
-                                .unwrap_or(self.replace_input.len());
+                            let new_pos = next_grapheme_boundary(&self.replace_input, self.replace_cursor_pos);
                             self.replace_cursor_pos = new_pos;
                         }
                     }
@@ -637,17 +2436,59 @@ impl Prompt {
                     self.clear_selection();
                 }
                 if self.cursor_pos < self.input.len() {
-                    let new_pos = self.input
-                        .char_indices()
-                        .find(|(idx, _)| *idx > self.cursor_pos)
-                        .map(|(idx, _)| idx)
-                        .unwrap_or(self.input.len());
+                    let new_pos = next_grapheme_boundary(&self.input, self.cursor_pos);
                     self.cursor_pos = new_pos;
                 }
             }
         }
     }
 
+    fn move_word_left(&mut self, extend_selection: bool, subword: bool) {
+        let pos = self.get_active_cursor_pos();
+        if extend_selection && !self.has_selection() {
+            self.set_active_selection_anchor(Some(pos));
+        } else if !extend_selection {
+            self.clear_selection();
+        }
+        let new_pos = prev_word_boundary(self.get_active_input(), pos, subword);
+        self.set_active_cursor_pos(new_pos);
+    }
+
+    fn move_word_right(&mut self, extend_selection: bool, subword: bool) {
+        let pos = self.get_active_cursor_pos();
+        if extend_selection && !self.has_selection() {
+            self.set_active_selection_anchor(Some(pos));
+        } else if !extend_selection {
+            self.clear_selection();
+        }
+        let new_pos = next_word_boundary(self.get_active_input(), pos, subword);
+        self.set_active_cursor_pos(new_pos);
+    }
+
+    fn delete_word_backward(&mut self) {
+        if self.delete_selection() {
+            return;
+        }
+        let pos = self.get_active_cursor_pos();
+        let start = prev_word_boundary(self.get_active_input(), pos, false);
+        if start < pos {
+            self.set_active_selection_anchor(Some(start));
+            self.delete_selection();
+        }
+    }
+
+    fn delete_word_forward(&mut self) {
+        if self.delete_selection() {
+            return;
+        }
+        let pos = self.get_active_cursor_pos();
+        let end = next_word_boundary(self.get_active_input(), pos, false);
+        if end > pos {
+            self.set_active_selection_anchor(Some(end));
+            self.delete_selection();
+        }
+    }
+
     fn move_cursor_home(&mut self, extend_selection: bool) {
         match self.prompt_type {
             PromptType::FindReplace => {
@@ -724,13 +2565,13 @@ impl Prompt {
             // Find the character position based on visual width
             let mut visual_pos = 0;
             let mut byte_pos = 0;
-            for (idx, ch) in self.input.char_indices() {
+            for (idx, g) in self.input.grapheme_indices(true) {
                 if visual_pos >= target_pos {
                     byte_pos = idx;
                     break;
                 }
-                visual_pos += ch.to_string().width();
-                byte_pos = idx + ch.len_utf8();
+                visual_pos += g.width();
+                byte_pos = idx + g.len();
             }
             
             if visual_pos < target_pos {
@@ -758,13 +2599,13 @@ impl Prompt {
             // Find the character position based on visual width
             let mut visual_pos = 0;
             let mut byte_pos = 0;
-            for (idx, ch) in self.input.char_indices() {
+            for (idx, g) in self.input.grapheme_indices(true) {
                 if visual_pos >= target_pos {
                     byte_pos = idx;
                     break;
                 }
-                visual_pos += ch.to_string().width();
-                byte_pos = idx + ch.len_utf8();
+                visual_pos += g.width();
+                byte_pos = idx + g.len();
             }
             
             if visual_pos < target_pos {
@@ -780,11 +2621,11 @@ impl Prompt {
             PromptType::SaveAs => {
                 // Calculate visual cursor position
                 let mut visual_pos = 0;
-                for (idx, ch) in self.input.char_indices() {
+                for (idx, g) in self.input.grapheme_indices(true) {
                     if idx >= self.cursor_pos {
                         break;
                     }
-                    visual_pos += ch.to_string().width();
+                    visual_pos += g.width();
                 }
                 
                 // Adjust scroll offset to keep cursor visible
@@ -799,11 +2640,11 @@ impl Prompt {
                     FindReplaceField::Find => {
                         // Calculate visual cursor position
                         let mut visual_pos = 0;
-                        for (idx, ch) in self.input.char_indices() {
+                        for (idx, g) in self.input.grapheme_indices(true) {
                             if idx >= self.cursor_pos {
                                 break;
                             }
-                            visual_pos += ch.to_string().width();
+                            visual_pos += g.width();
                         }
                         
                         // Adjust scroll offset to keep cursor visible
@@ -816,11 +2657,11 @@ impl Prompt {
                     FindReplaceField::Replace => {
                         // Calculate visual cursor position
                         let mut visual_pos = 0;
-                        for (idx, ch) in self.replace_input.char_indices() {
+                        for (idx, g) in self.replace_input.grapheme_indices(true) {
                             if idx >= self.replace_cursor_pos {
                                 break;
                             }
-                            visual_pos += ch.to_string().width();
+                            visual_pos += g.width();
                         }
                         
                         // Adjust scroll offset to keep cursor visible
@@ -844,27 +2685,273 @@ enum AppState {
     Exiting,
 }
 
+/// Where one pane is looking: its carets, selections and scroll position,
+/// and the visual lines laid out for its width. The focused pane's view
+/// lives in the editor's own fields; the others are parked here.
+#[derive(Default)]
+struct View {
+    caret: usize,
+    selection_anchor: Option<usize>,
+    preferred_col: usize,
+    extra_cursors: Vec<Cursor>,
+    block_anchor: Option<(usize, usize)>,
+    block_cursor: (usize, usize),
+    viewport_offset: (usize, usize),
+    visual_lines: Vec<Option<VisualLine>>,
+    visual_lines_valid: bool,
+    logical_line_map: Vec<(usize, usize)>,
+    viewport_follows_caret: bool,
+}
+
+/// A file with its undo history and caches. Like views, the buffer of
+/// the focused pane lives in the editor's fields and the others are
+/// parked here until one of their panes takes focus.
+struct Buffer {
+    rope: Rope,
+    filename: Option<PathBuf>,
+    modified: bool,
+    saved_state: Option<usize>,
+    saved_len: usize,
+    saved_hash: u64,
+    task_counts: Option<(usize, usize)>,
+    bracket_marks: Option<Vec<BracketMark>>,
+    flagged_chars: Option<Vec<usize>>,
+    last_paste: Option<(String, usize)>,
+    auto_closed: Vec<usize>,
+    folds: Vec<Fold>,
+    undo_tree: UndoTree,
+    last_step: Option<(bool, EditClass)>,
+    last_step_carets: Vec<usize>,
+    find_matches: Vec<(usize, usize)>,
+    current_match_index: Option<usize>,
+}
+
+impl Buffer {
+    fn new() -> Self {
+        Self {
+            rope: Rope::new(),
+            filename: None,
+            modified: false,
+            saved_state: Some(0),
+            saved_len: 0,
+            saved_hash: content_hash(""),
+            task_counts: None,
+            bracket_marks: None,
+            flagged_chars: None,
+            last_paste: None,
+            auto_closed: Vec::new(),
+            folds: Vec::new(),
+            undo_tree: UndoTree::new(),
+            last_step: None,
+            last_step_carets: Vec::new(),
+            find_matches: Vec::new(),
+            current_match_index: None,
+        }
+    }
+}
+
+struct Pane {
+    view: View,
+    buffer: usize,
+}
+
+/// How the text area is shared between panes: a pane, or two layouts
+/// side by side (`Horizontal`) or stacked (`Vertical`) with the first one
+/// given `share` percent of the room.
+enum PaneLayout {
+    Leaf(usize),
+    Split {
+        direction: Direction,
+        share: u16,
+        first: Box<PaneLayout>,
+        second: Box<PaneLayout>,
+    },
+}
+
+impl PaneLayout {
+    /// Lays the panes out in `area`, one cell apart. Returns each pane's
+    /// area and the cells of the dividers between them.
+    fn arrange(&self, area: Rect, panes: &mut Vec<(usize, Rect)>, dividers: &mut Vec<(Direction, Rect)>) {
+        match self {
+            PaneLayout::Leaf(pane) => panes.push((*pane, area)),
+            PaneLayout::Split { direction, share, first, second } => {
+                let total = match direction {
+                    Direction::Horizontal => area.width,
+                    Direction::Vertical => area.height,
+                };
+                // Both sides keep at least one cell while there is room
+                let first_len = if total >= 3 {
+                    ((total - 1) as u32 * *share as u32 / 100).clamp(1, total as u32 - 2) as u16
+                } else {
+                    total
+                };
+                let second_len = total.saturating_sub(first_len + 1);
+                let (first_area, divider, second_area) = match direction {
+                    Direction::Horizontal => (
+                        Rect::new(area.x, area.y, first_len, area.height),
+                        Rect::new(area.x + first_len, area.y, total.min(first_len + 1) - first_len, area.height),
+                        Rect::new(area.x + total - second_len, area.y, second_len, area.height),
+                    ),
+                    Direction::Vertical => (
+                        Rect::new(area.x, area.y, area.width, first_len),
+                        Rect::new(area.x, area.y + first_len, area.width, total.min(first_len + 1) - first_len),
+                        Rect::new(area.x, area.y + total - second_len, area.width, second_len),
+                    ),
+                };
+                first.arrange(first_area, panes, dividers);
+                dividers.push((*direction, divider));
+                second.arrange(second_area, panes, dividers);
+            }
+        }
+    }
+
+    /// The panes in screen order, left to right and top to bottom.
+    fn panes(&self) -> Vec<usize> {
+        match self {
+            PaneLayout::Leaf(pane) => vec![*pane],
+            PaneLayout::Split { first, second, .. } => {
+                let mut panes = first.panes();
+                panes.extend(second.panes());
+                panes
+            }
+        }
+    }
+
+    /// Puts `new_pane` next to `pane`, each taking half of its room.
+    fn split(&mut self, pane: usize, direction: Direction, new_pane: usize) {
+        match self {
+            PaneLayout::Leaf(leaf) if *leaf == pane => {
+                *self = PaneLayout::Split {
+                    direction,
+                    share: 50,
+                    first: Box::new(PaneLayout::Leaf(pane)),
+                    second: Box::new(PaneLayout::Leaf(new_pane)),
+                };
+            }
+            PaneLayout::Leaf(_) => {}
+            PaneLayout::Split { first, second, .. } => {
+                first.split(pane, direction, new_pane);
+                second.split(pane, direction, new_pane);
+            }
+        }
+    }
+
+    /// Takes `pane` out, giving its room to its neighbour, and renumbers
+    /// the panes after it.
+    fn remove(&mut self, pane: usize) {
+        match self {
+            PaneLayout::Leaf(leaf) => {
+                if *leaf > pane {
+                    *leaf -= 1;
+                }
+            }
+            PaneLayout::Split { first, second, .. } => {
+                let rest = if matches!(**first, PaneLayout::Leaf(leaf) if leaf == pane) {
+                    Some(std::mem::replace(&mut **second, PaneLayout::Leaf(0)))
+                } else if matches!(**second, PaneLayout::Leaf(leaf) if leaf == pane) {
+                    Some(std::mem::replace(&mut **first, PaneLayout::Leaf(0)))
+                } else {
+                    None
+                };
+                match rest {
+                    Some(rest) => {
+                        *self = rest;
+                        self.remove(pane);
+                    }
+                    None => {
+                        first.remove(pane);
+                        second.remove(pane);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Grows `pane` by `delta` percent of the innermost split holding it,
+    /// or shrinks it for a negative `delta`.
+    fn resize(&mut self, pane: usize, delta: i16) -> bool {
+        match self {
+            PaneLayout::Leaf(_) => false,
+            PaneLayout::Split { share, first, second, .. } => {
+                if first.resize(pane, delta) || second.resize(pane, delta) {
+                    return true;
+                }
+                let delta = if first.panes().contains(&pane) {
+                    delta
+                } else if second.panes().contains(&pane) {
+                    -delta
+                } else {
+                    return false;
+                };
+                *share = (*share as i16 + delta).clamp(10, 90) as u16;
+                true
+            }
+        }
+    }
+}
+
 struct Editor {
     rope: Rope,
     caret: usize,
     selection_anchor: Option<usize>,
     preferred_col: usize,
+    extra_cursors: Vec<Cursor>,
+    block_anchor: Option<(usize, usize)>,
+    block_cursor: (usize, usize),
+    last_block_copy: Option<String>,
+    clipboard_history: Vec<String>,
+    persist_clipboard_history: bool,
+    last_paste: Option<(String, usize)>,
     viewport_offset: (usize, usize),
     word_wrap: bool,
+    /// Display rows in text order, with `virtual_lines` empty rows at
+    /// each end and none in between
     visual_lines: Vec<Option<VisualLine>>,
     visual_lines_valid: bool,
     logical_line_map: Vec<(usize, usize)>,
     scrolloff: usize,
     virtual_lines: usize,
+    tab_width: usize,
+    use_hard_tabs: bool,
+    wrap_column: usize,
+    show_whitespace: bool,
     filename: Option<PathBuf>,
     modified: bool,
-    undo_stack: Vec<UndoGroup>,
-    redo_stack: Vec<UndoGroup>,
+    saved_state: Option<usize>,
+    saved_len: usize,
+    saved_hash: u64,
+    task_counts: Option<(usize, usize)>,
+    bracket_marks: Option<Vec<BracketMark>>,
+    flagged_chars: Option<Vec<usize>>,
+    auto_pair: bool,
+    auto_closed: Vec<usize>,
+    folds: Vec<Fold>,
+    undo_tree: UndoTree,
     current_group: Option<UndoGroup>,
-    last_edit_time: Option<Instant>,
+    undo_step_depth: usize,
+    undo_step_atomic: bool,
+    undo_step_started: bool,
+    undo_step_carets: Vec<usize>,
+    last_step: Option<(bool, EditClass)>,
+    last_step_carets: Vec<usize>,
+    macros: BTreeMap<char, Vec<event::KeyEvent>>,
+    recording: Option<(char, Vec<event::KeyEvent>)>,
+    /// Set when text was pasted during the recording, which keys cannot
+    /// reproduce.
+    recording_missed_paste: bool,
+    macro_register_pending: bool,
+    replaying: bool,
+    find_failed: bool,
     is_dragging: bool,
-    clipboard: Clipboard,
+    dragging_scrollbar: bool,
+    last_click: Option<(Instant, u16, u16)>,
+    click_count: usize,
+    clipboard: Option<Clipboard>,
     current_dir: PathBuf,
+    panes: Vec<Pane>,
+    buffers: Vec<Buffer>,
+    focused_pane: usize,
+    pane_layout: PaneLayout,
     app_state: AppState,
     find_matches: Vec<(usize, usize)>,
     current_match_index: Option<usize>,
@@ -883,6 +2970,13 @@ impl Editor {
             caret: 0,
             selection_anchor: None,
             preferred_col: 0,
+            extra_cursors: Vec::new(),
+            block_anchor: None,
+            block_cursor: (0, 0),
+            last_block_copy: None,
+            clipboard_history: Vec::new(),
+            persist_clipboard_history: false,
+            last_paste: None,
             viewport_offset: (0, 0),
             word_wrap: true,
             visual_lines: Vec::new(),
@@ -890,15 +2984,45 @@ impl Editor {
             logical_line_map: Vec::new(),
             scrolloff: 3,
             virtual_lines: 2,
+            tab_width: 4,
+            use_hard_tabs: false,
+            wrap_column: 72,
+            show_whitespace: false,
             filename: None,
             modified: false,
-            undo_stack: Vec::new(),
-            redo_stack: Vec::new(),
+            saved_state: Some(0),
+            saved_len: 0,
+            saved_hash: content_hash(""),
+            task_counts: None,
+            bracket_marks: None,
+            flagged_chars: None,
+            auto_pair: true,
+            auto_closed: Vec::new(),
+            folds: Vec::new(),
+            undo_tree: UndoTree::new(),
             current_group: None,
-            last_edit_time: None,
+            undo_step_depth: 0,
+            undo_step_atomic: false,
+            undo_step_started: false,
+            undo_step_carets: Vec::new(),
+            last_step: None,
+            last_step_carets: Vec::new(),
+            macros: BTreeMap::new(),
+            recording: None,
+            recording_missed_paste: false,
+            macro_register_pending: false,
+            replaying: false,
+            find_failed: false,
             is_dragging: false,
-            clipboard: Clipboard::new().unwrap(),
+            dragging_scrollbar: false,
+            last_click: None,
+            click_count: 0,
+            clipboard: Clipboard::new().ok(),
             current_dir,
+            panes: vec![Pane { view: View::default(), buffer: 0 }],
+            buffers: vec![Buffer::new()],
+            focused_pane: 0,
+            pane_layout: PaneLayout::Leaf(0),
             app_state: AppState::Editing,
             find_matches: Vec::new(),
             current_match_index: None,
@@ -908,220 +3032,959 @@ impl Editor {
             #[cfg(target_os = "windows")]
             modal_just_dismissed: false,
         };
-        editor.invalidate_visual_lines();
-        editor
+        editor.invalidate_visual_lines();
+        editor
+    }
+
+    fn save(&mut self) -> io::Result<()> {
+        if let Some(ref path) = self.filename {
+            let content = self.rope.to_string();
+            fs::write(path, &content)?;
+            self.store_undo_history(&content);
+            self.mark_saved(&content);
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::Other, "No filename"))
+        }
+    }
+
+    fn save_as(&mut self, path: PathBuf) -> io::Result<()> {
+        let content = self.rope.to_string();
+        fs::write(&path, &content)?;
+        self.filename = Some(path);
+        // The new extension may bring a different language
+        self.bracket_marks = None;
+        self.store_undo_history(&content);
+        self.mark_saved(&content);
+        Ok(())
+    }
+
+    /// Records the current undo state as the save point for `content`,
+    /// which is what the file on disk now holds.
+    fn mark_saved(&mut self, content: &str) {
+        self.finalize_undo_group();
+        self.saved_state = Some(self.undo_tree.current);
+        self.saved_len = content.len();
+        self.saved_hash = content_hash(content);
+        self.modified = false;
+    }
+
+    /// Recomputes `modified` against the save point. The buffer is clean at
+    /// the saved undo state, and also anywhere else its contents hash the
+    /// same as the file on disk, as long as the buffer is small enough to
+    /// hash on every edit.
+    fn update_modified(&mut self) {
+        let at_save_point = self.current_group.is_none() && self.saved_state == Some(self.undo_tree.current);
+        self.modified = if at_save_point {
+            false
+        } else if self.rope.len_bytes() != self.saved_len || self.rope.len_bytes() > MODIFIED_HASH_LIMIT {
+            true
+        } else {
+            content_hash(&self.rope.to_string()) != self.saved_hash
+        };
+    }
+
+    /// Undo history file for the current document, named after a hash of
+    /// its absolute path.
+    fn undo_file_path(&self) -> Option<(PathBuf, PathBuf)> {
+        let path = self.filename.as_ref()?;
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+        let file = undo_state_dir()?.join(format!("{:016x}.undo", content_hash(&path.to_string_lossy())));
+        Some((path, file))
+    }
+
+    /// Writes the undo tree next to a hash of the saved contents so it can
+    /// be restored the next time this exact file is opened. Failures are
+    /// ignored: persistent history is a convenience, not part of saving.
+    fn store_undo_history(&mut self, content: &str) {
+        self.finalize_undo_group();
+        let Some((path, file)) = self.undo_file_path() else {
+            return;
+        };
+        
+        let Some(data) = self.undo_tree.serialize_capped(&path, content_hash(content), UNDO_FILE_CAP) else {
+            let _ = fs::remove_file(&file);
+            return;
+        };
+        if let Some(dir) = file.parent() {
+            if fs::create_dir_all(dir).is_ok() {
+                let _ = fs::write(&file, data);
+                prune_undo_store(dir);
+            }
+        }
+    }
+
+    /// Restores the undo tree stored for this file when it was saved with
+    /// exactly these contents, and discards a stale one otherwise.
+    fn restore_undo_history(&mut self, content: &str) {
+        let Some((path, file)) = self.undo_file_path() else {
+            return;
+        };
+        let Ok(data) = fs::read_to_string(&file) else {
+            return;
+        };
+        match UndoTree::deserialize(&data) {
+            Some((tree, stored_path, hash)) if stored_path == path.to_string_lossy() && hash == content_hash(content) => {
+                self.undo_tree = tree;
+            }
+            _ => {
+                let _ = fs::remove_file(&file);
+            }
+        }
+    }
+
+    fn get_save_path_suggestion(&self) -> String {
+        if let Some(ref path) = self.filename {
+            path.to_string_lossy().to_string()
+        } else {
+            let mut path = self.current_dir.clone();
+            path.push("");
+            path.to_string_lossy().to_string()
+        }
+    }
+
+    fn load_file(&mut self, path: PathBuf) -> io::Result<()> {
+        let content = fs::read_to_string(&path)?;
+        self.rope = Rope::from_str(&content);
+        self.filename = Some(path.clone());
+        
+        // Update current directory to the file's directory
+        if let Some(parent) = path.parent() {
+            self.current_dir = parent.to_path_buf();
+        }
+        
+        self.caret = 0;
+        self.selection_anchor = None;
+        self.preferred_col = 0;
+        self.clear_extra_cursors();
+        self.modified = false;
+        self.invalidate_visual_lines();
+        self.logical_line_map.clear();
+        self.current_group = None;
+        self.auto_closed.clear();
+        self.folds.clear();
+        self.undo_tree = UndoTree::new();
+        self.restore_undo_history(&content);
+        self.mark_saved(&content);
+        Ok(())
+    }
+
+    fn has_selection(&self) -> bool {
+        self.selection_anchor.is_some()
+    }
+
+    fn get_selection_range(&self) -> Option<(usize, usize)> {
+        self.selection_anchor.map(|anchor| {
+            if anchor <= self.caret {
+                (anchor, self.caret)
+            } else {
+                (self.caret, anchor)
+            }
+        })
+    }
+
+    fn clear_selection(&mut self) {
+        self.selection_anchor = None;
+    }
+
+    fn delete_selection(&mut self) -> bool {
+        if let Some((start, end)) = self.get_selection_range() {
+            if start < end {
+                let text = self.rope.byte_slice(start..end).to_string();
+                let before = self.caret;
+                
+                let start_char = self.rope.byte_to_char(start);
+                let end_char = self.rope.byte_to_char(end);
+                self.rope.remove(start_char..end_char);
+                
+                self.caret = start;
+                self.push_op(EditOp::Delete { pos: start, text }, before, self.caret);
+                
+                self.invalidate_visual_lines();
+                self.clear_selection();
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Opens an undo step: every edit until the matching `end_undo_step`
+    /// is grouped as one user action, e.g. a keystroke applied at every
+    /// cursor. Atomic steps such as paste or indent always get an undo
+    /// group of their own. Steps may nest; only the outermost one counts.
+    fn begin_undo_step(&mut self, atomic: bool) {
+        if self.undo_step_depth == 0 {
+            self.undo_step_atomic = atomic;
+            self.undo_step_started = false;
+            self.undo_step_carets = self.all_cursors().iter().map(|cursor| cursor.caret).collect();
+            if atomic {
+                self.finalize_undo_group();
+            }
+        }
+        self.undo_step_depth += 1;
+    }
+
+    fn end_undo_step(&mut self) {
+        self.undo_step_depth = self.undo_step_depth.saturating_sub(1);
+        if self.undo_step_depth > 0 {
+            return;
+        }
+        if self.undo_step_atomic {
+            self.finalize_undo_group();
+            self.last_step = None;
+        } else if self.undo_step_started {
+            self.last_step_carets = self.all_cursors().iter().map(|cursor| cursor.caret).collect();
+        }
+    }
+
+    /// Records an edit for undo. The first edit of a step decides whether
+    /// the step extends the open group: it must carry on from where the
+    /// previous step left every caret and pass `continues_undo_group`.
+    /// Edits made outside a step count as a step of their own.
+    fn push_op(&mut self, op: EditOp, caret_before: usize, caret_after: usize) {
+        let (is_insert, text) = match &op {
+            EditOp::Insert { text, .. } => (true, text),
+            EditOp::Delete { text, .. } => (false, text),
+        };
+        
+        match &op {
+            EditOp::Insert { pos, text } => self.shift_with_edit(true, *pos, text),
+            EditOp::Delete { pos, text } => self.shift_with_edit(false, *pos, text),
+        }
+        
+        // Keep auto-inserted closers pointing at the same characters
+        match &op {
+            EditOp::Insert { pos, text } => {
+                for closer in &mut self.auto_closed {
+                    if *closer >= *pos {
+                        *closer += text.len();
+                    }
+                }
+            }
+            EditOp::Delete { pos, text } => {
+                self.auto_closed.retain(|closer| !(*pos..*pos + text.len()).contains(closer));
+                for closer in &mut self.auto_closed {
+                    if *closer > *pos {
+                        *closer -= text.len();
+                    }
+                }
+            }
+        }
+        let step = (is_insert, EditClass::of(text));
+        let implicit = self.undo_step_depth == 0;
+        
+        if implicit || !self.undo_step_started {
+            let carets_match = if implicit {
+                self.last_step_carets == [caret_before]
+            } else {
+                self.last_step_carets == self.undo_step_carets
+            };
+            let continues = (implicit || !self.undo_step_atomic)
+                && self.current_group.is_some()
+                && carets_match
+                && continues_undo_group(self.last_step, step);
+            if !continues {
+                self.finalize_undo_group();
+                self.current_group = Some(UndoGroup {
+                    ops: Vec::new(),
+                    timestamp: SystemTime::now(),
+                });
+            }
+            self.undo_step_started = !implicit;
+        }
+        
+        if let Some(ref mut group) = self.current_group {
+            if !Self::merge_into_last_op(&mut group.ops, &op, caret_before, caret_after) {
+                group.ops.push((op, caret_before, caret_after));
+            }
+        }
+        
+        self.last_step = Some(step);
+        if implicit {
+            self.last_step_carets = vec![caret_after];
+        }
+        self.update_modified();
+    }
+
+    /// Folds a single-character edit into the previous op when it directly
+    /// continues it: typing at the end of an insert, or backspacing/deleting
+    /// next to a deletion. Returns false if the edit must be kept separate.
+    fn merge_into_last_op(ops: &mut [(EditOp, usize, usize)], op: &EditOp, caret_before: usize, caret_after: usize) -> bool {
+        let Some((last, _, last_after)) = ops.last_mut() else {
+            return false;
+        };
+        if *last_after != caret_before {
+            return false;
+        }
+        let merged = match (last, op) {
+            (EditOp::Insert { pos: last_pos, text: last_text }, EditOp::Insert { pos, text })
+                if EditClass::of(text) != EditClass::Bulk && *last_pos + last_text.len() == *pos => {
+                last_text.push_str(text);
+                true
+            }
+            (EditOp::Delete { pos: last_pos, text: last_text }, EditOp::Delete { pos, text })
+                if EditClass::of(text) != EditClass::Bulk && *pos + text.len() == *last_pos => {
+                // Backspace: the deleted text sits just before the last deletion
+                last_text.insert_str(0, text);
+                *last_pos = *pos;
+                true
+            }
+            (EditOp::Delete { pos: last_pos, text: last_text }, EditOp::Delete { pos, text })
+                if EditClass::of(text) != EditClass::Bulk && *pos == *last_pos => {
+                last_text.push_str(text);
+                true
+            }
+            _ => false,
+        };
+        if merged {
+            *last_after = caret_after;
+        }
+        merged
+    }
+
+    fn finalize_undo_group(&mut self) {
+        if let Some(group) = self.current_group.take() {
+            if !group.ops.is_empty() {
+                self.undo_tree.push(group);
+            }
+        }
+    }
+
+    /// Reverts the edits of the current undo tree node and moves to its
+    /// parent. Returns the caret position from before those edits.
+    fn revert_current_node(&mut self) -> usize {
+        let node = self.undo_tree.current;
+        let ops = std::mem::take(&mut self.undo_tree.nodes[node].group.ops);
+        let mut caret = self.caret;
+        
+        for (op, before, _) in ops.iter().rev() {
+            match op {
+                EditOp::Insert { pos, text } => self.shift_with_edit(false, *pos, text),
+                EditOp::Delete { pos, text } => self.shift_with_edit(true, *pos, text),
+            }
+            match op {
+                EditOp::Insert { pos, text } => {
+                    // Ensure positions are within bounds
+                    let safe_pos = (*pos).min(self.rope.len_bytes());
+                    let safe_end = (pos + text.len()).min(self.rope.len_bytes());
+                    if safe_pos < self.rope.len_bytes() && safe_end <= self.rope.len_bytes() {
+                        let char_pos = self.rope.byte_to_char(safe_pos);
+                        let char_end = self.rope.byte_to_char(safe_end);
+                        self.rope.remove(char_pos..char_end);
+                    }
+                }
+                EditOp::Delete { pos, text } => {
+                    let safe_pos = (*pos).min(self.rope.len_bytes());
+                    self.rope.insert(self.rope.byte_to_char(safe_pos), text);
+                }
+            }
+            caret = *before;
+        }
+        
+        self.undo_tree.nodes[node].group.ops = ops;
+        let parent = self.undo_tree.nodes[node].parent;
+        self.undo_tree.nodes[parent].redo_child = Some(node);
+        self.undo_tree.current = parent;
+        caret
+    }
+
+    /// Replays the edits of a child of the current undo tree node and
+    /// moves to it. Returns the caret position after those edits.
+    fn apply_child_node(&mut self, node: usize) -> usize {
+        let ops = std::mem::take(&mut self.undo_tree.nodes[node].group.ops);
+        let mut caret = self.caret;
+        
+        for (op, _, after) in &ops {
+            match op {
+                EditOp::Insert { pos, text } => self.shift_with_edit(true, *pos, text),
+                EditOp::Delete { pos, text } => self.shift_with_edit(false, *pos, text),
+            }
+            match op {
+                EditOp::Insert { pos, text } => {
+                    let safe_pos = (*pos).min(self.rope.len_bytes());
+                    self.rope.insert(self.rope.byte_to_char(safe_pos), text);
+                }
+                EditOp::Delete { pos, text } => {
+                    // Ensure positions are within bounds
+                    let safe_pos = (*pos).min(self.rope.len_bytes());
+                    let safe_end = (pos + text.len()).min(self.rope.len_bytes());
+                    if safe_pos < self.rope.len_bytes() && safe_end <= self.rope.len_bytes() {
+                        let char_pos = self.rope.byte_to_char(safe_pos);
+                        let char_end = self.rope.byte_to_char(safe_end);
+                        self.rope.remove(char_pos..char_end);
+                    }
+                }
+            }
+            caret = *after;
+        }
+        
+        self.undo_tree.nodes[node].group.ops = ops;
+        let parent = self.undo_tree.nodes[node].parent;
+        self.undo_tree.nodes[parent].redo_child = Some(node);
+        self.undo_tree.current = node;
+        caret
+    }
+
+    /// Moves what points into the buffer along with an edit of `text` at
+    /// `pos`: folds, and the carets of other panes showing the buffer.
+    fn shift_with_edit(&mut self, insert: bool, pos: usize, text: &str) {
+        self.shift_folds(insert, pos, text);
+        
+        let buffer = self.panes[self.focused_pane].buffer;
+        let len = text.len();
+        let shift = |offset: &mut usize| {
+            if insert {
+                if *offset >= pos {
+                    *offset += len;
+                }
+            } else if *offset >= pos + len {
+                *offset -= len;
+            } else if *offset > pos {
+                *offset = pos;
+            }
+        };
+        for (index, pane) in self.panes.iter_mut().enumerate() {
+            if index == self.focused_pane || pane.buffer != buffer {
+                continue;
+            }
+            let view = &mut pane.view;
+            shift(&mut view.caret);
+            if let Some(anchor) = &mut view.selection_anchor {
+                shift(anchor);
+            }
+            for cursor in &mut view.extra_cursors {
+                shift(&mut cursor.caret);
+                if let Some(anchor) = &mut cursor.anchor {
+                    shift(anchor);
+                }
+            }
+            view.block_anchor = None;
+            view.visual_lines_valid = false;
+        }
     }
 
-    fn save(&mut self) -> io::Result<()> {
-        if let Some(ref path) = self.filename {
-            let content = self.rope.to_string();
-            fs::write(path, content)?;
-            self.modified = false;
-            Ok(())
-        } else {
-            Err(io::Error::new(io::ErrorKind::Other, "No filename"))
+    /// Exchanges the focused view with a parked one.
+    fn swap_view(&mut self, view: &mut View) {
+        std::mem::swap(&mut self.caret, &mut view.caret);
+        std::mem::swap(&mut self.selection_anchor, &mut view.selection_anchor);
+        std::mem::swap(&mut self.preferred_col, &mut view.preferred_col);
+        std::mem::swap(&mut self.extra_cursors, &mut view.extra_cursors);
+        std::mem::swap(&mut self.block_anchor, &mut view.block_anchor);
+        std::mem::swap(&mut self.block_cursor, &mut view.block_cursor);
+        std::mem::swap(&mut self.viewport_offset, &mut view.viewport_offset);
+        std::mem::swap(&mut self.visual_lines, &mut view.visual_lines);
+        std::mem::swap(&mut self.visual_lines_valid, &mut view.visual_lines_valid);
+        std::mem::swap(&mut self.logical_line_map, &mut view.logical_line_map);
+        std::mem::swap(&mut self.viewport_follows_caret, &mut view.viewport_follows_caret);
+    }
+
+    /// Exchanges the focused buffer with a parked one.
+    fn swap_buffer(&mut self, buffer: &mut Buffer) {
+        std::mem::swap(&mut self.rope, &mut buffer.rope);
+        std::mem::swap(&mut self.filename, &mut buffer.filename);
+        std::mem::swap(&mut self.modified, &mut buffer.modified);
+        std::mem::swap(&mut self.saved_state, &mut buffer.saved_state);
+        std::mem::swap(&mut self.saved_len, &mut buffer.saved_len);
+        std::mem::swap(&mut self.saved_hash, &mut buffer.saved_hash);
+        std::mem::swap(&mut self.task_counts, &mut buffer.task_counts);
+        std::mem::swap(&mut self.bracket_marks, &mut buffer.bracket_marks);
+        std::mem::swap(&mut self.flagged_chars, &mut buffer.flagged_chars);
+        std::mem::swap(&mut self.last_paste, &mut buffer.last_paste);
+        std::mem::swap(&mut self.auto_closed, &mut buffer.auto_closed);
+        std::mem::swap(&mut self.folds, &mut buffer.folds);
+        std::mem::swap(&mut self.undo_tree, &mut buffer.undo_tree);
+        std::mem::swap(&mut self.last_step, &mut buffer.last_step);
+        std::mem::swap(&mut self.last_step_carets, &mut buffer.last_step_carets);
+        std::mem::swap(&mut self.find_matches, &mut buffer.find_matches);
+        std::mem::swap(&mut self.current_match_index, &mut buffer.current_match_index);
+    }
+
+    /// Swaps `pane` into the editor's fields, parking the focused one.
+    fn switch_to_pane(&mut self, pane: usize) {
+        let old = self.focused_pane;
+        if pane == old {
+            return;
+        }
+        let mut view = std::mem::take(&mut self.panes[pane].view);
+        self.swap_view(&mut view);
+        self.panes[old].view = view;
+        
+        let (from, to) = (self.panes[old].buffer, self.panes[pane].buffer);
+        if from != to {
+            let mut buffer = std::mem::replace(&mut self.buffers[to], Buffer::new());
+            self.swap_buffer(&mut buffer);
+            self.buffers[from] = buffer;
+        }
+        self.focused_pane = pane;
+        
+        // The buffer may have been replaced while the view was parked
+        let len = self.rope.len_bytes();
+        self.caret = self.caret.min(len);
+        self.selection_anchor = self.selection_anchor.map(|anchor| anchor.min(len));
+        for cursor in &mut self.extra_cursors {
+            cursor.caret = cursor.caret.min(len);
+            cursor.anchor = cursor.anchor.map(|anchor| anchor.min(len));
         }
     }
 
-    fn save_as(&mut self, path: PathBuf) -> io::Result<()> {
-        let content = self.rope.to_string();
-        fs::write(&path, content)?;
-        self.filename = Some(path);
-        self.modified = false;
-        Ok(())
+    /// Runs `f` on `pane` as if it had focus, e.g. to draw it.
+    fn with_pane<R>(&mut self, pane: usize, f: impl FnOnce(&mut Self) -> R) -> R {
+        let focused = self.focused_pane;
+        self.switch_to_pane(pane);
+        let result = f(self);
+        self.switch_to_pane(focused);
+        result
     }
 
-    fn get_save_path_suggestion(&self) -> String {
-        if let Some(ref path) = self.filename {
-            path.to_string_lossy().to_string()
-        } else {
-            let mut path = self.current_dir.clone();
-            path.push("");
-            path.to_string_lossy().to_string()
+    /// Gives `pane` the focus. Edits in different panes never share an
+    /// undo group.
+    fn focus_pane(&mut self, pane: usize) {
+        if pane == self.focused_pane || pane >= self.panes.len() {
+            return;
         }
+        self.finalize_undo_group();
+        self.last_step = None;
+        self.switch_to_pane(pane);
     }
 
-    fn load_file(&mut self, path: PathBuf) -> io::Result<()> {
-        let content = fs::read_to_string(&path)?;
-        self.rope = Rope::from_str(&content);
-        self.filename = Some(path.clone());
+    /// Moves the focus to the next pane in screen order, or the previous one.
+    fn focus_next_pane(&mut self, forward: bool) {
+        let order = self.pane_layout.panes();
+        let index = order.iter().position(|&pane| pane == self.focused_pane).unwrap_or(0);
+        let next = if forward {
+            (index + 1) % order.len()
+        } else {
+            (index + order.len() - 1) % order.len()
+        };
+        self.focus_pane(order[next]);
+    }
+
+    /// Splits the focused pane in two views of its buffer, side by side
+    /// (`Horizontal`) or stacked (`Vertical`), and focuses the new one.
+    fn split_pane(&mut self, direction: Direction) {
+        let view = View {
+            caret: self.caret,
+            selection_anchor: self.selection_anchor,
+            preferred_col: self.preferred_col,
+            extra_cursors: self.extra_cursors.clone(),
+            viewport_offset: self.viewport_offset,
+            viewport_follows_caret: self.viewport_follows_caret,
+            ..View::default()
+        };
+        let new_pane = self.panes.len();
+        self.panes.push(Pane { view, buffer: self.panes[self.focused_pane].buffer });
+        self.pane_layout.split(self.focused_pane, direction, new_pane);
+        self.invalidate_views();
+        self.focus_pane(new_pane);
+    }
+
+    /// Opens `path` in a new pane beside the focused one. A file that does
+    /// not exist yet starts out empty, as on the command line.
+    fn open_in_new_pane(&mut self, path: PathBuf) {
+        let new_pane = self.panes.len();
+        let view = View { viewport_follows_caret: true, ..View::default() };
+        self.panes.push(Pane { view, buffer: self.buffers.len() });
+        self.buffers.push(Buffer::new());
+        self.pane_layout.split(self.focused_pane, Direction::Horizontal, new_pane);
+        self.invalidate_views();
+        self.focus_pane(new_pane);
         
-        // Update current directory to the file's directory
-        if let Some(parent) = path.parent() {
-            self.current_dir = parent.to_path_buf();
+        self.filename = Some(path.clone());
+        if self.load_file(path).is_ok() {
+            self.modified = false;
         }
+    }
+
+    /// Whether closing the focused pane would lose unsaved changes: no
+    /// other pane shows its buffer.
+    fn closing_pane_loses_changes(&self) -> bool {
+        let buffer = self.panes[self.focused_pane].buffer;
+        self.modified && self.panes.iter().filter(|pane| pane.buffer == buffer).count() == 1
+    }
+
+    /// Closes the focused pane, handing its room and the focus to its
+    /// neighbour. A buffer that no pane shows any more is dropped. The last
+    /// pane cannot be closed.
+    fn close_pane(&mut self) {
+        if self.panes.len() == 1 {
+            return;
+        }
+        let closing = self.focused_pane;
+        let order = self.pane_layout.panes();
+        let index = order.iter().position(|&pane| pane == closing).unwrap_or(0);
+        let neighbour = if index == 0 { order[1] } else { order[index - 1] };
+        self.focus_pane(neighbour);
         
-        self.caret = 0;
-        self.selection_anchor = None;
-        self.preferred_col = 0;
-        self.modified = false;
+        let buffer = self.panes.remove(closing).buffer;
+        self.pane_layout.remove(closing);
+        if self.focused_pane > closing {
+            self.focused_pane -= 1;
+        }
+        if !self.panes.iter().any(|pane| pane.buffer == buffer) {
+            self.buffers.remove(buffer);
+            for pane in &mut self.panes {
+                if pane.buffer > buffer {
+                    pane.buffer -= 1;
+                }
+            }
+        }
+        self.invalidate_views();
+    }
+
+    /// Grows the focused pane by `delta` percent, or shrinks it.
+    fn resize_pane(&mut self, delta: i16) {
+        if self.pane_layout.resize(self.focused_pane, delta) {
+            self.invalidate_views();
+        }
+    }
+
+    /// Where each pane goes when the panes share `area`.
+    fn pane_areas(&self, area: Rect) -> Vec<(usize, Rect)> {
+        let mut panes = Vec::new();
+        self.pane_layout.arrange(area, &mut panes, &mut Vec::new());
+        panes
+    }
+
+    /// The text area of the focused pane, left of its scrollbar, when the
+    /// panes share `area`.
+    fn focused_pane_area(&self, area: Rect) -> Rect {
+        let pane_area = self.pane_areas(area)
+            .into_iter()
+            .find(|&(pane, _)| pane == self.focused_pane)
+            .map_or(area, |(_, rect)| rect);
+        split_scrollbar(pane_area).0
+    }
+
+    /// The pane at a screen cell, if any.
+    fn pane_at(&self, area: Rect, col: u16, row: u16) -> Option<(usize, Rect)> {
+        self.pane_areas(area)
+            .into_iter()
+            .find(|&(_, rect)| col >= rect.x && col < rect.x + rect.width && row >= rect.y && row < rect.y + rect.height)
+    }
+
+    /// Drops the visual lines of every pane, e.g. after pane sizes or
+    /// layout settings changed.
+    fn invalidate_views(&mut self) {
         self.invalidate_visual_lines();
         self.logical_line_map.clear();
-        self.undo_stack.clear();
-        self.redo_stack.clear();
-        Ok(())
+        for pane in &mut self.panes {
+            pane.view.visual_lines_valid = false;
+            pane.view.logical_line_map.clear();
+        }
     }
 
-    fn has_selection(&self) -> bool {
-        self.selection_anchor.is_some()
+    /// Asks whether to save the first buffer, counting from `from`, with
+    /// unsaved changes, or exits once there is none.
+    fn confirm_quit_from(&mut self, from: usize) {
+        self.app_state = if self.focus_unsaved_buffer(from) {
+            AppState::Prompting(Prompt::new_confirm_save())
+        } else {
+            AppState::Exiting
+        };
     }
 
-    fn get_selection_range(&self) -> Option<(usize, usize)> {
-        self.selection_anchor.map(|anchor| {
-            if anchor <= self.caret {
-                (anchor, self.caret)
+    /// Focuses a pane on the first buffer, counting from `from`, that has
+    /// unsaved changes. Returns false if there is none.
+    fn focus_unsaved_buffer(&mut self, from: usize) -> bool {
+        let focused_buffer = self.panes[self.focused_pane].buffer;
+        let unsaved = (from..self.buffers.len()).find(|&buffer| {
+            if buffer == focused_buffer {
+                self.modified
             } else {
-                (self.caret, anchor)
+                self.buffers[buffer].modified
             }
-        })
+        });
+        let Some(buffer) = unsaved else {
+            return false;
+        };
+        if let Some(pane) = self.pane_layout.panes().into_iter().find(|&pane| self.panes[pane].buffer == buffer) {
+            self.focus_pane(pane);
+        }
+        true
     }
 
-    fn clear_selection(&mut self) {
-        self.selection_anchor = None;
+    /// Moves folds along with an edit of `text` at `pos`. Typing on a
+    /// header line keeps its fold; any other edit that touches a fold, or
+    /// splits its header, opens it.
+    fn shift_folds(&mut self, insert: bool, pos: usize, text: &str) {
+        if self.folds.is_empty() {
+            return;
+        }
+        let len = text.len();
+        let on_one_line = !text.contains('\n');
+        self.folds.retain_mut(|fold| {
+            if insert {
+                if pos <= fold.start {
+                    fold.start += len;
+                    fold.header_end += len;
+                    fold.end += len;
+                } else if pos <= fold.header_end && on_one_line {
+                    fold.header_end += len;
+                    fold.end += len;
+                } else if pos <= fold.end {
+                    return false;
+                }
+            } else if pos + len <= fold.start {
+                fold.start -= len;
+                fold.header_end -= len;
+                fold.end -= len;
+            } else if pos >= fold.start && pos + len <= fold.header_end && on_one_line {
+                fold.header_end -= len;
+                fold.end -= len;
+            } else if pos <= fold.end {
+                return false;
+            }
+            true
+        });
+        self.visual_lines_valid = false;
     }
 
-    fn delete_selection(&mut self) -> bool {
-        if let Some((start, end)) = self.get_selection_range() {
-            if start < end {
-                let text = self.rope.byte_slice(start..end).to_string();
-                let before = self.caret;
-                
-                let start_char = self.rope.byte_to_char(start);
-                let end_char = self.rope.byte_to_char(end);
-                self.rope.remove(start_char..end_char);
-                
-                self.caret = start;
-                self.push_op(EditOp::Delete { pos: start, text }, before, self.caret);
-                
-                self.invalidate_visual_lines();
-                self.clear_selection();
-                return true;
+    /// Opens folds that a caret has moved into, such as by a search jump.
+    fn open_folds_at_carets(&mut self) {
+        if self.folds.is_empty() {
+            return;
+        }
+        let carets: Vec<usize> = std::iter::once(self.caret)
+            .chain(self.extra_cursors.iter().map(|cursor| cursor.caret))
+            .collect();
+        let count = self.folds.len();
+        self.folds.retain(|fold| !carets.iter().any(|&caret| caret > fold.header_end && caret <= fold.end));
+        if self.folds.len() != count {
+            self.visual_lines_valid = false;
+        }
+    }
+
+    /// Last line of the region that `line_idx` opens: up to the matching
+    /// `}}}` marker, else to the matching bracket in files of a known
+    /// language, else over the following lines indented deeper.
+    fn fold_region_end(&mut self, line_idx: usize) -> Option<usize> {
+        let (line_start, text) = self.line_text(line_idx);
+        let line_count = self.rope.len_lines();
+        let marker_depth = |text: &str| text.matches("{{{").count() as isize - text.matches("}}}").count() as isize;
+        if marker_depth(&text) > 0 {
+            let mut depth = 0;
+            for idx in line_idx..line_count {
+                depth += marker_depth(&self.line_text(idx).1);
+                if depth <= 0 {
+                    return Some(idx);
+                }
             }
+            // An unclosed marker folds to the end of the file
+            return Some(line_count - 1).filter(|&last| last > line_idx);
         }
-        false
+        
+        let line_end = line_start + text.len();
+        let marks = self.bracket_marks();
+        let first = marks.partition_point(|mark| mark.pos < line_start);
+        let close = marks[first..]
+            .iter()
+            .take_while(|mark| mark.pos < line_end)
+            .filter(|mark| mark.structural)
+            .filter_map(|mark| mark.partner.filter(|&partner| partner > line_end))
+            .max();
+        if let Some(close) = close {
+            let close_line = self.rope.byte_to_line(close);
+            let (close_start, close_text) = self.line_text(close_line);
+            // Leave a line that starts with the closing bracket in view
+            let leads_line = close - close_start == close_text.len() - close_text.trim_start().len();
+            let last = if leads_line { close_line - 1 } else { close_line };
+            if last > line_idx {
+                return Some(last);
+            }
+        }
+        
+        if text.trim().is_empty() {
+            return None;
+        }
+        let indent_width = |text: &str| self.display_width_from(&text[..text.len() - text.trim_start().len()], 0);
+        let base = indent_width(&text);
+        let mut last = line_idx;
+        for idx in line_idx + 1..line_count {
+            let next = self.line_text(idx).1;
+            if next.trim().is_empty() {
+                continue;
+            }
+            if indent_width(&next) <= base {
+                break;
+            }
+            last = idx;
+        }
+        Some(last).filter(|&last| last > line_idx)
     }
 
-    fn push_op(&mut self, op: EditOp, caret_before: usize, caret_after: usize) {
-        let now = Instant::now();
-        let new_group = self.last_edit_time
-            .map_or(true, |t| now.duration_since(t) > Duration::from_secs(1));
+    fn add_fold(&mut self, header: usize, last: usize) {
+        let (start, header_text) = self.line_text(header);
+        let (last_start, last_text) = self.line_text(last);
+        let fold = Fold { start, header_end: start + header_text.len(), end: last_start + last_text.len() };
+        // Keep the caret in view on the summary row
+        if self.caret > fold.header_end && self.caret <= fold.end {
+            self.caret = fold.header_end;
+            self.selection_anchor = None;
+        }
+        self.folds.push(fold);
+    }
 
-        if new_group {
-            if let Some(group) = self.current_group.take() {
-                self.undo_stack.push(group);
+    /// Unfolds the fold headed by the caret line, or else folds the region
+    /// that line opens, or the innermost region around it.
+    fn toggle_fold(&mut self, viewport_width: usize) {
+        self.enable_viewport_following();
+        self.clear_extra_cursors();
+        let line_idx = self.rope.byte_to_line(self.caret);
+        let count = self.folds.len();
+        let rope = &self.rope;
+        self.folds.retain(|fold| rope.byte_to_line(fold.start) != line_idx);
+        if self.folds.len() == count {
+            let region = match self.fold_region_end(line_idx) {
+                Some(last) => Some((line_idx, last)),
+                None => (0..line_idx)
+                    .rev()
+                    .find_map(|header| self.fold_region_end(header).filter(|&last| last >= line_idx).map(|last| (header, last))),
+            };
+            if let Some((header, last)) = region {
+                self.add_fold(header, last);
             }
-            self.current_group = Some(UndoGroup {
-                ops: vec![(op, caret_before, caret_after)],
-                timestamp: now,
-            });
-        } else if let Some(ref mut group) = self.current_group {
-            group.ops.push((op, caret_before, caret_after));
         }
-
-        self.redo_stack.clear();
-        self.last_edit_time = Some(now);
-        self.modified = true;
+        
+        self.visual_lines_valid = false;
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
     }
 
-    fn finalize_undo_group(&mut self) {
-        if let Some(group) = self.current_group.take() {
-            if !group.ops.is_empty() {
-                self.undo_stack.push(group);
+    /// Folds every outermost region in the file, or unfolds everything if
+    /// anything is folded.
+    fn toggle_all_folds(&mut self, viewport_width: usize) {
+        self.enable_viewport_following();
+        self.clear_extra_cursors();
+        if self.folds.is_empty() {
+            let mut line_idx = 0;
+            while line_idx < self.rope.len_lines() {
+                match self.fold_region_end(line_idx) {
+                    Some(last) => {
+                        self.add_fold(line_idx, last);
+                        line_idx = last + 1;
+                    }
+                    None => line_idx += 1,
+                }
             }
+        } else {
+            self.folds.clear();
         }
+        
+        self.visual_lines_valid = false;
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
+    }
+
+    fn finish_history_move(&mut self, caret: usize) {
+        // Ensure caret is within valid bounds
+        self.caret = caret.min(self.rope.len_bytes());
+        self.auto_closed.clear();
+        self.clear_selection();
+        self.clear_extra_cursors();
+        self.invalidate_visual_lines();
+        self.logical_line_map.clear();
+        self.update_modified();
     }
 
     fn undo(&mut self) {
         self.finalize_undo_group();
         
-        if let Some(group) = self.undo_stack.pop() {
-            let mut caret = self.caret;
-            
-            for (op, before, _) in group.ops.iter().rev() {
-                match op {
-                    EditOp::Insert { pos, text } => {
-                        // Ensure positions are within bounds
-                        let safe_pos = (*pos).min(self.rope.len_bytes());
-                        let safe_end = (pos + text.len()).min(self.rope.len_bytes());
-                        if safe_pos < self.rope.len_bytes() && safe_end <= self.rope.len_bytes() {
-                            let char_pos = self.rope.byte_to_char(safe_pos);
-                            let char_end = self.rope.byte_to_char(safe_end);
-                            self.rope.remove(char_pos..char_end);
-                        }
-                    }
-                    EditOp::Delete { pos, text } => {
-                        let safe_pos = (*pos).min(self.rope.len_bytes());
-                        self.rope.insert(self.rope.byte_to_char(safe_pos), text);
-                    }
-                }
-                caret = *before;
-            }
-            
-            // Ensure caret is within valid bounds
-            self.caret = caret.min(self.rope.len_bytes());
-            self.clear_selection();
-            self.invalidate_visual_lines();
-            self.logical_line_map.clear();
-            self.redo_stack.push(group);
-            self.modified = !self.undo_stack.is_empty();
+        if self.undo_tree.current != 0 {
+            let caret = self.revert_current_node();
+            self.finish_history_move(caret);
         }
     }
 
+    /// Redoes along the branch that was most recently undone or created.
     fn redo(&mut self) {
-        if let Some(group) = self.redo_stack.pop() {
-            let mut caret = self.caret;
-            
-            for (op, _, after) in &group.ops {
-                match op {
-                    EditOp::Insert { pos, text } => {
-                        let safe_pos = (*pos).min(self.rope.len_bytes());
-                        self.rope.insert(self.rope.byte_to_char(safe_pos), text);
-                    }
-                    EditOp::Delete { pos, text } => {
-                        // Ensure positions are within bounds
-                        let safe_pos = (*pos).min(self.rope.len_bytes());
-                        let safe_end = (pos + text.len()).min(self.rope.len_bytes());
-                        if safe_pos < self.rope.len_bytes() && safe_end <= self.rope.len_bytes() {
-                            let char_pos = self.rope.byte_to_char(safe_pos);
-                            let char_end = self.rope.byte_to_char(safe_end);
-                            self.rope.remove(char_pos..char_end);
-                        }
-                    }
-                }
-                caret = *after;
-            }
-            
-            // Ensure caret is within valid bounds
-            self.caret = caret.min(self.rope.len_bytes());
-            self.clear_selection();
-            self.invalidate_visual_lines();
-            self.logical_line_map.clear();
-            self.undo_stack.push(group);
-            self.modified = true;
+        self.finalize_undo_group();
+        
+        let current = &self.undo_tree.nodes[self.undo_tree.current];
+        if let Some(child) = current.redo_child.or(current.children.last().copied()) {
+            let caret = self.apply_child_node(child);
+            self.finish_history_move(caret);
         }
     }
 
-    fn calculate_indent(line: &str) -> usize {
-        let trimmed = line.trim_start();
-        let base_indent = line.len() - trimmed.len();
+    /// Moves the buffer to any state in the undo tree by undoing up to the
+    /// common ancestor and redoing down the target's branch.
+    fn goto_undo_state(&mut self, target: usize) {
+        self.finalize_undo_group();
+        if target >= self.undo_tree.nodes.len() || target == self.undo_tree.current {
+            return;
+        }
         
-        if trimmed.starts_with("- ") || trimmed.starts_with("* ") || trimmed.starts_with("+ ")  || trimmed.starts_with("▢ ")  || trimmed.starts_with("◪ ")  || trimmed.starts_with("■ ") {
-            return base_indent + 4;
+        let path = self.undo_tree.path_to(target);
+        let mut caret = self.caret;
+        while !path.contains(&self.undo_tree.current) {
+            caret = self.revert_current_node();
+        }
+        let fork = path.iter().position(|&node| node == self.undo_tree.current).unwrap_or(0);
+        for &node in &path[fork + 1..] {
+            caret = self.apply_child_node(node);
+        }
+        self.finish_history_move(caret);
+    }
+
+    /// Steps to the previous or next state in the order the states were
+    /// created, crossing between branches.
+    fn step_undo_state(&mut self, later: bool) {
+        self.finalize_undo_group();
+        let current = self.undo_tree.current;
+        if later && current + 1 < self.undo_tree.nodes.len() {
+            self.goto_undo_state(current + 1);
+        } else if !later && current > 0 {
+            self.goto_undo_state(current - 1);
         }
+    }
+
+    /// Jumps to the newest state at least `amount` older (or, going
+    /// later, at most `amount` newer) than the current one.
+    fn jump_undo_time(&mut self, later: bool, amount: Duration) {
+        self.finalize_undo_group();
+        let now = self.undo_tree.nodes[self.undo_tree.current].group.timestamp;
+        let target = if later {
+            now.checked_add(amount).map(|time| self.undo_tree.state_at(time).max(self.undo_tree.current))
+        } else {
+            now.checked_sub(amount).map(|time| self.undo_tree.state_at(time))
+        };
+        self.goto_undo_state(target.unwrap_or(0));
+    }
+
+    /// One line per undo tree state for the visualizer, indented by branch.
+    fn undo_tree_entries(&self) -> Vec<(usize, String)> {
+        let now = SystemTime::now();
+        let snippet = |text: &str| -> String {
+            let flat: String = text.chars().take(24).map(|ch| if ch == '\n' { '⏎' } else { ch }).collect();
+            if text.chars().count() > 24 { format!("{}…", flat) } else { flat }
+        };
         
-        let mut chars = trimmed.chars();
-        let mut num_count = 0;
-        while let Some(ch) = chars.next() {
-            if ch.is_alphanumeric() {
-                num_count += 1;
-            } else if num_count > 0 && (ch == '.' || ch == ')') {
-                if chars.next() == Some(' ') {
-                    return base_indent + 4;
-                }
-                break;
-            } else {
-                break;
-            }
+        self.undo_tree.layout()
+            .into_iter()
+            .map(|(node, depth)| {
+                let group = &self.undo_tree.nodes[node].group;
+                let age = format_age(now.duration_since(group.timestamp).unwrap_or_default());
+                let summary = match group.ops.first() {
+                    None => "original".to_string(),
+                    Some((EditOp::Insert { text, .. }, _, _)) => format!("+\"{}\"", snippet(text)),
+                    Some((EditOp::Delete { text, .. }, _, _)) => format!("-\"{}\"", snippet(text)),
+                };
+                let more = if group.ops.len() > 1 { format!(" (+{} edits)", group.ops.len() - 1) } else { String::new() };
+                (node, format!("{}{:>4}  {:>7}  {}{}", "  ".repeat(depth), node, age, summary, more))
+            })
+            .collect()
+    }
+
+    fn calculate_indent(line: &str, tab_width: usize) -> usize {
+        let trimmed = line.trim_start();
+        let base_indent = line[..line.len() - trimmed.len()]
+            .graphemes(true)
+            .fold(0, |col, g| col + grapheme_display_width(g, col, tab_width));
+        
+        // Any labelled line hangs, though only short labels continue as
+        // lists on Enter
+        if list_marker(trimmed).is_some() || list_label_len(trimmed).is_some() {
+            return base_indent + 4;
         }
         
         base_indent
@@ -1135,13 +3998,33 @@ impl Editor {
             self.visual_lines.push(None);
         }
         
+        // Lines hidden by folds, and the number folded under each header
+        let line_count = self.rope.len_lines();
+        let mut hidden = vec![false; line_count];
+        let mut folded_lines = vec![0; line_count];
+        let len_bytes = self.rope.len_bytes();
+        self.folds.retain(|fold| fold.header_end < fold.end && fold.end <= len_bytes);
+        for fold in &self.folds {
+            let header = self.rope.byte_to_line(fold.start);
+            let last = self.rope.byte_to_line(fold.end);
+            folded_lines[header] = folded_lines[header].max(last - header);
+            for line_hidden in &mut hidden[header + 1..=last] {
+                *line_hidden = true;
+            }
+        }
+        
         let mut byte_pos = 0;
         
-        for line_idx in 0..self.rope.len_lines() {
+        for line_idx in 0..line_count {
             let line_start_idx = self.visual_lines.len();
             let line = self.rope.line(line_idx);
-            let line_str = line.to_string();
             let line_bytes = line.len_bytes();
+            if hidden[line_idx] {
+                self.logical_line_map.push((line_start_idx, 0));
+                byte_pos += line_bytes;
+                continue;
+            }
+            let line_str = line.to_string();
             
             if !self.word_wrap {
                 let has_newline = line_str.ends_with('\n');
@@ -1153,6 +4036,7 @@ impl Editor {
                     is_continuation: false,
                     indent: 0,
                     logical_line: line_idx,
+                    folded_lines: 0,
                 }));
             } else {
                 let has_newline = line_str.ends_with('\n');
@@ -1165,9 +4049,10 @@ impl Editor {
                         is_continuation: false,
                         indent: 0,
                         logical_line: line_idx,
+                        folded_lines: 0,
                     }));
                 } else {
-                    let indent = Self::calculate_indent(&line_str);
+                    let indent = Self::calculate_indent(&line_str, self.tab_width);
                     let segments = self.wrap_line(content, viewport_width, indent);
                     
                     for (i, (start, end)) in segments.into_iter().enumerate() {
@@ -1177,11 +4062,18 @@ impl Editor {
                             is_continuation: i > 0,
                             indent: if i > 0 { indent } else { 0 },
                             logical_line: line_idx,
+                            folded_lines: 0,
                         }));
                     }
                 }
             }
             
+            if folded_lines[line_idx] > 0 {
+                if let Some(Some(last_row)) = self.visual_lines.last_mut() {
+                    last_row.folded_lines = folded_lines[line_idx];
+                }
+            }
+            
             let line_visual_count = self.visual_lines.len() - line_start_idx;
             self.logical_line_map.push((line_start_idx, line_visual_count));
             
@@ -1191,12 +4083,20 @@ impl Editor {
         for _ in 0..self.virtual_lines {
             self.visual_lines.push(None);
         }
+        debug_assert!({
+            let rows = &self.visual_lines[self.virtual_lines..self.visual_lines.len() - self.virtual_lines];
+            rows.iter().all(Option::is_some)
+                && rows.windows(2).all(|pair| matches!(pair, [Some(a), Some(b)] if a.start_byte <= b.start_byte))
+        });
         
         self.visual_lines_valid = true;
     }
 
     fn invalidate_visual_lines(&mut self) {
         self.visual_lines_valid = false;
+        self.task_counts = None;
+        self.bracket_marks = None;
+        self.flagged_chars = None;
     }
 
     fn ensure_visual_lines(&mut self, viewport_width: usize) {
@@ -1218,82 +4118,39 @@ impl Editor {
             };
             
             if available_width == 0 {
-                break;
-            }
-            
-            let mut width = 0;
-            let mut end = start;
-            let mut last_break = start;
-            
-            let slice = if start <= content.len() {
-                content.chars().skip(content[..start].chars().count())
-            } else {
-                break;
-            };
-            
-            let mut char_offset = 0;
-            for ch in slice {
-                let ch_width = ch.to_string().width();
-                if width + ch_width > available_width && char_offset > 0 {
-                    end = if last_break > start { 
-                        last_break 
-                    } else {
-                        // Calculate the byte position for char_offset characters from start
-                        let mut byte_pos = start;
-                        for (idx, ch) in content[start..].chars().enumerate() {
-                            if idx >= char_offset {
-                                break;
-                            }
-                            byte_pos += ch.len_utf8();
-                        }
-                        byte_pos
-                    };
-                    break;
-                }
-                
-                width += ch_width;
-                if ch == ' ' || ch == '-' || ch == '/' {
-                    // Calculate byte position for the break point
-                    let mut byte_pos = start;
-                    for (idx, c) in content[start..].chars().enumerate() {
-                        if idx == char_offset {
-                            byte_pos += c.len_utf8();
-                            break;
-                        }
-                        byte_pos += c.len_utf8();
+                break;
+            }
+            
+            // Tab stops are measured from the row's first screen column
+            let base_col = if is_first { 0 } else { continuation_indent };
+            let mut width = 0;
+            let mut end = start;
+            let mut last_break = start;
+            
+            // Measure whole grapheme clusters so a segment never splits one
+            for (offset, g) in content[start..].grapheme_indices(true) {
+                let g_width = grapheme_display_width(g, base_col + width, self.tab_width);
+                if width + g_width > available_width && end > start {
+                    if last_break > start {
+                        end = last_break;
                     }
-                    last_break = byte_pos;
+                    break;
                 }
                 
-                // Calculate end byte position
-                let mut byte_pos = start;
-                for (idx, c) in content[start..].chars().enumerate() {
-                    if idx == char_offset {
-                        byte_pos += c.len_utf8();
-                        break;
-                    }
-                    byte_pos += c.len_utf8();
+                width += g_width;
+                end = start + offset + g.len();
+                if g == " " || g == "-" || g == "/" {
+                    last_break = end;
                 }
-                end = byte_pos;
-                
-                char_offset += 1;
             }
             
             segments.push((start, end));
             start = end;
             is_first = false;
             
-            // Skip spaces at the beginning of the next line, respecting UTF-8 boundaries
-            while start < content.len() {
-                if let Some(ch) = content[start..].chars().next() {
-                    if ch == ' ' {
-                        start += ch.len_utf8();
-                    } else {
-                        break;
-                    }
-                } else {
-                    break;
-                }
+            // Skip spaces at the beginning of the next line
+            while content[start..].starts_with(' ') {
+                start += 1;
             }
         }
         
@@ -1318,8 +4175,8 @@ impl Editor {
                 }
                 
                 if byte_pos >= vl.start_byte && byte_pos <= vl.end_byte {
-                    let text = &self.rope.byte_slice(vl.start_byte..byte_pos).to_string();
-                    let col = vl.indent + text.width();
+                    let text = self.rope.byte_slice(vl.start_byte..byte_pos).to_string();
+                    let col = self.display_width_from(&text, vl.indent);
                     return (row, col);
                 }
             }
@@ -1332,6 +4189,26 @@ impl Editor {
         }
     }
 
+    /// The visual row showing byte `pos`, or the header row of the fold
+    /// hiding it. Expects up-to-date visual lines, and relies on their
+    /// rows being in text order for the binary search.
+    fn visual_row_at(&self, pos: usize) -> usize {
+        let first = self.virtual_lines.min(self.visual_lines.len());
+        let rows = &self.visual_lines[first..];
+        let starts_before = rows.partition_point(|vline| vline.as_ref().is_some_and(|vline| vline.start_byte <= pos));
+        first + starts_before.saturating_sub(1)
+    }
+
+    /// Scrolls so the scrollbar thumb is centred on `bar_row` of a
+    /// scrollbar `height` rows tall.
+    fn scroll_to_scrollbar_row(&mut self, bar_row: usize, height: usize, viewport_width: usize) {
+        self.ensure_visual_lines(viewport_width);
+        let total = self.visual_lines.len();
+        let row = bar_row * total / height.max(1);
+        self.viewport_offset.0 = row.saturating_sub(height / 2).min(total.saturating_sub(height));
+        self.viewport_follows_caret = false;
+    }
+
     fn visual_to_byte(&mut self, row: usize, col: usize, viewport_width: usize) -> usize {
         self.ensure_visual_lines(viewport_width);
         
@@ -1340,18 +4217,17 @@ impl Editor {
                 return vline.start_byte;
             }
             
-            let adjusted_col = col.saturating_sub(vline.indent);
-            let slice = self.rope.byte_slice(vline.start_byte..vline.end_byte);
+            let slice = self.rope.byte_slice(vline.start_byte..vline.end_byte).to_string();
             
-            let mut width = 0;
+            let mut width = vline.indent;
             let mut byte_offset = 0;
             
-            for ch in slice.chars() {
-                if width >= adjusted_col {
+            for g in slice.graphemes(true) {
+                if width >= col {
                     break;
                 }
-                width += ch.to_string().width();
-                byte_offset += ch.len_utf8();
+                width += grapheme_display_width(g, width, self.tab_width);
+                byte_offset += g.len();
             }
             
             vline.start_byte + byte_offset
@@ -1360,6 +4236,34 @@ impl Editor {
         }
     }
 
+    /// Visual column reached after laying out `text` starting at `start_col`.
+    fn display_width_from(&self, text: &str, start_col: usize) -> usize {
+        text.graphemes(true)
+            .fold(start_col, |col, g| col + grapheme_display_width(g, col, self.tab_width))
+    }
+
+    /// Start of the grapheme cluster that ends at `byte_pos`.
+    fn prev_cluster_pos(&self, byte_pos: usize) -> usize {
+        if byte_pos == 0 {
+            return 0;
+        }
+        let line_idx = self.rope.byte_to_line(byte_pos - 1);
+        let line_start = self.rope.line_to_byte(line_idx);
+        let line = self.rope.line(line_idx).to_string();
+        line_start + prev_grapheme_boundary(&line, byte_pos - line_start)
+    }
+
+    /// End of the grapheme cluster that starts at `byte_pos`.
+    fn next_cluster_pos(&self, byte_pos: usize) -> usize {
+        if byte_pos >= self.rope.len_bytes() {
+            return self.rope.len_bytes();
+        }
+        let line_idx = self.rope.byte_to_line(byte_pos);
+        let line_start = self.rope.line_to_byte(line_idx);
+        let line = self.rope.line(line_idx).to_string();
+        line_start + next_grapheme_boundary(&line, byte_pos - line_start)
+    }
+
     fn move_up(&mut self, viewport_width: usize, extend_selection: bool) {
         self.enable_viewport_following();
         if extend_selection && self.selection_anchor.is_none() {
@@ -1416,12 +4320,9 @@ impl Editor {
         }
 
         if self.caret > 0 {
-            let char_idx = self.rope.byte_to_char(self.caret);
-            if char_idx > 0 {
-                self.caret = self.rope.char_to_byte(char_idx - 1);
-                let (_, col) = self.get_visual_position(self.caret, viewport_width);
-                self.preferred_col = col;
-            }
+            self.caret = self.prev_cluster_pos(self.caret);
+            let (_, col) = self.get_visual_position(self.caret, viewport_width);
+            self.preferred_col = col;
         }
     }
 
@@ -1444,15 +4345,265 @@ impl Editor {
         }
 
         if self.caret < self.rope.len_bytes() {
-            let char_idx = self.rope.byte_to_char(self.caret);
-            if char_idx < self.rope.len_chars() {
-                self.caret = self.rope.char_to_byte(char_idx + 1);
-                let (_, col) = self.get_visual_position(self.caret, viewport_width);
-                self.preferred_col = col;
+            self.caret = self.next_cluster_pos(self.caret);
+            let (_, col) = self.get_visual_position(self.caret, viewport_width);
+            self.preferred_col = col;
+        }
+    }
+
+    /// Byte offset and text of a logical line, without its line break.
+    fn line_text(&self, line_idx: usize) -> (usize, String) {
+        let line_start = self.rope.line_to_byte(line_idx);
+        let mut text = self.rope.line(line_idx).to_string();
+        let content_len = text.trim_end_matches(['\n', '\r']).len();
+        text.truncate(content_len);
+        (line_start, text)
+    }
+
+    /// Byte range of the whitespace at the end of a logical line, before
+    /// its line break. Walks back from the end rather than copying the
+    /// line, which may be very long.
+    fn trailing_whitespace(&self, line_idx: usize) -> (usize, usize) {
+        let line = self.rope.line(line_idx);
+        let mut end = self.rope.line_to_byte(line_idx) + line.len_bytes();
+        let mut chars = line.chars_at(line.len_chars()).reversed().peekable();
+        while let Some(ch) = chars.next_if(|ch| matches!(ch, '\n' | '\r')) {
+            end -= ch.len_utf8();
+        }
+        let start = end - chars.take_while(|ch| ch.is_whitespace()).map(char::len_utf8).sum::<usize>();
+        (start, end)
+    }
+
+    fn prev_word_pos(&self, byte_pos: usize, subword: bool) -> usize {
+        let line_idx = self.rope.byte_to_line(byte_pos);
+        let (line_start, text) = self.line_text(line_idx);
+        if byte_pos == line_start {
+            // Step over the line break onto the end of the previous line
+            return self.prev_cluster_pos(byte_pos);
+        }
+        line_start + prev_word_boundary(&text, (byte_pos - line_start).min(text.len()), subword)
+    }
+
+    fn next_word_pos(&self, byte_pos: usize, subword: bool) -> usize {
+        let line_idx = self.rope.byte_to_line(byte_pos);
+        let (line_start, text) = self.line_text(line_idx);
+        if byte_pos >= line_start + text.len() {
+            return self.next_cluster_pos(byte_pos);
+        }
+        line_start + next_word_boundary(&text, byte_pos - line_start, subword)
+    }
+
+    fn move_word_left(&mut self, viewport_width: usize, extend_selection: bool, subword: bool) {
+        self.enable_viewport_following();
+        if extend_selection && self.selection_anchor.is_none() {
+            self.selection_anchor = Some(self.caret);
+        } else if !extend_selection {
+            self.clear_selection();
+        }
+
+        self.caret = self.prev_word_pos(self.caret, subword);
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
+    }
+
+    fn move_word_right(&mut self, viewport_width: usize, extend_selection: bool, subword: bool) {
+        self.enable_viewport_following();
+        if extend_selection && self.selection_anchor.is_none() {
+            self.selection_anchor = Some(self.caret);
+        } else if !extend_selection {
+            self.clear_selection();
+        }
+
+        self.caret = self.next_word_pos(self.caret, subword);
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
+    }
+
+    fn delete_word_backward(&mut self, viewport_width: usize) {
+        self.enable_viewport_following();
+        if !self.delete_selection() {
+            let start = self.prev_word_pos(self.caret, false);
+            if start < self.caret {
+                self.selection_anchor = Some(start);
+                self.delete_selection();
+            }
+        }
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
+    }
+
+    fn delete_word_forward(&mut self, _viewport_width: usize) {
+        self.enable_viewport_following();
+        if self.delete_selection() {
+            return;
+        }
+        let end = self.next_word_pos(self.caret, false);
+        if end > self.caret {
+            self.selection_anchor = Some(end);
+            self.delete_selection();
+        }
+    }
+
+    /// Range of the word under the caret, or the one just before it.
+    fn word_range_at_caret(&self) -> Option<(usize, usize)> {
+        let (line_start, text) = self.line_text(self.rope.byte_to_line(self.caret));
+        let pos = (self.caret - line_start).min(text.len());
+        [pos, prev_grapheme_boundary(&text, pos)]
+            .into_iter()
+            .map(|pos| word_range_at(&text, pos))
+            .find(|&(start, end)| start < end && grapheme_class(&text[start..end]) == CharClass::Word)
+            .map(|(start, end)| (line_start + start, line_start + end))
+    }
+
+    /// Replaces the selection, or the word at the caret, with the result of
+    /// `transform` in one undo group, and selects the result.
+    fn transform_selection(&mut self, transform: TextTransform, viewport_width: usize) -> Result<(), String> {
+        let (start, end) = match self.get_selection_range().filter(|(start, end)| start < end) {
+            Some(range) => range,
+            None => self.word_range_at_caret().ok_or("Nothing to transform, select some text first")?,
+        };
+        let backwards = self.selection_anchor.is_some_and(|anchor| self.caret < anchor);
+        let text = self.rope.byte_slice(start..end).to_string();
+        let result = apply_text_transform(&text, transform)?;
+        
+        self.enable_viewport_following();
+        if result != text {
+            self.begin_undo_step(true);
+            self.splice(start, end - start, &result);
+            self.end_undo_step();
+        }
+        if backwards {
+            self.selection_anchor = Some(start + result.len());
+            self.caret = start;
+        } else {
+            self.selection_anchor = Some(start);
+            self.caret = start + result.len();
+        }
+        
+        self.invalidate_visual_lines();
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
+        Ok(())
+    }
+
+    fn select_word_at_caret(&mut self, viewport_width: usize) {
+        let line_idx = self.rope.byte_to_line(self.caret);
+        let (line_start, text) = self.line_text(line_idx);
+        let (start, end) = word_range_at(&text, (self.caret - line_start).min(text.len()));
+        self.selection_anchor = Some(line_start + start);
+        self.caret = line_start + end;
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
+    }
+
+    /// Brackets in the buffer, paired up. Computed on demand after edits.
+    fn bracket_marks(&mut self) -> &[BracketMark] {
+        if self.bracket_marks.is_none() {
+            let marks = if self.rope.len_bytes() > BRACKET_SCAN_LIMIT {
+                Vec::new()
+            } else {
+                scan_brackets(&self.rope.to_string(), language_for(&self.file_extension()).as_ref())
+            };
+            self.bracket_marks = Some(marks);
+        }
+        self.bracket_marks.as_deref().unwrap_or_default()
+    }
+
+    /// Positions of the invisible and look-alike characters that are
+    /// highlighted in the text, for the scrollbar.
+    fn flagged_chars(&mut self) -> &[usize] {
+        if self.flagged_chars.is_none() {
+            let mut flagged = Vec::new();
+            if self.rope.len_bytes() <= BRACKET_SCAN_LIMIT {
+                for line_idx in 0..self.rope.len_lines() {
+                    let (line_start, text) = self.line_text(line_idx);
+                    for (offset, g) in text.grapheme_indices(true) {
+                        if invisible_char_symbol(g).is_some() || is_confusable_space(g) {
+                            flagged.push(line_start + offset);
+                        }
+                    }
+                }
             }
+            self.flagged_chars = Some(flagged);
+        }
+        self.flagged_chars.as_deref().unwrap_or_default()
+    }
+
+    /// The bracket at the caret, or else the one just before it.
+    fn bracket_at_caret(&mut self) -> Option<BracketMark> {
+        let caret = self.caret;
+        let marks = self.bracket_marks();
+        let mark_at = |pos: usize| marks.binary_search_by_key(&pos, |mark| mark.pos).ok().map(|idx| marks[idx]);
+        mark_at(caret).or_else(|| caret.checked_sub(1).and_then(mark_at))
+    }
+
+    /// Positions of the innermost bracket pair around the caret.
+    fn enclosing_brackets(&mut self) -> Option<(usize, usize)> {
+        let caret = self.caret;
+        self.bracket_marks()
+            .iter()
+            .filter(|mark| mark.pos < caret && mark.structural && bracket_partner(mark.ch).is_some_and(|(_, is_open)| is_open))
+            .filter_map(|mark| mark.partner.filter(|&close| close >= caret).map(|close| (mark.pos, close)))
+            .max_by_key(|(open, _)| *open)
+    }
+
+    /// Moves the caret to the partner of the bracket at the caret, or to
+    /// the opening bracket around it.
+    fn jump_to_matching_bracket(&mut self, viewport_width: usize) {
+        let target = match self.bracket_at_caret() {
+            Some(mark) => mark.partner,
+            None => self.enclosing_brackets().map(|(open, _)| open),
+        };
+        if let Some(target) = target {
+            self.enable_viewport_following();
+            self.clear_extra_cursors();
+            self.clear_selection();
+            self.caret = target;
+            let (_, col) = self.get_visual_position(self.caret, viewport_width);
+            self.preferred_col = col;
+        }
+    }
+
+    /// Selects from the bracket at the caret through its partner, or the
+    /// contents of the brackets around the caret.
+    fn select_to_matching_bracket(&mut self, viewport_width: usize) {
+        let range = match self.bracket_at_caret() {
+            Some(mark) => mark.partner.map(|partner| (mark.pos.min(partner), mark.pos.max(partner) + 1)),
+            None => self.enclosing_brackets().map(|(open, close)| (open + 1, close)),
+        };
+        if let Some((start, end)) = range {
+            self.enable_viewport_following();
+            self.clear_extra_cursors();
+            self.selection_anchor = Some(start);
+            self.caret = end;
+            let (_, col) = self.get_visual_position(self.caret, viewport_width);
+            self.preferred_col = col;
         }
     }
 
+    fn select_line_at_caret(&mut self) {
+        let line_idx = self.rope.byte_to_line(self.caret);
+        self.selection_anchor = Some(self.rope.line_to_byte(line_idx));
+        self.caret = if line_idx + 1 < self.rope.len_lines() {
+            self.rope.line_to_byte(line_idx + 1)
+        } else {
+            self.rope.len_bytes()
+        };
+        self.preferred_col = 0;
+    }
+
+    /// Records a mouse press and returns how many presses in a row landed on
+    /// the same cell (1 = single, 2 = double, 3 = triple click).
+    fn register_click(&mut self, col: u16, row: u16) -> usize {
+        let now = Instant::now();
+        let is_repeat = self.last_click.is_some_and(|(time, last_col, last_row)| {
+            last_col == col && last_row == row && now.duration_since(time) < Duration::from_millis(400)
+        });
+        self.click_count = if is_repeat { self.click_count % 3 + 1 } else { 1 };
+        self.last_click = Some((now, col, row));
+        self.click_count
+    }
+
     fn insert_char(&mut self, ch: char, viewport_width: usize) {
         self.enable_viewport_following();
         self.delete_selection();
@@ -1469,50 +4620,342 @@ impl Editor {
         self.preferred_col = col;
     }
 
-    fn delete(&mut self, _viewport_width: usize) {
-        self.enable_viewport_following();
-        if self.delete_selection() {
-            return;
+    /// Types a character with auto-pairing for the file type: an opener
+    /// wraps the selection or gets its closer added, and typing a closer
+    /// that was added automatically steps over it.
+    fn type_char(&mut self, ch: char, viewport_width: usize) {
+        let pairs = auto_pairs(&self.file_extension());
+        let closer = pairs.iter().find(|(open, _)| *open == ch).map(|(_, close)| *close);
+        if !self.auto_pair || (closer.is_none() && !pairs.iter().any(|(_, close)| *close == ch)) {
+            self.insert_char(ch, viewport_width);
+            return;
+        }
+        self.enable_viewport_following();
+        
+        let selection = self.get_selection_range().filter(|(start, end)| start < end);
+        let next = self.rope.byte_slice(self.caret..).chars().next();
+        let caret_char = self.rope.byte_to_char(self.caret);
+        let prev = (caret_char > 0).then(|| self.rope.char(caret_char - 1));
+        if let (Some(close), Some((start, end))) = (closer, selection) {
+            let before = self.caret;
+            self.rope.insert_char(self.rope.byte_to_char(end), close);
+            self.push_op(EditOp::Insert { pos: end, text: close.to_string() }, before, before);
+            self.rope.insert_char(self.rope.byte_to_char(start), ch);
+            self.selection_anchor = Some(start + ch.len_utf8());
+            self.caret = end + ch.len_utf8();
+            self.push_op(EditOp::Insert { pos: start, text: ch.to_string() }, before, self.caret);
+        } else if selection.is_none() && next == Some(ch) && self.auto_closed.contains(&self.caret) {
+            let closer_pos = self.caret;
+            self.auto_closed.retain(|pos| *pos != closer_pos);
+            self.caret += ch.len_utf8();
+        } else if let Some(close) = closer.filter(|_| selection.is_none()) {
+            // Only pair where nothing follows that the pair would split, and
+            // not quotes next to a word or another quote, as in "don't"
+            let free_after = next
+                .filter(|&next| !next.is_whitespace() && !pairs.iter().any(|(_, close)| *close == next) && !",;:.".contains(next))
+                .is_none();
+            let free_before = ch != close || (next != Some(ch) && prev.filter(|&prev| prev.is_alphanumeric() || prev == '_' || prev == ch).is_none());
+            if !(free_after && free_before) {
+                self.insert_char(ch, viewport_width);
+                return;
+            }
+            let before = self.caret;
+            let pair = format!("{}{}", ch, close);
+            self.rope.insert(self.rope.byte_to_char(before), &pair);
+            self.caret = before + ch.len_utf8();
+            self.push_op(EditOp::Insert { pos: before, text: pair }, before, self.caret);
+            self.auto_closed.push(self.caret);
+        } else {
+            self.insert_char(ch, viewport_width);
+            return;
+        }
+        
+        self.invalidate_visual_lines();
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
+    }
+
+    fn delete(&mut self, _viewport_width: usize) {
+        self.enable_viewport_following();
+        if self.delete_selection() {
+            return;
+        }
+
+        if self.caret < self.rope.len_bytes() {
+            let end = self.next_cluster_pos(self.caret);
+            let text = self.rope.byte_slice(self.caret..end).to_string();
+            let before = self.caret;
+            
+            let start_char = self.rope.byte_to_char(self.caret);
+            let end_char = self.rope.byte_to_char(end);
+            self.rope.remove(start_char..end_char);
+            
+            self.push_op(EditOp::Delete { pos: self.caret, text }, before, self.caret);
+            
+            self.invalidate_visual_lines();
+        }
+    }
+
+    fn backspace(&mut self, _viewport_width: usize) {
+        self.enable_viewport_following();
+        if self.delete_selection() {
+            return;
+        }
+
+        if self.caret > 0 {
+            let start = self.prev_cluster_pos(self.caret);
+            let mut end = self.caret;
+            // Backspacing inside an empty pair that was typed with its
+            // closer added automatically removes both halves
+            let prev = self.rope.byte_slice(start..self.caret).to_string();
+            let next = self.rope.byte_slice(self.caret..).chars().next();
+            if self.auto_pair && self.auto_closed.contains(&self.caret) && auto_pairs(&self.file_extension()).iter().any(|(open, close)| prev == open.to_string() && next == Some(*close)) {
+                end += next.map_or(0, |close| close.len_utf8());
+            }
+            
+            let text = self.rope.byte_slice(start..end).to_string();
+            let before = self.caret;
+            
+            let start_char = self.rope.byte_to_char(start);
+            let end_char = self.rope.byte_to_char(end);
+            self.rope.remove(start_char..end_char);
+            self.caret = start;
+            
+            self.push_op(EditOp::Delete { pos: self.caret, text }, before, self.caret);
+            
+            self.invalidate_visual_lines();
+        }
+    }
+
+    /// Breaks the line at the caret, carrying the indentation forward,
+    /// continuing list items and indenting one level after a block opener.
+    /// Enter on an empty list item ends the list instead.
+    fn insert_newline(&mut self, viewport_width: usize) {
+        self.enable_viewport_following();
+        self.delete_selection();
+        
+        let (line_start, text) = self.line_text(self.rope.byte_to_line(self.caret));
+        let offset = self.caret - line_start;
+        let head = &text[..offset];
+        let indent = &text[..text.len() - text.trim_start().len()];
+        let indent = &indent[..indent.len().min(offset)];
+        let mut continuation = indent.to_string();
+        let mut closing = None;
+        
+        if let Some((marker, next_marker)) = list_marker(&text[indent.len()..]) {
+            if offset >= indent.len() + marker.len() {
+                if text[indent.len() + marker.len()..].trim().is_empty() {
+                    // Empty item: drop the marker and leave a blank line
+                    self.selection_anchor = Some(line_start);
+                    self.caret = line_start + text.len();
+                    self.delete_selection();
+                    self.invalidate_visual_lines();
+                    self.preferred_col = 0;
+                    return;
+                }
+                continuation.push_str(&next_marker);
+            }
+        } else {
+            let extension = self.file_extension();
+            let head = head.trim_end();
+            let opener = indent_openers(&extension).iter().find(|opener| {
+                head.ends_with(*opener) && (opener.len() == 1 || head.len() == opener.len()
+                    || !head[..head.len() - opener.len()].ends_with(|ch: char| ch.is_alphanumeric() || ch == '_'))
+            });
+            if let Some(opener) = opener {
+                let close = match *opener {
+                    "{" => Some('}'),
+                    "[" => Some(']'),
+                    "(" => Some(')'),
+                    _ => None,
+                };
+                // Caret between a pair: push the closer onto its own line
+                closing = close
+                    .filter(|&close| text[offset..].trim_start().starts_with(close))
+                    .map(|_| format!("\n{}", indent));
+                continuation.push_str(&self.indent_unit());
+            }
+        }
+        
+        self.insert_text(&format!("\n{}", continuation), viewport_width);
+        if let Some(closing) = closing {
+            let caret = self.caret;
+            self.insert_text(&closing, viewport_width);
+            self.caret = caret;
+        }
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
+    }
+
+    /// Advances the checkbox on each line under the cursor or selection:
+    /// ▢ → ◪ → ■ → ▢, and `[ ]` ↔ `[x]` for Markdown tasks. List items
+    /// without a checkbox get an open one, as does the caret line alone.
+    fn cycle_task_checkboxes(&mut self, viewport_width: usize) {
+        self.enable_viewport_following();
+        let (start_line, end_line) = self.selected_line_range();
+        
+        for line_idx in (start_line..=end_line).rev() {
+            let (line_start, text) = self.line_text(line_idx);
+            let (start, end, replacement) = match task_checkbox(&text) {
+                Some((start, end, state)) => {
+                    let replacement = match (text[start..].starts_with('['), state) {
+                        (true, TaskState::Done) => "[ ]",
+                        (true, _) => "[x]",
+                        (false, TaskState::Open) => "◪",
+                        (false, TaskState::InProgress) => "■",
+                        (false, TaskState::Done) => "▢",
+                    };
+                    (start, end, replacement.to_string())
+                }
+                None => {
+                    let indent = text.len() - text.trim_start().len();
+                    match list_marker(&text[indent..]) {
+                        Some(("- ", _)) | Some(("* ", _)) | Some(("+ ", _)) => {
+                            (indent + 2, indent + 2, "[ ] ".to_string())
+                        }
+                        Some(_) => (indent, indent, "▢ ".to_string()),
+                        // Across several lines, prose and blank lines are
+                        // left as they are
+                        None if start_line != end_line => continue,
+                        None => (indent, indent, "▢ ".to_string()),
+                    }
+                }
+            };
+            
+            let pos = line_start + start;
+            let removed = text[start..end].to_string();
+            let before = self.caret;
+            let shift = |offset: usize| {
+                if offset >= pos + removed.len() {
+                    offset - removed.len() + replacement.len()
+                } else {
+                    offset
+                }
+            };
+            
+            let start_char = self.rope.byte_to_char(pos);
+            if !removed.is_empty() {
+                let end_char = self.rope.byte_to_char(pos + removed.len());
+                self.rope.remove(start_char..end_char);
+                self.push_op(EditOp::Delete { pos, text: removed.clone() }, before, pos);
+            }
+            self.rope.insert(start_char, &replacement);
+            self.caret = shift(self.caret);
+            self.selection_anchor = self.selection_anchor.map(shift);
+            self.push_op(EditOp::Insert { pos, text: replacement.clone() }, pos, self.caret);
+        }
+        
+        self.invalidate_visual_lines();
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
+    }
+
+    /// Lines holding an open or in-progress task, with their trimmed text.
+    fn open_tasks(&self) -> Vec<(usize, String)> {
+        self.rope
+            .lines()
+            .enumerate()
+            .filter_map(|(line_idx, line)| {
+                let text = line.to_string();
+                match task_checkbox(&text) {
+                    Some((_, _, TaskState::Done)) | None => None,
+                    Some(_) => Some((line_idx, text.trim().to_string())),
+                }
+            })
+            .collect()
+    }
+
+    /// Moves the caret to the start of the task text on the given line.
+    fn jump_to_task(&mut self, line_idx: usize, viewport_width: usize) {
+        self.enable_viewport_following();
+        self.clear_extra_cursors();
+        self.clear_selection();
+        let (line_start, text) = self.line_text(line_idx);
+        let offset = task_checkbox(&text)
+            .map(|(_, end, _)| (end + 1).min(text.len()))
+            .unwrap_or(0);
+        self.caret = line_start + offset;
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
+    }
+
+    /// Status bar summary of task-list progress, empty when the buffer
+    /// has no checkboxes.
+    fn get_task_info(&mut self) -> String {
+        if self.task_counts.is_none() {
+            let mut done = 0;
+            let mut total = 0;
+            for line in self.rope.lines() {
+                if let Some((_, _, state)) = task_checkbox(&line.to_string()) {
+                    total += 1;
+                    if state == TaskState::Done {
+                        done += 1;
+                    }
+                }
+            }
+            self.task_counts = Some((done, total));
+        }
+        match self.task_counts {
+            Some((done, total)) if total > 0 => format!(" | Tasks {}/{}", done, total),
+            _ => String::new(),
+        }
+    }
+
+    fn get_macro_info(&self) -> String {
+        match (&self.recording, self.macro_register_pending) {
+            (Some((register, _)), _) if self.recording_missed_paste => format!(" | Recording @{} (paste not recorded)", register),
+            (Some((register, _)), _) => format!(" | Recording @{}", register),
+            (None, true) => " | Record into register?".to_string(),
+            _ => String::new(),
         }
+    }
 
-        if self.caret < self.rope.len_bytes() {
-            let char_idx = self.rope.byte_to_char(self.caret);
-            
-            if let Some(ch) = self.rope.get_char(char_idx) {
-                let before = self.caret;
-                self.rope.remove(char_idx..char_idx + 1);
-                
-                self.push_op(EditOp::Delete { pos: self.caret, text: ch.to_string() }, before, self.caret);
-                
-                self.invalidate_visual_lines();
+    /// Starts recording into the register named by the next key, or stops
+    /// the recording in progress and saves it.
+    fn toggle_macro_recording(&mut self) {
+        match self.recording.take() {
+            Some((register, keys)) => {
+                if !keys.is_empty() {
+                    self.macros.insert(register, keys);
+                    let _ = save_macros(&self.macros);
+                }
             }
+            None => self.macro_register_pending = true,
         }
     }
 
-    fn backspace(&mut self, _viewport_width: usize) {
-        self.enable_viewport_following();
-        if self.delete_selection() {
-            return;
+    /// Lowercased extension of the file being edited, empty if none.
+    fn file_extension(&self) -> String {
+        self.filename.as_ref()
+            .and_then(|path| path.extension())
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase())
+            .unwrap_or_default()
+    }
+
+    fn indent_unit(&self) -> String {
+        if self.use_hard_tabs {
+            "\t".to_string()
+        } else {
+            " ".repeat(self.tab_width)
         }
+    }
 
-        if self.caret > 0 {
-            let char_idx = self.rope.byte_to_char(self.caret);
-            if char_idx > 0 {
-                let ch = self.rope.char(char_idx - 1);
-                let ch_bytes = ch.len_utf8();
-                let before = self.caret;
-                
-                self.rope.remove(char_idx - 1..char_idx);
-                self.caret -= ch_bytes;
-                
-                self.push_op(EditOp::Delete { pos: self.caret, text: ch.to_string() }, before, self.caret);
-                
-                self.invalidate_visual_lines();
-            }
+    /// Leading indentation removed by one dedent step on the given line:
+    /// a single tab, or up to `tab_width` spaces.
+    fn dedent_prefix(&self, line_idx: usize) -> String {
+        let line = self.rope.line(line_idx);
+        if line.chars().next() == Some('\t') {
+            return "\t".to_string();
         }
+        line.chars()
+            .take(self.tab_width)
+            .take_while(|&ch| ch == ' ')
+            .collect()
     }
 
     fn indent(&mut self, viewport_width: usize) {
+        let unit = self.indent_unit();
         if let Some((start, end)) = self.get_selection_range() {
             // Handle selection - indent all lines in selection
             let start_char = self.rope.byte_to_char(start);
@@ -1529,20 +4972,20 @@ impl Editor {
                 let line_start = self.rope.line_to_char(line_idx);
                 let line_byte = self.rope.char_to_byte(line_start);
                 
-                self.rope.insert(line_start, "    ");
+                self.rope.insert(line_start, &unit);
                 
                 // Track adjustments for caret and anchor
                 if self.caret >= line_byte {
-                    caret_adjustment += 4;
+                    caret_adjustment += unit.len();
                 }
                 
                 if let Some(anchor) = self.selection_anchor {
                     if anchor >= line_byte {
-                        anchor_adjustment += 4;
+                        anchor_adjustment += unit.len();
                     }
                 }
                 
-                self.push_op(EditOp::Insert { pos: line_byte, text: "    ".to_string() }, before_caret, self.caret);
+                self.push_op(EditOp::Insert { pos: line_byte, text: unit.clone() }, before_caret, self.caret);
             }
             
             // Apply adjustments
@@ -1562,12 +5005,12 @@ impl Editor {
             let line_byte = self.rope.char_to_byte(line_start);
             
             let before = self.caret;
-            self.rope.insert(line_start, "    ");
+            self.rope.insert(line_start, &unit);
             if self.caret >= line_byte {
-                self.caret += 4;
+                self.caret += unit.len();
             }
             
-            self.push_op(EditOp::Insert { pos: line_byte, text: "    ".to_string() }, before, self.caret);
+            self.push_op(EditOp::Insert { pos: line_byte, text: unit }, before, self.caret);
             
             self.invalidate_visual_lines();
             
@@ -1590,16 +5033,8 @@ impl Editor {
             
             // Process lines from end to start to avoid offset issues
             for line_idx in (start_line..=end_line).rev() {
-                let line = self.rope.line(line_idx);
-                
-                let mut spaces = 0;
-                for ch in line.chars().take(4) {
-                    if ch == ' ' {
-                        spaces += 1;
-                    } else {
-                        break;
-                    }
-                }
+                let prefix = self.dedent_prefix(line_idx);
+                let spaces = prefix.len();
                 
                 if spaces > 0 {
                     let line_start = self.rope.line_to_char(line_idx);
@@ -1626,7 +5061,7 @@ impl Editor {
                         }
                     }
                     
-                    self.push_op(EditOp::Delete { pos: line_byte, text: " ".repeat(spaces) }, before_caret, self.caret);
+                    self.push_op(EditOp::Delete { pos: line_byte, text: prefix }, before_caret, self.caret);
                 }
             }
             
@@ -1643,16 +5078,8 @@ impl Editor {
             // No selection - dedent current line only
             let char_idx = self.rope.byte_to_char(self.caret);
             let line_idx = self.rope.char_to_line(char_idx);
-            let line = self.rope.line(line_idx);
-            
-            let mut spaces = 0;
-            for ch in line.chars().take(4) {
-                if ch == ' ' {
-                    spaces += 1;
-                } else {
-                    break;
-                }
-            }
+            let prefix = self.dedent_prefix(line_idx);
+            let spaces = prefix.len();
             
             if spaces > 0 {
                 let line_start = self.rope.line_to_char(line_idx);
@@ -1667,7 +5094,7 @@ impl Editor {
                     self.caret = line_byte;
                 }
                 
-                self.push_op(EditOp::Delete { pos: line_byte, text: " ".repeat(spaces) }, before, self.caret);
+                self.push_op(EditOp::Delete { pos: line_byte, text: prefix }, before, self.caret);
                 
                 self.invalidate_visual_lines();
                 
@@ -1677,18 +5104,187 @@ impl Editor {
         }
     }
 
-    fn move_lines_up(&mut self, viewport_width: usize) {
-        let (start_line, end_line) = if let Some((start, end)) = self.get_selection_range() {
+    /// Replaces `len` bytes at `pos` with `text`, carrying the caret and
+    /// selection anchor along with the text around them.
+    fn splice(&mut self, pos: usize, len: usize, text: &str) {
+        let before = self.caret;
+        let shift = |offset: usize| {
+            if offset >= pos + len {
+                offset - len + text.len()
+            } else {
+                offset.min(pos)
+            }
+        };
+        
+        let start_char = self.rope.byte_to_char(pos);
+        if len > 0 {
+            let removed = self.rope.byte_slice(pos..pos + len).to_string();
+            let end_char = self.rope.byte_to_char(pos + len);
+            self.rope.remove(start_char..end_char);
+            self.push_op(EditOp::Delete { pos, text: removed }, before, pos);
+        }
+        self.caret = shift(self.caret);
+        self.selection_anchor = self.selection_anchor.map(shift);
+        if !text.is_empty() {
+            self.rope.insert(start_char, text);
+            self.push_op(EditOp::Insert { pos, text: text.to_string() }, pos, self.caret);
+        }
+    }
+
+    /// Comments out the caret line or the selected lines with the file
+    /// type's line comment, or uncomments them if they all are. Comment
+    /// tokens line up at the smallest indentation; blank lines are skipped.
+    /// Languages without line comments get a block comment on each line.
+    fn toggle_line_comment(&mut self, viewport_width: usize) {
+        let Some(language) = language_for(&self.file_extension()) else {
+            return;
+        };
+        let (open, close) = match (language.line_comment, language.block_comment) {
+            (Some(token), _) => (token, ""),
+            (None, Some(tokens)) => tokens,
+            (None, None) => return,
+        };
+        self.enable_viewport_following();
+        
+        let (start_line, end_line) = self.selected_line_range();
+        let mut lines: Vec<(usize, String)> = (start_line..=end_line)
+            .map(|line_idx| self.line_text(line_idx))
+            .filter(|(_, text)| !text.trim().is_empty())
+            .collect();
+        if lines.is_empty() {
+            lines.push(self.line_text(start_line));
+        }
+        let indent_len = |text: &str| text.len() - text.trim_start().len();
+        // In columns, as tabs and spaces may be mixed
+        let min_indent = lines.iter().map(|(_, text)| indent_width(text, self.tab_width)).min().unwrap_or(0);
+        let commented = lines.iter().all(|(_, text)| {
+            let body = text.trim();
+            body.len() >= open.len() + close.len() && body.starts_with(open) && body.ends_with(close)
+        });
+        
+        for (line_start, text) in lines.iter().rev() {
+            let body_start = indent_len(text);
+            let body_end = text.trim_end().len();
+            if commented {
+                // Drop the tokens along with the space next to each
+                let mut close_start = body_end - close.len();
+                if !close.is_empty() && close_start > body_start + open.len() && text[..close_start].ends_with(' ') {
+                    close_start -= 1;
+                }
+                self.splice(line_start + close_start, body_end - close_start, "");
+                let space = body_start + open.len() < close_start && text[body_start + open.len()..].starts_with(' ');
+                self.splice(line_start + body_start, open.len() + usize::from(space), "");
+            } else {
+                if !close.is_empty() {
+                    self.splice(line_start + body_end, 0, &format!(" {}", close));
+                }
+                let pos = line_start + indent_byte_at_col(text, min_indent, self.tab_width);
+                // A selection that starts where the token goes takes it in
+                let anchor_at_pos = self.selection_anchor == Some(pos) && self.caret > pos;
+                let caret_at_pos = self.caret == pos && self.selection_anchor.is_some_and(|anchor| anchor > pos);
+                self.splice(pos, 0, &format!("{} ", open));
+                if anchor_at_pos {
+                    self.selection_anchor = Some(pos);
+                }
+                if caret_at_pos {
+                    self.caret = pos;
+                }
+            }
+        }
+        
+        self.invalidate_visual_lines();
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
+    }
+
+    /// Wraps the selection in the file type's block comment, or unwraps it
+    /// if it already is one. Without a selection, or when it spans several
+    /// lines, the whole lines are wrapped from their smallest indentation.
+    fn toggle_block_comment(&mut self, viewport_width: usize) {
+        let Some(language) = language_for(&self.file_extension()) else {
+            return;
+        };
+        let Some((open, close)) = language.block_comment else {
+            self.toggle_line_comment(viewport_width);
+            return;
+        };
+        self.enable_viewport_following();
+        
+        let (start_line, end_line) = self.selected_line_range();
+        let selection = self.get_selection_range().filter(|(start, end)| start < end);
+        let (start, end) = match selection.filter(|_| start_line == end_line) {
+            Some(range) => range,
+            None => {
+                let lines: Vec<(usize, String)> = (start_line..=end_line).map(|line_idx| self.line_text(line_idx)).collect();
+                let min_indent = lines.iter()
+                    .filter(|(_, text)| !text.trim().is_empty())
+                    .map(|(_, text)| indent_width(text, self.tab_width))
+                    .min()
+                    .unwrap_or(0);
+                let (first_start, first_text) = &lines[0];
+                let (last_start, last_text) = &lines[lines.len() - 1];
+                (first_start + indent_byte_at_col(first_text, min_indent, self.tab_width), last_start + last_text.trim_end().len())
+            }
+        };
+        
+        let text = self.rope.byte_slice(start..end).to_string();
+        let body = text.trim();
+        let body_start = start + (text.len() - text.trim_start().len());
+        if body.len() >= open.len() + close.len() && body.starts_with(open) && body.ends_with(close) {
+            // Drop the tokens along with the space next to each
+            let body_end = body_start + body.len();
+            let mut close_start = body_end - close.len();
+            if close_start > body_start + open.len() && body[..close_start - body_start].ends_with(' ') {
+                close_start -= 1;
+            }
+            self.splice(close_start, body_end - close_start, "");
+            let space = body_start + open.len() < close_start && body[open.len()..].starts_with(' ');
+            self.splice(body_start, open.len() + usize::from(space), "");
+        } else {
+            let closing = format!(" {}", close);
+            let opening = format!("{} ", open);
+            self.splice(end, 0, &closing);
+            self.splice(start, 0, &opening);
+            if let Some((sel_start, sel_end)) = selection {
+                // Keep the whole comment selected, facing the same way
+                let (anchor, caret) = (sel_start, sel_end + opening.len() + closing.len());
+                if self.caret < self.selection_anchor.unwrap_or(0) {
+                    self.selection_anchor = Some(caret);
+                    self.caret = anchor;
+                } else {
+                    self.selection_anchor = Some(anchor);
+                    self.caret = caret;
+                }
+            }
+        }
+        
+        self.invalidate_visual_lines();
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
+    }
+
+    /// First and last logical line touched by the selection, or the caret line.
+    fn selected_line_range(&self) -> (usize, usize) {
+        if let Some((start, end)) = self.get_selection_range() {
             let start_char = self.rope.byte_to_char(start);
             let end_char = self.rope.byte_to_char(end);
             let start_line = self.rope.char_to_line(start_char);
-            let end_line = self.rope.char_to_line(end_char);
+            let mut end_line = self.rope.char_to_line(end_char);
+            // A selection that stops at the start of a line does not take
+            // that line along
+            if end_line > start_line && end == self.rope.line_to_byte(end_line) {
+                end_line -= 1;
+            }
             (start_line, end_line)
         } else {
             let char_idx = self.rope.byte_to_char(self.caret);
             let line_idx = self.rope.char_to_line(char_idx);
             (line_idx, line_idx)
-        };
+        }
+    }
+
+    fn move_lines_up(&mut self, viewport_width: usize) {
+        let (start_line, end_line) = self.selected_line_range();
         
         if start_line == 0 {
             return;
@@ -1754,8 +5350,10 @@ impl Editor {
         }
         
         // Record the operation for undo
+        self.begin_undo_step(true);
         self.push_op(EditOp::Delete { pos: prev_line_start_byte, text: original_content }, before_caret, self.caret);
         self.push_op(EditOp::Insert { pos: prev_line_start_byte, text: new_content }, before_caret, self.caret);
+        self.end_undo_step();
         
         self.invalidate_visual_lines();
         let (_, col) = self.get_visual_position(self.caret, viewport_width);
@@ -1763,17 +5361,7 @@ impl Editor {
     }
     
     fn move_lines_down(&mut self, viewport_width: usize) {
-        let (start_line, end_line) = if let Some((start, end)) = self.get_selection_range() {
-            let start_char = self.rope.byte_to_char(start);
-            let end_char = self.rope.byte_to_char(end);
-            let start_line = self.rope.char_to_line(start_char);
-            let end_line = self.rope.char_to_line(end_char);
-            (start_line, end_line)
-        } else {
-            let char_idx = self.rope.byte_to_char(self.caret);
-            let line_idx = self.rope.char_to_line(char_idx);
-            (line_idx, line_idx)
-        };
+        let (start_line, end_line) = self.selected_line_range();
         
         let total_lines = self.rope.len_lines();
         
@@ -1835,65 +5423,520 @@ impl Editor {
             next_line_text.as_bytes().len()
         };
         
-        if self.caret >= block_start_byte && self.caret < block_end_byte {
+        if self.caret >= block_start_byte && self.caret <= block_end_byte {
             // The cursor was in the block that moved down
             let offset_in_block = self.caret - block_start_byte;
             self.caret = block_start_byte + next_line_actual_size + offset_in_block;
         }
         
         if let Some(anchor) = self.selection_anchor {
-            if anchor >= block_start_byte && anchor < block_end_byte {
+            if anchor >= block_start_byte && anchor <= block_end_byte {
                 let offset_in_block = anchor - block_start_byte;
                 self.selection_anchor = Some(block_start_byte + next_line_actual_size + offset_in_block);
             }
         }
         
         // Record the operation for undo
+        self.begin_undo_step(true);
         self.push_op(EditOp::Delete { pos: block_start_byte, text: original_content }, before_caret, self.caret);
         self.push_op(EditOp::Insert { pos: block_start_byte, text: new_content }, before_caret, self.caret);
+        self.end_undo_step();
+        
+        self.invalidate_visual_lines();
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
+    }
+
+    /// Line break used by the given line, for text inserted next to it.
+    fn line_break(&self, line_idx: usize) -> &'static str {
+        let line = self.rope.line(line_idx);
+        let len = line.len_chars();
+        if len >= 2 && line.char(len - 2) == '\r' && line.char(len - 1) == '\n' {
+            "\r\n"
+        } else {
+            "\n"
+        }
+    }
+
+    /// Replaces lines `start_line..=end_line` with what `transform` makes of
+    /// them, as one undo group. A selection is widened to the rewritten
+    /// lines; a lone caret keeps its offset into them.
+    fn rewrite_lines<F: FnOnce(Vec<String>) -> Vec<String>>(&mut self, start_line: usize, end_line: usize, viewport_width: usize, transform: F) {
+        let start = self.rope.line_to_byte(start_line);
+        let (last_start, last_text) = self.line_text(end_line);
+        let end = last_start + last_text.len();
+        let old = self.rope.byte_slice(start..end).to_string();
+        let lines = (start_line..=end_line).map(|line_idx| self.line_text(line_idx).1).collect();
+        let new = transform(lines).join(self.line_break(start_line));
+        if new == old {
+            return;
+        }
+        self.enable_viewport_following();
+        
+        let had_selection = self.get_selection_range().is_some_and(|(sel_start, sel_end)| sel_start < sel_end);
+        let backwards = self.selection_anchor.is_some_and(|anchor| self.caret < anchor);
+        let mut offset = self.caret.saturating_sub(start).min(new.len());
+        while !new.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        
+        self.begin_undo_step(true);
+        self.splice(start, end - start, &new);
+        self.end_undo_step();
+        
+        if !had_selection {
+            self.selection_anchor = None;
+            self.caret = start + offset;
+        } else if backwards {
+            self.selection_anchor = Some(start + new.len());
+            self.caret = start;
+        } else {
+            self.selection_anchor = Some(start);
+            self.caret = start + new.len();
+        }
+        
+        self.invalidate_visual_lines();
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
+    }
+
+    /// Duplicates the selection within a line, or else the caret line or
+    /// selected lines. The copy goes in front, leaving the caret and
+    /// selection on the original.
+    fn duplicate_lines(&mut self, viewport_width: usize) {
+        self.enable_viewport_following();
+        let (start_line, end_line) = self.selected_line_range();
+        self.begin_undo_step(true);
+        match self.get_selection_range().filter(|(start, end)| start < end && start_line == end_line) {
+            Some((start, end)) => {
+                let text = self.rope.byte_slice(start..end).to_string();
+                self.splice(start, 0, &text);
+            }
+            None => {
+                // The copy goes above, so that everything at or after the
+                // block start moves down with the original
+                let start = self.rope.line_to_byte(start_line);
+                let (last_start, last_text) = self.line_text(end_line);
+                let block = self.rope.byte_slice(start..last_start + last_text.len()).to_string();
+                let copy = format!("{}{}", block, self.line_break(start_line));
+                self.splice(start, 0, &copy);
+            }
+        }
+        self.end_undo_step();
+        
+        self.invalidate_visual_lines();
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
+    }
+
+    /// Deletes the caret line or the selected lines, keeping the caret's
+    /// column on the line that takes their place.
+    fn delete_lines(&mut self, viewport_width: usize) {
+        let (start_line, end_line) = self.selected_line_range();
+        let caret_line = self.rope.byte_to_line(self.caret);
+        let column = self.caret - self.rope.line_to_byte(caret_line);
+        
+        let mut start = self.rope.line_to_byte(start_line);
+        let end = if end_line + 1 < self.rope.len_lines() {
+            self.rope.line_to_byte(end_line + 1)
+        } else {
+            // On the last line, take the line break before it instead
+            if start_line > 0 {
+                let (prev_start, prev_text) = self.line_text(start_line - 1);
+                start = prev_start + prev_text.len();
+            }
+            self.rope.len_bytes()
+        };
+        if start == end {
+            return;
+        }
+        self.enable_viewport_following();
+        
+        self.selection_anchor = None;
+        self.begin_undo_step(true);
+        self.splice(start, end - start, "");
+        self.end_undo_step();
+        
+        let (line_start, text) = self.line_text(self.rope.byte_to_line(self.caret));
+        let mut offset = column.min(text.len());
+        while !text.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        self.caret = line_start + offset;
+        
+        self.invalidate_visual_lines();
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
+    }
+
+    /// Joins the selected lines, or the caret line and the next one.
+    fn join_lines(&mut self, viewport_width: usize) {
+        let (start_line, mut end_line) = self.selected_line_range();
+        if start_line == end_line {
+            end_line += 1;
+        }
+        if end_line >= self.rope.len_lines() {
+            return;
+        }
+        self.rewrite_lines(start_line, end_line, viewport_width, |lines| vec![join_line_texts(&lines)]);
+    }
+
+    /// First and last line of the paragraph around `line_idx`: the
+    /// neighbouring non-blank lines with the same prefix. A list item
+    /// starts a paragraph of its own, which takes in the lines under its
+    /// hanging indent. Lines marked in `fenced` count as blank.
+    fn paragraph_at(&self, line_idx: usize, prefix_markers: &[&str], fenced: &[bool]) -> Option<(usize, usize)> {
+        // Prefix markers, prefix width, hanging indent, list item, blank
+        let shape = |idx: usize| {
+            let text = self.line_text(idx).1;
+            let (prefix, body) = reflow_prefix(&text, prefix_markers);
+            let markers: String = prefix.chars().filter(|ch| !ch.is_whitespace()).collect();
+            let width = prefix.graphemes(true).fold(0, |col, g| col + grapheme_display_width(g, col, self.tab_width));
+            let hanging = width + Self::calculate_indent(body, self.tab_width);
+            let blank = body.trim().is_empty() || fenced.get(idx) == Some(&true);
+            (markers, width, hanging, list_marker(body).is_some(), blank)
+        };
+        
+        let (mut markers, mut width, _, mut list, blank) = shape(line_idx);
+        if blank {
+            return None;
+        }
+        let mut start = line_idx;
+        while start > 0 && !list {
+            let (prev_markers, prev_width, prev_hanging, prev_list, prev_blank) = shape(start - 1);
+            if prev_blank || prev_markers != markers || prev_hanging != width {
+                break;
+            }
+            start -= 1;
+            (markers, width, list) = (prev_markers, prev_width, prev_list);
+        }
+        
+        let (_, _, hanging, _, _) = shape(start);
+        let mut end = start;
+        while end + 1 < self.rope.len_lines() {
+            let (next_markers, next_width, _, next_list, next_blank) = shape(end + 1);
+            if next_blank || next_list || next_markers != markers || next_width != hanging {
+                break;
+            }
+            end += 1;
+        }
+        Some((start, end))
+    }
+
+    /// Rewraps the paragraph at the caret, or every paragraph the selection
+    /// touches, to `wrap_column` as one undo step. The caret stays by the
+    /// same character of text.
+    fn reflow(&mut self, viewport_width: usize) {
+        let extension = self.file_extension();
+        let prefix_markers = reflow_markers(&extension);
+        let fenced = if is_markdown(&extension) {
+            fenced_code_lines((0..self.rope.len_lines()).map(|idx| self.line_text(idx).1))
+        } else {
+            Vec::new()
+        };
+        let (first_line, last_line) = self.selected_line_range();
+        let mut paragraphs = Vec::new();
+        let mut line_idx = first_line;
+        while line_idx <= last_line {
+            match self.paragraph_at(line_idx, &prefix_markers, &fenced) {
+                Some((start, end)) => {
+                    paragraphs.push((start, end));
+                    line_idx = end + 1;
+                }
+                None => line_idx += 1,
+            }
+        }
+        let (Some(&(start_line, _)), Some(&(_, end_line))) = (paragraphs.first(), paragraphs.last()) else {
+            return;
+        };
+        
+        let had_selection = self.get_selection_range().is_some_and(|(start, end)| start < end);
+        let backwards = self.selection_anchor.is_some_and(|anchor| self.caret < anchor);
+        // Count the text characters before the caret, prefixes aside
+        let mut chars_before = 0;
+        for idx in start_line..=end_line {
+            let (line_start, text) = self.line_text(idx);
+            let (prefix, body) = reflow_prefix(&text, &prefix_markers);
+            let body_start = line_start + prefix.len();
+            if self.caret > body_start {
+                let len = (self.caret - body_start).min(body.len());
+                chars_before += body[..len].chars().filter(|ch| !ch.is_whitespace()).count();
+            }
+        }
+        
+        let lines_before = self.rope.len_lines();
+        let (column, tab_width) = (self.wrap_column, self.tab_width);
+        self.begin_undo_step(true);
+        for &(start, end) in paragraphs.iter().rev() {
+            self.rewrite_lines(start, end, viewport_width, |lines| reflow_lines(&lines, column, tab_width, &prefix_markers));
+        }
+        self.end_undo_step();
+        let end_line = end_line + self.rope.len_lines() - lines_before;
+        
+        let start = self.rope.line_to_byte(start_line);
+        let (last_start, last_text) = self.line_text(end_line);
+        let end = last_start + last_text.len();
+        if had_selection {
+            if backwards {
+                self.selection_anchor = Some(end);
+                self.caret = start;
+            } else {
+                self.selection_anchor = Some(start);
+                self.caret = end;
+            }
+        } else {
+            self.selection_anchor = None;
+            self.caret = end;
+            'lines: for idx in start_line..=end_line {
+                let (line_start, text) = self.line_text(idx);
+                let (prefix, body) = reflow_prefix(&text, &prefix_markers);
+                for (offset, ch) in body.char_indices().filter(|(_, ch)| !ch.is_whitespace()) {
+                    if chars_before == 0 {
+                        self.caret = line_start + prefix.len() + offset;
+                        break 'lines;
+                    }
+                    chars_before -= 1;
+                    // Stay right after the last character rather than
+                    // jumping to the next word
+                    let rest = &body[offset + ch.len_utf8()..];
+                    if chars_before == 0 && (rest.is_empty() || rest.starts_with(char::is_whitespace)) {
+                        self.caret = line_start + prefix.len() + offset + ch.len_utf8();
+                        break 'lines;
+                    }
+                }
+            }
+        }
+        
+        self.invalidate_visual_lines();
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
+    }
+
+    /// Removes whitespace at the ends of the selected lines, or of every
+    /// line without a selection, as one undo step.
+    fn strip_trailing_whitespace(&mut self, viewport_width: usize) {
+        let (start_line, end_line) = if self.get_selection_range().is_some_and(|(start, end)| start < end) {
+            self.selected_line_range()
+        } else {
+            (0, self.rope.len_lines() - 1)
+        };
+        self.begin_undo_step(true);
+        for line_idx in (start_line..=end_line).rev() {
+            let (line_start, text) = self.line_text(line_idx);
+            let content_len = text.trim_end().len();
+            if content_len < text.len() {
+                self.splice(line_start + content_len, text.len() - content_len, "");
+            }
+        }
+        self.end_undo_step();
         
         self.invalidate_visual_lines();
         let (_, col) = self.get_visual_position(self.caret, viewport_width);
         self.preferred_col = col;
     }
 
+    fn run_line_command(&mut self, command: LineCommand, viewport_width: usize) {
+        match command {
+            LineCommand::Duplicate => self.duplicate_lines(viewport_width),
+            LineCommand::Delete => self.delete_lines(viewport_width),
+            LineCommand::Join => self.join_lines(viewport_width),
+            LineCommand::StripTrailingWhitespace => self.strip_trailing_whitespace(viewport_width),
+            LineCommand::Reorder(order) => {
+                let (start_line, end_line) = self.selected_line_range();
+                if start_line < end_line {
+                    self.rewrite_lines(start_line, end_line, viewport_width, |mut lines| {
+                        reorder_lines(&mut lines, order);
+                        lines
+                    });
+                }
+            }
+        }
+    }
+
     fn select_all(&mut self) {
+        self.clear_extra_cursors();
         self.selection_anchor = Some(0);
         self.caret = self.rope.len_bytes();
     }
 
     fn copy(&mut self) -> bool {
+        self.last_block_copy = None;
+        if !self.extra_cursors.is_empty() {
+            // One line per cursor, in document order
+            let text = self.all_cursors()
+                .into_iter()
+                .map(|c| self.rope.byte_slice(c.start()..c.end()).to_string())
+                .collect::<Vec<_>>()
+                .join("\n");
+            if text.trim_matches('\n').is_empty() {
+                return false;
+            }
+            if self.block_anchor.is_some() {
+                self.last_block_copy = Some(text.clone());
+            }
+            // The history still works when the system clipboard does not
+            if let Some(clipboard) = &mut self.clipboard {
+                let _ = clipboard.set_text(text.clone());
+            }
+            self.remember_clipboard(text);
+            return true;
+        }
         if let Some((start, end)) = self.get_selection_range() {
             if start < end {
                 let text = self.rope.byte_slice(start..end).to_string();
-                if let Err(_) = self.clipboard.set_text(text) {
-                    return false;
+                if let Some(clipboard) = &mut self.clipboard {
+                    let _ = clipboard.set_text(text.clone());
                 }
+                self.remember_clipboard(text);
                 return true;
             }
         }
-        false
+        false
+    }
+
+    /// Puts copied or cut text at the front of the clipboard history,
+    /// dropping an older entry with the same text.
+    fn remember_clipboard(&mut self, text: String) {
+        self.clipboard_history.retain(|entry| *entry != text);
+        self.clipboard_history.insert(0, text);
+        self.clipboard_history.truncate(CLIPBOARD_HISTORY_MAX);
+        if self.persist_clipboard_history {
+            let _ = save_clipboard_history(&self.clipboard_history);
+        }
+    }
+
+    /// Switches keeping the clipboard history across sessions on or off.
+    fn toggle_clipboard_persistence(&mut self) {
+        self.persist_clipboard_history = !self.persist_clipboard_history;
+        if self.persist_clipboard_history {
+            let _ = save_clipboard_history(&self.clipboard_history);
+        } else if let Some(path) = clipboard_history_path() {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    fn cut(&mut self) -> bool {
+        if self.copy() {
+            self.begin_undo_step(true);
+            self.for_each_cursor(|ed| {
+                ed.delete_selection();
+            });
+            self.end_undo_step();
+            return true;
+        }
+        false
+    }
+
+    fn paste(&mut self, viewport_width: usize) {
+        // Text copied in other programs joins the history when pasted
+        if let Some(Ok(text)) = self.clipboard.as_mut().map(Clipboard::get_text) {
+            if !text.is_empty() && self.clipboard_history.first() != Some(&text) {
+                self.remember_clipboard(text);
+            }
+        }
+        self.paste_history_entry(0, viewport_width);
+    }
+
+    /// Pastes a clipboard history entry as one undo step, remembering it so
+    /// the paste can be cycled through older entries.
+    fn paste_history_entry(&mut self, index: usize, viewport_width: usize) {
+        let Some(text) = self.clipboard_history.get(index).cloned() else {
+            return;
+        };
+        let state_before = self.undo_tree.current;
+        self.begin_undo_step(true);
+        self.paste_text(&text, viewport_width);
+        self.end_undo_step();
+        self.last_paste = (self.undo_tree.current != state_before).then_some((text, self.undo_tree.current));
+    }
+
+    /// Swaps the text just pasted for the next older history entry. Does
+    /// nothing once anything else has been edited since the paste.
+    fn cycle_paste(&mut self, viewport_width: usize) {
+        let Some((text, state)) = &self.last_paste else {
+            return;
+        };
+        if *state != self.undo_tree.current || self.current_group.is_some() || self.clipboard_history.len() < 2 {
+            return;
+        }
+        // Looked up by text, as copies since the paste reorder the history
+        let next = self.clipboard_history.iter().position(|entry| entry == text).map_or(0, |index| index + 1);
+        self.undo();
+        self.paste_history_entry(next % self.clipboard_history.len(), viewport_width);
     }
 
-    fn cut(&mut self) -> bool {
-        if self.copy() {
-            self.delete_selection();
-            return true;
+    fn paste_text(&mut self, text: &str, viewport_width: usize) {
+        let cursor_count = self.extra_cursors.len() + 1;
+        let lines: Vec<&str> = text.lines().collect();
+        let is_block = self.block_anchor.is_some() || self.last_block_copy.as_deref() == Some(text);
+        if is_block && lines.len() > 1 && lines.len() != cursor_count {
+            self.paste_block(&lines, viewport_width);
+        } else if cursor_count > 1 && lines.len() == cursor_count {
+            // Distribute one line to each cursor; cursors are visited last to first
+            let mut pieces = lines.into_iter().rev();
+            self.for_each_cursor(|ed| ed.insert_text(pieces.next().unwrap_or(""), viewport_width));
+        } else {
+            self.for_each_cursor(|ed| ed.insert_text(text, viewport_width));
         }
-        false
     }
 
-    fn paste(&mut self, viewport_width: usize) {
-        if let Ok(text) = self.clipboard.get_text() {
-            self.delete_selection();
+    /// Inserts each line at the same column on consecutive lines, starting at
+    /// the topmost cursor. Short lines are padded and missing lines appended.
+    fn paste_block(&mut self, lines: &[&str], viewport_width: usize) {
+        self.enable_viewport_following();
+        self.for_each_cursor(|ed| {
+            ed.delete_selection();
+        });
+        let top = self.all_cursors().first().map_or(self.caret, |c| c.start());
+        self.clear_extra_cursors();
+        
+        let start_line = self.rope.byte_to_line(top);
+        let (line_start, text) = self.line_text(start_line);
+        let column = self.display_width_from(&text[..top - line_start], 0);
+        let before = self.caret;
+        
+        for (i, piece) in lines.iter().enumerate() {
+            let line_idx = start_line + i;
+            if line_idx >= self.rope.len_lines() {
+                let end = self.rope.len_bytes();
+                self.rope.insert(self.rope.len_chars(), "\n");
+                self.push_op(EditOp::Insert { pos: end, text: "\n".to_string() }, before, self.caret);
+            }
+            
+            let (line_start, text) = self.line_text(line_idx);
+            let mut width = 0;
+            let mut offset = 0;
+            for g in text.graphemes(true) {
+                if width >= column {
+                    break;
+                }
+                width += grapheme_display_width(g, width, self.tab_width);
+                offset += g.len();
+            }
             
+            let insert = format!("{}{}", " ".repeat(column.saturating_sub(width)), piece);
+            let pos = line_start + offset;
+            self.rope.insert(self.rope.byte_to_char(pos), &insert);
+            self.caret = pos + insert.len();
+            self.push_op(EditOp::Insert { pos, text: insert }, before, self.caret);
+        }
+        
+        self.invalidate_visual_lines();
+        let (_, col) = self.get_visual_position(self.caret, viewport_width);
+        self.preferred_col = col;
+    }
+
+    fn insert_text(&mut self, text: &str, viewport_width: usize) {
+        self.enable_viewport_following();
+        self.delete_selection();
+        if !text.is_empty() {
             let before = self.caret;
             let char_pos = self.rope.byte_to_char(self.caret);
             let bytes_inserted = text.len();
-            self.rope.insert(char_pos, &text);
+            self.rope.insert(char_pos, text);
             self.caret += bytes_inserted;
             
-            self.push_op(EditOp::Insert { pos: before, text: text.clone() }, before, self.caret);
+            self.push_op(EditOp::Insert { pos: before, text: text.to_string() }, before, self.caret);
             
             self.invalidate_visual_lines();
             
@@ -1951,22 +5994,37 @@ impl Editor {
     }
     
     fn update_viewport(&mut self, height: usize, width: usize) {
+        // Every move and edit ends here, so this is where folds a caret
+        // was taken into are opened
+        self.open_folds_at_carets();
         self.ensure_visual_lines(width);
         
-        if self.viewport_follows_caret {
+        if self.viewport_follows_caret && height > 0 && width > 0 {
             let (row, col) = self.get_visual_position(self.caret, width);
             
-            if row < self.viewport_offset.0 + self.scrolloff {
-                self.viewport_offset.0 = row.saturating_sub(self.scrolloff);
-            } else if row >= self.viewport_offset.0 + height - self.scrolloff {
-                self.viewport_offset.0 = row + self.scrolloff + 1 - height;
+            // Small panes cannot keep the full margin around the caret
+            let scrolloff = self.scrolloff.min((height - 1) / 2);
+            if row < self.viewport_offset.0 + scrolloff {
+                self.viewport_offset.0 = row.saturating_sub(scrolloff);
+            } else if row >= self.viewport_offset.0 + height - scrolloff {
+                self.viewport_offset.0 = row + scrolloff + 1 - height;
             }
             
             if !self.word_wrap {
-                if col < self.viewport_offset.1 + self.scrolloff {
-                    self.viewport_offset.1 = col.saturating_sub(self.scrolloff);
-                } else if col >= self.viewport_offset.1 + width - self.scrolloff {
-                    self.viewport_offset.1 = col + self.scrolloff + 1 - width;
+                // The whole character under the caret has to fit, which
+                // takes more than one column for tabs and wide characters
+                let next = self.next_cluster_pos(self.caret);
+                let caret_width = if next > self.caret && !matches!(self.rope.byte(self.caret), b'\n' | b'\r') {
+                    let g = self.rope.byte_slice(self.caret..next).to_string();
+                    grapheme_display_width(&g, col, self.tab_width).max(1)
+                } else {
+                    1
+                };
+                let scrolloff = self.scrolloff.min((width - 1) / 2);
+                if col < self.viewport_offset.1 + scrolloff {
+                    self.viewport_offset.1 = col.saturating_sub(scrolloff);
+                } else if col + caret_width > self.viewport_offset.1 + width - scrolloff {
+                    self.viewport_offset.1 = (col + caret_width + scrolloff).saturating_sub(width);
                 }
             } else {
                 self.viewport_offset.1 = 0;
@@ -1974,8 +6032,60 @@ impl Editor {
         }
     }
 
+    /// Scrolls for a mouse wheel turn: three rows up or down, or sideways
+    /// for a horizontal wheel. Shift turns the vertical wheel sideways,
+    /// unless lines wrap and there is nothing to the side.
+    fn scroll_wheel(&mut self, kind: MouseEventKind, shift: bool, width: usize, height: usize) {
+        let down = matches!(kind, MouseEventKind::ScrollDown);
+        let sideways = match kind {
+            MouseEventKind::ScrollLeft => Some(false),
+            MouseEventKind::ScrollRight => Some(true),
+            _ if shift && !self.word_wrap => Some(down),
+            _ => None,
+        };
+        if let Some(right) = sideways {
+            self.scroll_horizontally(right, width, height);
+            return;
+        }
+        if down {
+            self.ensure_visual_lines(width);
+            let max = self.visual_lines.len().saturating_sub(height);
+            self.viewport_offset.0 = (self.viewport_offset.0 + 3).min(max);
+        } else {
+            self.viewport_offset.0 = self.viewport_offset.0.saturating_sub(3);
+        }
+        self.viewport_follows_caret = false;
+    }
+
+    /// Scrolls a no-wrap view sideways, no further than the widest line
+    /// in view needs.
+    fn scroll_horizontally(&mut self, right: bool, width: usize, height: usize) {
+        if self.word_wrap {
+            return;
+        }
+        self.ensure_visual_lines(width);
+        if right {
+            let end = (self.viewport_offset.0 + height).min(self.visual_lines.len());
+            let widest = self.visual_lines[self.viewport_offset.0.min(end)..end]
+                .iter()
+                .flatten()
+                .map(|vline| {
+                    let text = self.rope.byte_slice(vline.start_byte..vline.end_byte).to_string();
+                    self.display_width_from(&text, 0)
+                })
+                .max()
+                .unwrap_or(0);
+            let max = widest.saturating_sub(width.saturating_sub(1));
+            self.viewport_offset.1 = (self.viewport_offset.1 + HORIZONTAL_SCROLL_STEP).min(max.max(self.viewport_offset.1));
+        } else {
+            self.viewport_offset.1 = self.viewport_offset.1.saturating_sub(HORIZONTAL_SCROLL_STEP);
+        }
+        self.viewport_follows_caret = false;
+    }
+
     fn handle_click(&mut self, col: u16, row: u16, area: Rect, viewport_width: usize, shift_held: bool) {
         self.enable_viewport_following();
+        self.clear_extra_cursors();
         self.ensure_visual_lines(viewport_width);
         let click_row = self.viewport_offset.0 + row.saturating_sub(area.y) as usize;
         let click_col = self.viewport_offset.1 + col.saturating_sub(area.x) as usize;
@@ -2005,6 +6115,392 @@ impl Editor {
         }
     }
 
+    fn primary_cursor(&self) -> Cursor {
+        Cursor {
+            caret: self.caret,
+            anchor: self.selection_anchor,
+            preferred_col: self.preferred_col,
+        }
+    }
+
+    fn set_primary_cursor(&mut self, cursor: Cursor) {
+        self.caret = cursor.caret;
+        self.selection_anchor = cursor.anchor;
+        self.preferred_col = cursor.preferred_col;
+    }
+
+    /// Every cursor, primary included, in document order.
+    fn all_cursors(&self) -> Vec<Cursor> {
+        let mut cursors = self.extra_cursors.clone();
+        cursors.push(self.primary_cursor());
+        cursors.sort_by_key(|c| (c.start(), c.end()));
+        cursors
+    }
+
+    /// Non-empty selections of every cursor, in document order.
+    fn all_selection_ranges(&self) -> Vec<(usize, usize)> {
+        self.all_cursors()
+            .into_iter()
+            .map(|c| (c.start(), c.end()))
+            .filter(|(start, end)| start < end)
+            .collect()
+    }
+
+    /// Runs a single-cursor operation at every cursor. Cursors are visited from
+    /// the end of the document backwards so that each edit leaves the positions
+    /// of the cursors still to be visited untouched; cursors already visited are
+    /// shifted by however much the edit grew or shrank the text.
+    fn for_each_cursor<F: FnMut(&mut Editor)>(&mut self, mut op: F) {
+        // Once edited or moved, a column selection is just a set of cursors
+        self.block_anchor = None;
+        self.begin_undo_step(false);
+        if self.extra_cursors.is_empty() {
+            op(self);
+            self.end_undo_step();
+            return;
+        }
+        
+        let mut cursors = std::mem::take(&mut self.extra_cursors);
+        cursors.push(self.primary_cursor());
+        let primary = cursors.len() - 1;
+        
+        let mut order: Vec<usize> = (0..cursors.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(cursors[i].start()));
+        
+        let mut visited: Vec<usize> = Vec::new();
+        for i in order {
+            self.set_primary_cursor(cursors[i]);
+            let len_before = self.rope.len_bytes();
+            op(self);
+            let len_after = self.rope.len_bytes();
+            cursors[i] = self.primary_cursor();
+            
+            let shift = |pos: usize| (pos + len_after).saturating_sub(len_before);
+            for &j in &visited {
+                cursors[j].caret = shift(cursors[j].caret);
+                cursors[j].anchor = cursors[j].anchor.map(shift);
+            }
+            visited.push(i);
+        }
+        
+        let primary_cursor = cursors.remove(primary);
+        self.set_primary_cursor(primary_cursor);
+        self.extra_cursors = cursors;
+        self.merge_cursors();
+        self.end_undo_step();
+    }
+
+    /// Collapses cursors that share a position or whose selections overlap.
+    fn merge_cursors(&mut self) {
+        if self.extra_cursors.is_empty() {
+            return;
+        }
+        
+        let primary = self.primary_cursor();
+        let mut cursors = std::mem::take(&mut self.extra_cursors);
+        cursors.push(primary);
+        cursors.sort_by_key(|c| (c.start(), c.end()));
+        
+        let mut merged: Vec<(Cursor, bool)> = Vec::new();
+        for cursor in cursors {
+            let is_primary = cursor == primary;
+            if let Some((last, last_is_primary)) = merged.last_mut() {
+                let same_spot = cursor.start() == last.start() && cursor.end() == last.end();
+                if same_spot || cursor.start() < last.end() {
+                    let start = last.start();
+                    let end = last.end().max(cursor.end());
+                    *last = Cursor {
+                        caret: end,
+                        anchor: if start < end { Some(start) } else { None },
+                        preferred_col: cursor.preferred_col,
+                    };
+                    *last_is_primary |= is_primary;
+                    continue;
+                }
+            }
+            merged.push((cursor, is_primary));
+        }
+        
+        let primary_idx = merged.iter().position(|(_, is_primary)| *is_primary).unwrap_or(merged.len() - 1);
+        let (primary_cursor, _) = merged.remove(primary_idx);
+        self.set_primary_cursor(primary_cursor);
+        self.extra_cursors = merged.into_iter().map(|(cursor, _)| cursor).collect();
+    }
+
+    fn clear_extra_cursors(&mut self) {
+        self.extra_cursors.clear();
+        self.block_anchor = None;
+    }
+
+    /// Makes `cursor` the primary cursor, keeping the current one as a secondary.
+    fn push_cursor(&mut self, cursor: Cursor) {
+        let previous = self.primary_cursor();
+        self.extra_cursors.push(previous);
+        self.set_primary_cursor(cursor);
+        self.merge_cursors();
+    }
+
+    fn add_cursor_vertical(&mut self, viewport_width: usize, below: bool) {
+        self.enable_viewport_following();
+        let (row, _) = self.get_visual_position(self.caret, viewport_width);
+        let first_row = self.virtual_lines;
+        let last_row = self.visual_lines.len().saturating_sub(self.virtual_lines + 1);
+        
+        let target_row = if below {
+            if row >= last_row {
+                return;
+            }
+            row + 1
+        } else {
+            if row <= first_row {
+                return;
+            }
+            row - 1
+        };
+        
+        let caret = self.visual_to_byte(target_row, self.preferred_col, viewport_width);
+        self.push_cursor(Cursor { caret, anchor: None, preferred_col: self.preferred_col });
+    }
+
+    /// Text of the primary selection, selecting the word at the caret if there is none.
+    fn selection_query(&mut self, viewport_width: usize) -> Option<String> {
+        if !matches!(self.get_selection_range(), Some((start, end)) if start < end) {
+            self.select_word_at_caret(viewport_width);
+        }
+        match self.get_selection_range() {
+            Some((start, end)) if start < end => Some(self.rope.byte_slice(start..end).to_string()),
+            _ => None,
+        }
+    }
+
+    fn add_next_occurrence(&mut self, viewport_width: usize) {
+        self.enable_viewport_following();
+        let (start, end) = match self.get_selection_range() {
+            Some((start, end)) if start < end => (start, end),
+            _ => {
+                // First press only selects the word under the caret
+                self.select_word_at_caret(viewport_width);
+                return;
+            }
+        };
+        
+        let query = self.rope.byte_slice(start..end).to_string();
+        let taken = self.all_selection_ranges();
+        let is_free = |m: &&(usize, usize)| !taken.iter().any(|&(start, end)| m.0 < end && start < m.1);
+        let matches = self.find_occurrences(&query);
+        
+        let next = matches.iter()
+            .filter(is_free)
+            .find(|m| m.0 >= end)
+            .or_else(|| matches.iter().find(is_free))
+            .copied();
+        
+        if let Some((start, end)) = next {
+            let (_, col) = self.get_visual_position(end, viewport_width);
+            self.push_cursor(Cursor { caret: end, anchor: Some(start), preferred_col: col });
+        }
+    }
+
+    fn select_all_occurrences(&mut self, viewport_width: usize) {
+        self.enable_viewport_following();
+        let query = match self.selection_query(viewport_width) {
+            Some(query) => query,
+            None => return,
+        };
+        let current_start = self.get_selection_range().map_or(self.caret, |(start, _)| start);
+        let matches = self.find_occurrences(&query);
+        if matches.is_empty() {
+            return;
+        }
+        
+        let mut cursors = Vec::new();
+        for &(start, end) in &matches {
+            let (_, col) = self.get_visual_position(end, viewport_width);
+            cursors.push(Cursor { caret: end, anchor: Some(start), preferred_col: col });
+        }
+        let primary_idx = matches.iter().position(|&(start, _)| start == current_start).unwrap_or(0);
+        let primary = cursors.remove(primary_idx);
+        self.set_primary_cursor(primary);
+        self.extra_cursors = cursors;
+    }
+
+    /// Replaces every multi-line selection with one cursor per line it covers.
+    fn split_selection_into_lines(&mut self, viewport_width: usize) {
+        let mut cursors = std::mem::take(&mut self.extra_cursors);
+        cursors.push(self.primary_cursor());
+        cursors.sort_by_key(|c| c.start());
+        
+        let mut result = Vec::new();
+        for cursor in cursors {
+            if cursor.anchor.is_none() {
+                result.push(cursor);
+                continue;
+            }
+            let (start, end) = (cursor.start(), cursor.end());
+            let first_line = self.rope.byte_to_line(start);
+            let last_line = self.rope.byte_to_line(end);
+            for line_idx in first_line..=last_line {
+                let (line_start, text) = self.line_text(line_idx);
+                if line_idx > first_line && end == line_start {
+                    // Selection stops at the very start of this line
+                    break;
+                }
+                let line_end = (line_start + text.len()).min(end);
+                let line_sel_start = start.max(line_start).min(line_end);
+                let (_, col) = self.get_visual_position(line_end, viewport_width);
+                result.push(Cursor {
+                    caret: line_end,
+                    anchor: if line_sel_start < line_end { Some(line_sel_start) } else { None },
+                    preferred_col: col,
+                });
+            }
+        }
+        
+        if let Some(primary) = result.pop() {
+            self.set_primary_cursor(primary);
+        }
+        self.extra_cursors = result;
+        self.merge_cursors();
+    }
+
+    /// Visual (row, column) under a screen cell, clamped to the text rows.
+    fn screen_to_visual(&self, col: u16, row: u16, area: Rect) -> (usize, usize) {
+        let first_row = self.virtual_lines;
+        let last_row = self.visual_lines.len().saturating_sub(self.virtual_lines + 1).max(first_row);
+        let visual_row = (self.viewport_offset.0 + row.saturating_sub(area.y) as usize).clamp(first_row, last_row);
+        let visual_col = self.viewport_offset.1 + col.saturating_sub(area.x) as usize;
+        (visual_row, visual_col)
+    }
+
+    fn start_block_selection(&mut self, row: usize, col: usize, viewport_width: usize) {
+        self.enable_viewport_following();
+        self.block_anchor = Some((row, col));
+        self.block_select_to(row, col, viewport_width);
+    }
+
+    /// Moves the active corner of the column selection and rebuilds one
+    /// cursor per visual row between the anchor and that corner.
+    fn block_select_to(&mut self, row: usize, col: usize, viewport_width: usize) {
+        self.ensure_visual_lines(viewport_width);
+        let (anchor_row, anchor_col) = match self.block_anchor {
+            Some(anchor) => anchor,
+            None => return,
+        };
+        self.block_cursor = (row, col);
+        
+        let mut cursors = Vec::new();
+        let mut primary = None;
+        for visual_row in anchor_row.min(row)..=anchor_row.max(row) {
+            if !matches!(self.visual_lines.get(visual_row), Some(Some(_))) {
+                continue;
+            }
+            let anchor = self.visual_to_byte(visual_row, anchor_col, viewport_width);
+            let caret = self.visual_to_byte(visual_row, col, viewport_width);
+            let cursor = Cursor {
+                caret,
+                anchor: if anchor != caret { Some(anchor) } else { None },
+                preferred_col: col,
+            };
+            if visual_row == row {
+                primary = Some(cursor);
+            } else {
+                cursors.push(cursor);
+            }
+        }
+        
+        if let Some(primary) = primary {
+            self.set_primary_cursor(primary);
+            self.extra_cursors = cursors;
+            self.merge_cursors();
+        }
+    }
+
+    /// Grows or shrinks the column selection by whole rows and columns,
+    /// starting one at the caret if none is active.
+    fn extend_block_selection(&mut self, viewport_width: usize, row_delta: isize, col_delta: isize) {
+        if self.block_anchor.is_none() {
+            let (row, col) = self.get_visual_position(self.caret, viewport_width);
+            self.clear_extra_cursors();
+            self.start_block_selection(row, col, viewport_width);
+        }
+        self.enable_viewport_following();
+        
+        let first_row = self.virtual_lines;
+        let last_row = self.visual_lines.len().saturating_sub(self.virtual_lines + 1).max(first_row);
+        let (row, col) = self.block_cursor;
+        let row = row.saturating_add_signed(row_delta).clamp(first_row, last_row);
+        let col = col.saturating_add_signed(col_delta);
+        self.block_select_to(row, col, viewport_width);
+    }
+
+    /// Runs a line-based operation under every cursor as one undo step,
+    /// skipping cursors whose lines were already handled.
+    fn for_each_cursor_lines<F: FnMut(&mut Editor)>(&mut self, mut op: F) {
+        let mut touched_lines: Vec<usize> = Vec::new();
+        self.begin_undo_step(true);
+        self.for_each_cursor(|ed| {
+            let (start_line, end_line) = ed.selected_line_range();
+            if (start_line..=end_line).any(|line| touched_lines.contains(&line)) {
+                return;
+            }
+            touched_lines.extend(start_line..=end_line);
+            op(ed);
+        });
+        self.end_undo_step();
+    }
+
+    /// Indents or dedents under every cursor, touching each line only once.
+    fn indent_at_cursors(&mut self, viewport_width: usize, dedent: bool) {
+        self.for_each_cursor_lines(|ed| {
+            if dedent {
+                ed.dedent(viewport_width);
+            } else {
+                ed.indent(viewport_width);
+            }
+        });
+    }
+
+    /// Cycles task checkboxes under every cursor, touching each line once.
+    fn cycle_task_checkboxes_at_cursors(&mut self, viewport_width: usize) {
+        self.for_each_cursor_lines(|ed| ed.cycle_task_checkboxes(viewport_width));
+    }
+
+    fn line_command_at_cursors(&mut self, command: LineCommand, viewport_width: usize) {
+        self.for_each_cursor_lines(|ed| ed.run_line_command(command, viewport_width));
+    }
+
+    /// Transforms the text under every cursor as one undo step. The last
+    /// failure, if any, is returned; the other cursors are still applied.
+    fn transform_at_cursors(&mut self, transform: TextTransform, viewport_width: usize) -> Result<(), String> {
+        let mut outcome = Ok(());
+        self.begin_undo_step(true);
+        self.for_each_cursor(|ed| {
+            if let Err(message) = ed.transform_selection(transform, viewport_width) {
+                outcome = Err(message);
+            }
+        });
+        self.end_undo_step();
+        outcome
+    }
+
+    fn toggle_comments_at_cursors(&mut self, viewport_width: usize, block: bool) {
+        self.for_each_cursor_lines(|ed| {
+            if block {
+                ed.toggle_block_comment(viewport_width);
+            } else {
+                ed.toggle_line_comment(viewport_width);
+            }
+        });
+    }
+
+    fn get_cursor_info(&self) -> String {
+        if self.extra_cursors.is_empty() {
+            String::new()
+        } else {
+            format!(" | {} cursors", self.extra_cursors.len() + 1)
+        }
+    }
+
     fn get_display_name(&self) -> String {
         let name = self.filename.as_ref()
             .and_then(|p| p.file_name())
@@ -2018,6 +6514,14 @@ impl Editor {
         }
     }
 
+    fn get_tab_display(&self) -> String {
+        if self.use_hard_tabs {
+            format!("Tabs:{}", self.tab_width)
+        } else {
+            format!("Spaces:{}", self.tab_width)
+        }
+    }
+
     fn get_position(&self) -> (usize, usize) {
         let char_idx = self.rope.byte_to_char(self.caret);
         let line = self.rope.char_to_line(char_idx);
@@ -2034,13 +6538,8 @@ impl Editor {
             return;
         }
 
-        let text = self.rope.to_string();
-        let query_lower = query.to_lowercase();
-        let text_lower = text.to_lowercase();
-        
-        for (idx, _) in text_lower.match_indices(&query_lower) {
-            self.find_matches.push((idx, idx + query.len()));
-        }
+        self.find_matches = self.find_all(query);
+        self.find_failed = self.find_matches.is_empty();
 
         if !self.find_matches.is_empty() {
             // Find the first match at or after the current caret position
@@ -2054,7 +6553,9 @@ impl Editor {
                 }
             }
             
-            // If no match after current position, wrap to the first match
+            // If no match after current position, wrap to the first match.
+            // Wrapping counts as a failed find, which ends macro replay.
+            self.find_failed = found_index.is_none();
             self.current_match_index = found_index.or(Some(0));
             
             // Only jump to match if buffer is not focused
@@ -2066,9 +6567,44 @@ impl Editor {
         }
     }
 
+    /// Byte ranges of every case-insensitive occurrence of `query`.
+    fn find_all(&self, query: &str) -> Vec<(usize, usize)> {
+        let text = self.rope.to_string();
+        let query_lower: String = query.chars().flat_map(char::to_lowercase).collect();
+        
+        // Lowercasing can change a character's length, so each lowercased
+        // character remembers where it came from. Matches that start or
+        // end inside one are dropped.
+        let mut text_lower = String::with_capacity(text.len());
+        let mut origins = Vec::with_capacity(text.len() + 1);
+        for (idx, ch) in text.char_indices() {
+            let start = text_lower.len();
+            text_lower.extend(ch.to_lowercase());
+            origins.resize(text_lower.len(), None);
+            origins[start] = Some(idx);
+        }
+        origins.push(Some(text.len()));
+        
+        text_lower
+            .match_indices(&query_lower)
+            .filter_map(|(idx, m)| Some((origins[idx]?, origins[idx + m.len()]?)))
+            .collect()
+    }
+
+    /// Every exact match of `query`, for selecting occurrences.
+    fn find_occurrences(&self, query: &str) -> Vec<(usize, usize)> {
+        self.rope
+            .to_string()
+            .match_indices(query)
+            .map(|(idx, m)| (idx, idx + m.len()))
+            .collect()
+    }
+
     fn find_next(&mut self) {
+        self.find_failed = true;
         if let Some(idx) = self.current_match_index {
             if !self.find_matches.is_empty() {
+                self.find_failed = idx + 1 == self.find_matches.len();
                 self.current_match_index = Some((idx + 1) % self.find_matches.len());
                 self.jump_to_current_match();
             }
@@ -2076,8 +6612,10 @@ impl Editor {
     }
 
     fn find_previous(&mut self) {
+        self.find_failed = true;
         if let Some(idx) = self.current_match_index {
             if !self.find_matches.is_empty() {
+                self.find_failed = idx == 0;
                 self.current_match_index = Some(if idx == 0 {
                     self.find_matches.len() - 1
                 } else {
@@ -2101,8 +6639,8 @@ impl Editor {
     fn replace_current(&mut self, replacement: &str, viewport_width: usize) {
         if let Some(idx) = self.current_match_index {
             if let Some(&(start, end)) = self.find_matches.get(idx) {
-                // Finalize any pending undo group before starting replace
-                self.finalize_undo_group();
+                // The replacement is its own undo step
+                self.begin_undo_step(true);
                 
                 self.caret = start;
                 self.selection_anchor = Some(end);
@@ -2112,10 +6650,7 @@ impl Editor {
                     self.insert_char(ch, viewport_width);
                 }
                 
-                // Finalize the replace operation as its own undo group
-                self.finalize_undo_group();
-                // Reset last edit time to prevent timing issues with immediate undo
-                self.last_edit_time = None;
+                self.end_undo_step();
                 
                 let query = if let AppState::Prompting(ref prompt) = self.app_state {
                     prompt.input.clone()
@@ -2161,8 +6696,8 @@ impl Editor {
             return;
         }
 
-        // Finalize any pending undo group before starting replace all
-        self.finalize_undo_group();
+        // Every replacement is undone together
+        self.begin_undo_step(true);
 
         self.update_find_matches(query);
         
@@ -2182,10 +6717,7 @@ impl Editor {
             }
         }
         
-        // Finalize the replace all operation as its own undo group
-        self.finalize_undo_group();
-        // Reset last edit time to prevent timing issues with immediate undo
-        self.last_edit_time = None;
+        self.end_undo_step();
     }
 
     fn refresh_find_matches_if_active(&mut self) {
@@ -2207,6 +6739,9 @@ fn main() -> Result<(), Box<dyn Error>> {
     enable_raw_mode()?;
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    // Windows delivers pastes as key events either way
+    #[cfg(not(target_os = "windows"))]
+    execute!(stdout, EnableBracketedPaste)?;
     
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
@@ -2220,6 +6755,8 @@ fn main() -> Result<(), Box<dyn Error>> {
     let result = run_app(&mut terminal);
     
     disable_raw_mode()?;
+    #[cfg(not(target_os = "windows"))]
+    execute!(terminal.backend_mut(), DisableBracketedPaste)?;
     execute!(
         terminal.backend_mut(),
         LeaveAlternateScreen,
@@ -2237,6 +6774,11 @@ fn main() -> Result<(), Box<dyn Error>> {
 
 fn run_app<B: Backend>(terminal: &mut Terminal<B>) -> io::Result<()> {
     let mut editor = Editor::new();
+    editor.macros = load_macros();
+    if let Some(history) = load_clipboard_history() {
+        editor.clipboard_history = history;
+        editor.persist_clipboard_history = true;
+    }
     
     if let Some(filename) = env::args().nth(1) {
         let path = PathBuf::from(filename);
@@ -2246,6 +6788,11 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>) -> io::Result<()> {
             editor.modified = false;
         }
     }
+    // Further files open in panes side by side
+    for filename in env::args().skip(2) {
+        editor.open_in_new_pane(PathBuf::from(filename));
+    }
+    editor.focus_pane(0);
     
     execute!(io::stdout(), SetTitle(&editor.get_display_name()))?;
     
@@ -2254,8 +6801,9 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>) -> io::Result<()> {
         #[cfg(target_os = "windows")]
         {
             let size = terminal.size().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-            let viewport_height = size.height as usize - 1;
-            let viewport_width = size.width as usize;
+            let area = focused_viewport(&editor, size.width, size.height);
+            let viewport_height = area.height as usize;
+            let viewport_width = area.width as usize;
             
             // Update viewport BEFORE drawing to get correct positions
             editor.ensure_visual_lines(viewport_width);
@@ -2294,7 +6842,7 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>) -> io::Result<()> {
                 if screen_col < viewport_width {
                     execute!(
                         io::stdout(),
-                        MoveTo(screen_col as u16, screen_row as u16)
+                        MoveTo(area.x + screen_col as u16, area.y + screen_row as u16)
                     ).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
                 }
             }
@@ -2323,512 +6871,1057 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>) -> io::Result<()> {
                 }
                 
                 let size = terminal.size().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-                let viewport_width = size.width as usize;
-                let viewport_height = size.height as usize - 1;
+                let area = focused_viewport(&editor, size.width, size.height);
                 
+                handle_key(&mut editor, key, area.width as usize, area.height as usize)?;
+            }
+            Event::Paste(text) => {
+                let (width, height) = crossterm::terminal::size()?;
+                let area = focused_viewport(&editor, width, height);
+                handle_paste(&mut editor, &text, area.width as usize, area.height as usize);
+            }
+            Event::Mouse(mouse) => {
                 match &mut editor.app_state {
                     AppState::Prompting(prompt) => {
-                        // Handle buffer-focused input for find/replace mode
-                        if matches!(prompt.prompt_type, PromptType::FindReplace) && prompt.active_field == FindReplaceField::Buffer {
-                            // Allow normal editor commands except Tab and Esc
-                            match key.code {
-                                KeyCode::Esc => {
-                                    editor.clear_find_matches();
-                                    #[cfg(target_os = "windows")]
-                                    {
-                                        editor.modal_just_dismissed = true;
-                                    }
-                                    editor.app_state = AppState::Editing;
-                                }
-                                KeyCode::Tab => {
-                                    // Switch focus back to find field
-                                    prompt.active_field = FindReplaceField::Find;
-                                }
-                                KeyCode::Char('f') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                                    if key.modifiers.contains(event::KeyModifiers::SHIFT) {
-                                        editor.find_previous();
-                                    } else {
-                                        editor.find_next();
-                                    }
-                                    editor.update_viewport(viewport_height, viewport_width);
-                                }
-                                KeyCode::Char('h') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                                    if key.modifiers.contains(event::KeyModifiers::SHIFT) {
-                                        let query = prompt.input.clone();
-                                        let replacement = prompt.replace_input.clone();
-                                        editor.replace_all(&query, &replacement, viewport_width);
-                                        editor.update_viewport(viewport_height, viewport_width);
-                                        editor.clear_find_matches();
-                                        editor.app_state = AppState::Editing;
-                                    } else {
-                                        let replacement = prompt.replace_input.clone();
-                                        editor.replace_current(&replacement, viewport_width);
-                                        editor.update_viewport(viewport_height, viewport_width);
-                                    }
-                                }
-                                KeyCode::Char('q') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                                    // Dismiss the modal first
-                                    editor.clear_find_matches();
-                                    #[cfg(target_os = "windows")]
-                                    {
-                                        editor.modal_just_dismissed = true;
-                                    }
-                                    editor.app_state = AppState::Editing;
-                                    
-                                    // Then follow normal Ctrl+Q behavior
-                                    if editor.modified {
-                                        editor.app_state = AppState::Prompting(Prompt::new_confirm_save());
-                                    } else {
-                                        return Ok(());
-                                    }
-                                }
-                                _ => {
-                                    // Handle normal editor commands
-                                    handle_editor_key(&mut editor, key, viewport_width, viewport_height)?;
-                                }
-                            }
-                        } else {
-                            // Normal prompt handling
-                            match key.code {
-                                KeyCode::Esc => {
-                                    if matches!(prompt.prompt_type, PromptType::FindReplace) {
-                                        editor.clear_find_matches();
-                                    }
-                                    #[cfg(target_os = "windows")]
-                                    {
-                                        editor.modal_just_dismissed = true;
-                                    }
-                                    editor.app_state = AppState::Editing;
-                                }
-                            KeyCode::Enter => {
-                                match prompt.prompt_type {
-                                    PromptType::SaveAs => {
-                                        if !prompt.input.is_empty() {
-                                            let path = PathBuf::from(&prompt.input);
-                                            if let Err(e) = editor.save_as(path) {
-                                                // TODO: Show error message
-                                                eprintln!("Save failed: {:?}", e);
-                                            } else {
-                                                execute!(io::stdout(), SetTitle(&editor.get_display_name()))?;
-                                            }
-                                            editor.clear_find_matches();
-                                            #[cfg(target_os = "windows")]
-                                            {
-                                                editor.modal_just_dismissed = true;
-                                            }
-                                            editor.app_state = AppState::Editing;
-                                        }
-                                    }
-                                    PromptType::ConfirmSave => {
-                                        // Handle in the key event below
-                                    }
-                                    PromptType::FindReplace => {
-                                        // Handle Enter for find operation
-                                        let query = prompt.input.clone();
-                                        editor.update_find_matches(&query);
-                                    }
-                                }
-                            }
-                            KeyCode::Char('a') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                                prompt.select_all();
-                            }
-                            KeyCode::Char('c') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                                prompt.copy();
-                            }
-                            KeyCode::Char('x') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                                prompt.cut();
-                            }
-                            KeyCode::Char('v') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                                prompt.paste();
-                            }
-                            KeyCode::Char('q') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                                // Dismiss the modal first
-                                if matches!(prompt.prompt_type, PromptType::FindReplace) {
-                                    editor.clear_find_matches();
-                                }
-                                #[cfg(target_os = "windows")]
-                                {
-                                    editor.modal_just_dismissed = true;
-                                }
-                                editor.app_state = AppState::Editing;
-                                
-                                // Then follow normal Ctrl+Q behavior
-                                if editor.modified {
-                                    editor.app_state = AppState::Prompting(Prompt::new_confirm_save());
-                                } else {
-                                    return Ok(());
+                        // Get the prompt area coordinates
+                        let size = terminal.size().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                        let area = centered_rect(60, 20, Rect::new(0, 0, size.width, size.height));
+                        let inner = Block::default()
+                            .borders(Borders::ALL)
+                            .inner(area);
+                        
+                        let input_area = Layout::default()
+                            .direction(Direction::Vertical)
+                            .constraints([
+                                Constraint::Length(1),
+                                Constraint::Length(1),
+                                Constraint::Min(1),
+                            ])
+                            .split(inner);
+                        
+                        let input_y = input_area[1].y;
+                        
+                        match mouse.kind {
+                            MouseEventKind::Down(MouseButton::Left) => {
+                                if mouse.row == input_y && 
+                                   mouse.column >= inner.x && 
+                                   mouse.column < inner.x + inner.width {
+                                    let shift_held = mouse.modifiers.contains(event::KeyModifiers::SHIFT);
+                                    prompt.handle_click(mouse.column, inner, shift_held);
                                 }
                             }
-                            KeyCode::Tab if matches!(prompt.prompt_type, PromptType::FindReplace) => {
-                                // Switch between find, replace, and buffer
-                                match prompt.active_field {
-                                    FindReplaceField::Find => prompt.active_field = FindReplaceField::Replace,
-                                    FindReplaceField::Replace => prompt.active_field = FindReplaceField::Buffer,
-                                    FindReplaceField::Buffer => prompt.active_field = FindReplaceField::Find,
+                            MouseEventKind::Drag(MouseButton::Left) => {
+                                if mouse.row == input_y &&
+                                   mouse.column >= inner.x && 
+                                   mouse.column < inner.x + inner.width {
+                                    prompt.handle_drag(mouse.column, inner);
                                 }
                             }
-                            KeyCode::Char('z') if key.modifiers.contains(event::KeyModifiers::CONTROL) && matches!(prompt.prompt_type, PromptType::FindReplace) => {
-                                // Undo in main buffer
-                                editor.undo();
-                                editor.refresh_find_matches_if_active();
-                                editor.update_viewport(viewport_height, viewport_width);
-                            }
-                            KeyCode::Char('y') if key.modifiers.contains(event::KeyModifiers::CONTROL) && matches!(prompt.prompt_type, PromptType::FindReplace) => {
-                                // Redo in main buffer
-                                editor.redo();
-                                editor.refresh_find_matches_if_active();
-                                editor.update_viewport(viewport_height, viewport_width);
-                            }
-                            KeyCode::Char('f') if key.modifiers.contains(event::KeyModifiers::CONTROL) && matches!(prompt.prompt_type, PromptType::FindReplace) => {
-                                if key.modifiers.contains(event::KeyModifiers::ALT) {
-                                    // Find previous (Ctrl+Alt+F)
-                                    editor.find_previous();
-                                } else {
-                                    // Find next
-                                    editor.find_next();
+                            _ => {}
+                        }
+                    }
+                    AppState::Editing => {
+                        let size = terminal.size().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                        let (text_area, _, _) = screen_areas(&editor, Rect::new(0, 0, size.width, size.height));
+                        match mouse.kind {
+                            MouseEventKind::Down(MouseButton::Left) => {
+                                // The pane under the pointer takes the click and the focus
+                                let Some((pane, pane_area)) = editor.pane_at(text_area, mouse.column, mouse.row) else {
+                                    continue;
+                                };
+                                editor.focus_pane(pane);
+                                let (area, scrollbar_area) = split_scrollbar(pane_area);
+                                let viewport_width = area.width as usize;
+                                editor.ensure_visual_lines(viewport_width);
+                                
+                                if scrollbar_area.width > 0 && mouse.column == scrollbar_area.x {
+                                    // Jump to the clicked spot and follow the pointer while it drags
+                                    editor.scroll_to_scrollbar_row((mouse.row - area.y) as usize, area.height as usize, viewport_width);
+                                    editor.dragging_scrollbar = true;
+                                    continue;
                                 }
-                                editor.update_viewport(viewport_height, viewport_width);
-                            }
-                            KeyCode::Char('r') if key.modifiers.contains(event::KeyModifiers::CONTROL) && matches!(prompt.prompt_type, PromptType::FindReplace) => {
-                                if key.modifiers.contains(event::KeyModifiers::ALT) {
-                                    // Replace all (Ctrl+Alt+R)
-                                    let query = prompt.input.clone();
-                                    let replacement = prompt.replace_input.clone();
-                                    editor.replace_all(&query, &replacement, viewport_width);
-                                    editor.update_viewport(viewport_height, viewport_width);
-                                    editor.clear_find_matches();
-                                    #[cfg(target_os = "windows")]
-                                    {
-                                        editor.modal_just_dismissed = true;
-                                    }
-                                    editor.app_state = AppState::Editing;
-                                } else {
-                                    // Replace current and find next
-                                    let replacement = prompt.replace_input.clone();
-                                    editor.replace_current(&replacement, viewport_width);
-                                    editor.update_viewport(viewport_height, viewport_width);
+                                
+                                if mouse.modifiers.contains(event::KeyModifiers::ALT) {
+                                    // Alt+drag starts a column selection
+                                    editor.clear_extra_cursors();
+                                    let (row, col) = editor.screen_to_visual(mouse.column, mouse.row, area);
+                                    editor.start_block_selection(row, col, viewport_width);
+                                    editor.is_dragging = true;
+                                    continue;
                                 }
-                            }
-                            KeyCode::Char(ch) => {
-                                match prompt.prompt_type {
-                                    PromptType::ConfirmSave => {
-                                        match ch.to_ascii_lowercase() {
-                                            'y' => {
-                                                if editor.filename.is_some() {
-                                                    if let Err(e) = editor.save() {
-                                                        eprintln!("Save failed: {:?}", e);
-                                                    }
-                                                    editor.app_state = AppState::Exiting;
-                                                } else {
-                                                    let path = editor.get_save_path_suggestion();
-                                                    editor.app_state = AppState::Prompting(Prompt::new_save_as(path));
-                                                }
-                                            }
-                                            'n' => {
-                                                editor.app_state = AppState::Exiting;
-                                            }
-                                            'c' => {
-                                                if matches!(prompt.prompt_type, PromptType::FindReplace) {
-                                                    editor.clear_find_matches();
-                                                }
-                                                #[cfg(target_os = "windows")]
-                                                {
-                                                    editor.modal_just_dismissed = true;
-                                                }
-                                                editor.app_state = AppState::Editing;
-                                            }
-                                            _ => {}
-                                        }
-                                    }
+                                
+                                let shift_held = mouse.modifiers.contains(event::KeyModifiers::SHIFT);
+                                let click_count = if shift_held { 1 } else { editor.register_click(mouse.column, mouse.row) };
+                                editor.handle_click(mouse.column, mouse.row, area, viewport_width, shift_held);
+                                
+                                editor.is_dragging = true;
+                                match click_count {
+                                    2 => editor.select_word_at_caret(viewport_width),
+                                    3 => editor.select_line_at_caret(),
                                     _ => {
-                                        prompt.insert_char(ch);
-                                        if matches!(prompt.prompt_type, PromptType::FindReplace) && prompt.active_field == FindReplaceField::Find {
-                                            let query = prompt.input.clone();
-                                            editor.update_find_matches(&query);
+                                        if !shift_held {
+                                            editor.selection_anchor = Some(editor.caret);
                                         }
                                     }
                                 }
                             }
-                            KeyCode::Backspace => {
-                                prompt.backspace();
-                                if matches!(prompt.prompt_type, PromptType::FindReplace) && prompt.active_field == FindReplaceField::Find {
-                                    let query = prompt.input.clone();
-                                    editor.update_find_matches(&query);
-                                }
+                            MouseEventKind::Drag(MouseButton::Left) if editor.dragging_scrollbar => {
+                                let area = editor.focused_pane_area(text_area);
+                                let bar_row = mouse.row.saturating_sub(area.y).min(area.height.saturating_sub(1));
+                                editor.scroll_to_scrollbar_row(bar_row as usize, area.height as usize, area.width as usize);
                             }
-                            KeyCode::Delete => {
-                                prompt.delete();
-                                if matches!(prompt.prompt_type, PromptType::FindReplace) && prompt.active_field == FindReplaceField::Find {
-                                    let query = prompt.input.clone();
-                                    editor.update_find_matches(&query);
+                            MouseEventKind::Drag(MouseButton::Left) if editor.is_dragging => {
+                                // A drag stays in the pane it started in
+                                let area = editor.focused_pane_area(text_area);
+                                let viewport_width = area.width as usize;
+                                
+                                if editor.block_anchor.is_some() {
+                                    let (row, col) = editor.screen_to_visual(mouse.column, mouse.row, area);
+                                    editor.block_select_to(row, col, viewport_width);
+                                    continue;
+                                }
+                                
+                                let click_row = editor.viewport_offset.0 + mouse.row.saturating_sub(area.y) as usize;
+                                let click_col = editor.viewport_offset.1 + mouse.column.saturating_sub(area.x) as usize;
+                                
+                                if click_row >= editor.virtual_lines && 
+                                   click_row < editor.visual_lines.len() - editor.virtual_lines {
+                                    if let Some(Some(vline)) = editor.visual_lines.get(click_row) {
+                                        let actual_col = if vline.is_continuation {
+                                            click_col.max(vline.indent)
+                                        } else {
+                                            click_col
+                                        };
+                                        editor.caret = editor.visual_to_byte(click_row, actual_col, viewport_width);
+                                        editor.preferred_col = actual_col;
+                                    }
                                 }
                             }
-                            KeyCode::Left => {
-                                prompt.move_cursor_left(key.modifiers.contains(event::KeyModifiers::SHIFT));
-                            }
-                            KeyCode::Right => {
-                                prompt.move_cursor_right(key.modifiers.contains(event::KeyModifiers::SHIFT));
-                            }
-                            KeyCode::Home => {
-                                prompt.move_cursor_home(key.modifiers.contains(event::KeyModifiers::SHIFT));
+                            MouseEventKind::Up(MouseButton::Left) => {
+                                editor.is_dragging = false;
+                                editor.dragging_scrollbar = false;
                             }
-                            KeyCode::End => {
-                                prompt.move_cursor_end(key.modifiers.contains(event::KeyModifiers::SHIFT));
+                            MouseEventKind::ScrollUp | MouseEventKind::ScrollDown
+                            | MouseEventKind::ScrollLeft | MouseEventKind::ScrollRight => {
+                                // The wheel scrolls the pane under the pointer without focusing it
+                                let Some((pane, pane_area)) = editor.pane_at(text_area, mouse.column, mouse.row) else {
+                                    continue;
+                                };
+                                let (area, _) = split_scrollbar(pane_area);
+                                let shift = mouse.modifiers.contains(event::KeyModifiers::SHIFT);
+                                editor.with_pane(pane, |ed| {
+                                    ed.scroll_wheel(mouse.kind, shift, area.width as usize, area.height as usize);
+                                });
                             }
                             _ => {}
                         }
+                    }
+                    AppState::Exiting => {}
+                }
+            }
+            Event::Resize(_, _) => {
+                let size = terminal.size().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                let area = focused_viewport(&editor, size.width, size.height);
+                editor.invalidate_views();
+                editor.update_viewport(area.height as usize, area.width as usize);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Dispatches one key press to the prompt or the editor, whichever has
+/// focus.
+fn handle_key(editor: &mut Editor, key: event::KeyEvent, viewport_width: usize, viewport_height: usize) -> io::Result<()> {
+    // Macro recording sits in front of every handler so it captures
+    // exactly the keys they are given
+    if editor.macro_register_pending {
+        editor.macro_register_pending = false;
+        if let KeyCode::Char(register) = key.code {
+            if is_macro_register(register) && !key.modifiers.intersects(event::KeyModifiers::CONTROL | event::KeyModifiers::ALT) {
+                editor.recording = Some((register, Vec::new()));
+                editor.recording_missed_paste = false;
+            }
+        }
+        return Ok(());
+    }
+    if key.code == KeyCode::Char('q') && key.modifiers == event::KeyModifiers::ALT {
+        if !editor.replaying {
+            editor.toggle_macro_recording();
+        }
+        return Ok(());
+    }
+    let macro_panel_key = match &editor.app_state {
+        AppState::Editing => key.code == KeyCode::Char('m') && key.modifiers == event::KeyModifiers::ALT,
+        AppState::Prompting(prompt) => matches!(prompt.prompt_type, PromptType::Macros),
+        AppState::Exiting => false,
+    };
+    if macro_panel_key && editor.replaying {
+        // Macros cannot start other macros
+        return Ok(());
+    }
+    if let Some((_, keys)) = &mut editor.recording {
+        if !macro_panel_key {
+            keys.push(key);
+        }
+    }
+    
+    match &mut editor.app_state {
+        AppState::Prompting(prompt) => {
+            // Handle buffer-focused input for find/replace mode
+            if matches!(prompt.prompt_type, PromptType::FindReplace) && prompt.active_field == FindReplaceField::Buffer {
+                // Allow normal editor commands except Tab and Esc
+                match key.code {
+                    KeyCode::Esc => {
+                        editor.clear_find_matches();
+                        #[cfg(target_os = "windows")]
+                        {
+                            editor.modal_just_dismissed = true;
                         }
+                        editor.app_state = AppState::Editing;
                     }
-                    AppState::Editing => {
-                        match key.code {
-                            KeyCode::Char('q') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                                if editor.modified {
-                                    editor.app_state = AppState::Prompting(Prompt::new_confirm_save());
-                                } else {
+                    KeyCode::Tab => {
+                        // Switch focus back to find field
+                        prompt.active_field = FindReplaceField::Find;
+                    }
+                    KeyCode::Char('f') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                        if key.modifiers.contains(event::KeyModifiers::SHIFT) {
+                            editor.find_previous();
+                        } else {
+                            editor.find_next();
+                        }
+                        editor.update_viewport(viewport_height, viewport_width);
+                    }
+                    KeyCode::Char('h') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                        if key.modifiers.contains(event::KeyModifiers::SHIFT) {
+                            let query = prompt.input.clone();
+                            let replacement = prompt.replace_input.clone();
+                            editor.replace_all(&query, &replacement, viewport_width);
+                            editor.update_viewport(viewport_height, viewport_width);
+                            editor.clear_find_matches();
+                            editor.app_state = AppState::Editing;
+                        } else {
+                            let replacement = prompt.replace_input.clone();
+                            editor.replace_current(&replacement, viewport_width);
+                            editor.update_viewport(viewport_height, viewport_width);
+                        }
+                    }
+                    KeyCode::Char('q') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                        // Dismiss the modal first
+                        editor.clear_find_matches();
+                        #[cfg(target_os = "windows")]
+                        {
+                            editor.modal_just_dismissed = true;
+                        }
+                        editor.app_state = AppState::Editing;
+
+                        // Then follow normal Ctrl+Q behavior
+                        editor.confirm_quit_from(0);
+                    }
+                    _ => {
+                        // Handle normal editor commands
+                        handle_editor_key(editor, key, viewport_width, viewport_height)?;
+                    }
+                }
+            } else {
+                // Normal prompt handling
+                match key.code {
+                    KeyCode::Esc => {
+                        // Drop an undo tree preview and go back to where the panel was opened
+                        let origin = matches!(prompt.prompt_type, PromptType::UndoTree).then_some(prompt.origin_state);
+                        if matches!(prompt.prompt_type, PromptType::FindReplace) {
+                            editor.clear_find_matches();
+                        }
+                        if let Some(origin) = origin {
+                            editor.goto_undo_state(origin);
+                            editor.update_viewport(viewport_height, viewport_width);
+                        }
+                        #[cfg(target_os = "windows")]
+                        {
+                            editor.modal_just_dismissed = true;
+                        }
+                        editor.app_state = AppState::Editing;
+                    }
+                KeyCode::Enter => {
+                    match prompt.prompt_type {
+                        PromptType::SaveAs => {
+                            if !prompt.input.is_empty() {
+                                let input = prompt.input.clone();
+                                let closes_pane = prompt.closes_pane;
+                                if let Err(e) = editor.save_as(PathBuf::from(&input)) {
+                                    // Keep the prompt, and the pane, open to try another path
+                                    let mut retry = if closes_pane {
+                                        Prompt::new_save_as_then_close(input)
+                                    } else {
+                                        Prompt::new_save_as(input)
+                                    };
+                                    retry.message = format!("Save failed: {}. {}", e, retry.message);
+                                    editor.app_state = AppState::Prompting(retry);
                                     return Ok(());
                                 }
-                            }
-                            KeyCode::Char('s') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                                if key.modifiers.contains(event::KeyModifiers::SHIFT) || key.modifiers.contains(event::KeyModifiers::ALT) {
-                                    // Save As (Ctrl+Shift+S or Ctrl+Alt+S)
-                                    let path = editor.get_save_path_suggestion();
-                                    editor.app_state = AppState::Prompting(Prompt::new_save_as(path));
-                                } else {
-                                    // Save (Ctrl+S)
-                                    if editor.filename.is_some() {
-                                        if let Err(e) = editor.save() {
-                                            eprintln!("Save failed: {:?}", e);
-                                        } else {
-                                            execute!(io::stdout(), SetTitle(&editor.get_display_name()))?;
-                                        }
-                                    } else {
-                                        let path = editor.get_save_path_suggestion();
-                                        editor.app_state = AppState::Prompting(Prompt::new_save_as(path));
-                                    }
+                                if closes_pane {
+                                    editor.close_pane();
                                 }
-                            }
-                            KeyCode::F(12) => {
-                                // Save As (F12) - Alternative to Ctrl+Shift+S
-                                let path = editor.get_save_path_suggestion();
-                                editor.app_state = AppState::Prompting(Prompt::new_save_as(path));
-                            }
-                            KeyCode::Char('a') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                                editor.select_all();
-                                editor.update_viewport(viewport_height, viewport_width);
-                            }
-                            KeyCode::Char('c') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                                editor.copy();
-                            }
-                            KeyCode::Char('x') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                                if editor.cut() {
-                                    editor.update_viewport(viewport_height, viewport_width);
+                                execute!(io::stdout(), SetTitle(&editor.get_display_name()))?;
+                                editor.clear_find_matches();
+                                #[cfg(target_os = "windows")]
+                                {
+                                    editor.modal_just_dismissed = true;
                                 }
+                                editor.app_state = AppState::Editing;
                             }
-                            KeyCode::Char('v') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                                editor.paste(viewport_width);
-                                editor.update_viewport(viewport_height, viewport_width);
-                            }
-                            KeyCode::Char('w') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                                editor.word_wrap = !editor.word_wrap;
-                                editor.invalidate_visual_lines();
-                                editor.logical_line_map.clear();
-                            }
-                            KeyCode::Char('z') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                                editor.undo();
-                                editor.update_viewport(viewport_height, viewport_width);
+                        }
+                        PromptType::ConfirmSave | PromptType::ConfirmClose => {
+                            // Handle in the key event below
+                        }
+                        PromptType::FindReplace => {
+                            // Handle Enter for find operation
+                            let query = prompt.input.clone();
+                            editor.update_find_matches(&query);
+                        }
+                        PromptType::UndoTree => {
+                            if prompt.input.trim().is_empty() {
+                                // Keep the state being previewed
+                            } else if let Ok(state) = prompt.input.trim().parse::<usize>() {
+                                editor.goto_undo_state(state);
+                            } else if let Some((later, amount)) = parse_time_jump(&prompt.input) {
+                                editor.jump_undo_time(later, amount);
+                            } else {
+                                prompt.message = format!("Not a state or time: {}", prompt.input);
+                                return Ok(());
                             }
-                            KeyCode::Char('y') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                                editor.redo();
-                                editor.update_viewport(viewport_height, viewport_width);
+                            editor.update_viewport(viewport_height, viewport_width);
+                            #[cfg(target_os = "windows")]
+                            {
+                                editor.modal_just_dismissed = true;
                             }
-                            KeyCode::Char('f') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                                editor.app_state = AppState::Prompting(Prompt::new_find_replace());
+                            editor.app_state = AppState::Editing;
+                        }
+                        PromptType::Macros => {
+                            let input = prompt.input.clone();
+                            if let Some((register, text)) = parse_macro_definition(&input) {
+                                // Define or edit a register and stay in the panel
+                                prompt.message = match parse_macro_keys(text) {
+                                    Ok(keys) => {
+                                        if keys.is_empty() {
+                                            editor.macros.remove(&register);
+                                        } else {
+                                            editor.macros.insert(register, keys);
+                                        }
+                                        match save_macros(&editor.macros) {
+                                            Ok(()) => format!("Saved @{}", register),
+                                            Err(e) => format!("Kept @{} for this session, save failed: {}", register, e),
+                                        }
+                                    }
+                                    Err(message) => message,
+                                };
+                                prompt.items = macro_entries(&editor.macros);
+                                prompt.input.clear();
+                                prompt.cursor_pos = 0;
+                                prompt.selection_anchor = None;
+                                return Ok(());
                             }
-                            KeyCode::Tab => {
-                                if key.modifiers.contains(event::KeyModifiers::SHIFT) {
-                                    editor.dedent(viewport_width);
-                                } else {
-                                    editor.indent(viewport_width);
+                            
+                            let selected = prompt.visible_items()
+                                .get(prompt.selected_item)
+                                .and_then(|(register, _)| char::from_u32(*register as u32));
+                            match parse_macro_run(&input, selected) {
+                                Some((register, repeat)) if editor.macros.contains_key(&register) => {
+                                    let keys = editor.macros[&register].clone();
+                                    #[cfg(target_os = "windows")]
+                                    {
+                                        editor.modal_just_dismissed = true;
+                                    }
+                                    editor.app_state = AppState::Editing;
+                                    replay_macro(editor, &keys, repeat, viewport_width, viewport_height)?;
                                 }
-                                editor.update_viewport(viewport_height, viewport_width);
-                            }
-                            KeyCode::BackTab => {
-                                editor.dedent(viewport_width);
-                                editor.update_viewport(viewport_height, viewport_width);
-                            }
-                            KeyCode::Char(c) => {
-                                editor.insert_char(c, viewport_width);
-                                editor.update_viewport(viewport_height, viewport_width);
+                                Some((register, _)) => prompt.message = format!("Register @{} is empty", register),
+                                None => prompt.message = format!("Not a register: {}", input.trim()),
                             }
-                            KeyCode::Enter => {
-                                editor.insert_char('\n', viewport_width);
-                                editor.preferred_col = 0;
-                                editor.update_viewport(viewport_height, viewport_width);
+                        }
+                        PromptType::ClipboardHistory => {
+                            let target = prompt.visible_items()
+                                .get(prompt.selected_item)
+                                .map(|(index, _)| *index);
+                            #[cfg(target_os = "windows")]
+                            {
+                                editor.modal_just_dismissed = true;
                             }
-                            KeyCode::Backspace => {
-                                editor.backspace(viewport_width);
+                            editor.app_state = AppState::Editing;
+                            if let Some(index) = target {
+                                editor.paste_history_entry(index, viewport_width);
                                 editor.update_viewport(viewport_height, viewport_width);
                             }
-                            KeyCode::Delete => {
-                                editor.delete(viewport_width);
-                                editor.update_viewport(viewport_height, viewport_width);
+                        }
+                        PromptType::LineCommands => {
+                            let target = prompt.visible_items()
+                                .get(prompt.selected_item)
+                                .map(|(index, _)| LINE_COMMANDS[*index].0);
+                            #[cfg(target_os = "windows")]
+                            {
+                                editor.modal_just_dismissed = true;
                             }
-                            KeyCode::Left => {
-                                editor.move_left(viewport_width, key.modifiers.contains(event::KeyModifiers::SHIFT));
+                            editor.app_state = AppState::Editing;
+                            if let Some(command) = target {
+                                editor.line_command_at_cursors(command, viewport_width);
                                 editor.update_viewport(viewport_height, viewport_width);
                             }
-                            KeyCode::Right => {
-                                editor.move_right(viewport_width, key.modifiers.contains(event::KeyModifiers::SHIFT));
-                                editor.update_viewport(viewport_height, viewport_width);
+                        }
+                        PromptType::Transforms => {
+                            let target = prompt.visible_items()
+                                .get(prompt.selected_item)
+                                .map(|(index, _)| TEXT_TRANSFORMS[*index].0);
+                            #[cfg(target_os = "windows")]
+                            {
+                                editor.modal_just_dismissed = true;
                             }
-                            KeyCode::Up => {
-                                if key.modifiers.contains(event::KeyModifiers::CONTROL) && key.modifiers.contains(event::KeyModifiers::SHIFT) {
-                                    editor.move_lines_up(viewport_width);
-                                    editor.update_viewport(viewport_height, viewport_width);
-                                } else {
-                                    editor.move_up(viewport_width, key.modifiers.contains(event::KeyModifiers::SHIFT));
-                                    editor.update_viewport(viewport_height, viewport_width);
+                            editor.app_state = AppState::Editing;
+                            if let Some(transform) = target {
+                                if let Err(message) = editor.transform_at_cursors(transform, viewport_width) {
+                                    // Reopen the panel to say why nothing changed
+                                    let mut prompt = Prompt::new_transforms();
+                                    prompt.message = message;
+                                    editor.app_state = AppState::Prompting(prompt);
                                 }
+                                editor.update_viewport(viewport_height, viewport_width);
                             }
-                            KeyCode::Down => {
-                                if key.modifiers.contains(event::KeyModifiers::CONTROL) && key.modifiers.contains(event::KeyModifiers::SHIFT) {
-                                    editor.move_lines_down(viewport_width);
-                                    editor.update_viewport(viewport_height, viewport_width);
-                                } else {
-                                    editor.move_down(viewport_width, key.modifiers.contains(event::KeyModifiers::SHIFT));
+                        }
+                        PromptType::ReflowColumn => {
+                            let column = if prompt.input.trim().is_empty() {
+                                prompt.visible_items().get(prompt.selected_item).map(|(column, _)| *column)
+                            } else {
+                                prompt.input.trim().parse().ok()
+                            };
+                            match column {
+                                Some(column) if column >= 10 => {
+                                    editor.wrap_column = column;
+                                    #[cfg(target_os = "windows")]
+                                    {
+                                        editor.modal_just_dismissed = true;
+                                    }
+                                    editor.app_state = AppState::Editing;
+                                    editor.reflow(viewport_width);
                                     editor.update_viewport(viewport_height, viewport_width);
                                 }
+                                _ => prompt.message = format!("Not a column of 10 or more: {}", prompt.input.trim()),
                             }
-                            KeyCode::PageUp => {
-                                editor.page_up(viewport_width, viewport_height, key.modifiers.contains(event::KeyModifiers::SHIFT));
+                        }
+                        PromptType::TaskList => {
+                            let target = prompt.visible_items()
+                                .get(prompt.selected_item)
+                                .map(|(line_idx, _)| *line_idx);
+                            if let Some(line_idx) = target {
+                                editor.jump_to_task(line_idx, viewport_width);
                                 editor.update_viewport(viewport_height, viewport_width);
                             }
-                            KeyCode::PageDown => {
-                                editor.page_down(viewport_width, viewport_height, key.modifiers.contains(event::KeyModifiers::SHIFT));
-                                editor.update_viewport(viewport_height, viewport_width);
+                            #[cfg(target_os = "windows")]
+                            {
+                                editor.modal_just_dismissed = true;
                             }
-                            _ => {}
+                            editor.app_state = AppState::Editing;
                         }
-                        
-                        execute!(io::stdout(), SetTitle(&editor.get_display_name()))?;
                     }
-                    AppState::Exiting => {}
                 }
-            }
-            Event::Mouse(mouse) => {
-                match &mut editor.app_state {
-                    AppState::Prompting(prompt) => {
-                        // Get the prompt area coordinates
-                        let size = terminal.size().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-                        let area = centered_rect(60, 20, Rect::new(0, 0, size.width, size.height));
-                        let inner = Block::default()
-                            .borders(Borders::ALL)
-                            .inner(area);
-                        
-                        let input_area = Layout::default()
-                            .direction(Direction::Vertical)
-                            .constraints([
-                                Constraint::Length(1),
-                                Constraint::Length(1),
-                                Constraint::Min(1),
-                            ])
-                            .split(inner);
-                        
-                        let input_y = input_area[1].y;
-                        
-                        match mouse.kind {
-                            MouseEventKind::Down(MouseButton::Left) => {
-                                if mouse.row == input_y && 
-                                   mouse.column >= inner.x && 
-                                   mouse.column < inner.x + inner.width {
-                                    let shift_held = mouse.modifiers.contains(event::KeyModifiers::SHIFT);
-                                    prompt.handle_click(mouse.column, inner, shift_held);
+                KeyCode::Char('a') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    prompt.select_all();
+                }
+                KeyCode::Char('c') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    prompt.copy();
+                }
+                KeyCode::Char('x') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    prompt.cut();
+                }
+                KeyCode::Char('v') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    prompt.paste();
+                }
+                KeyCode::Char('q') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    // Dismiss the modal first
+                    if matches!(prompt.prompt_type, PromptType::FindReplace) {
+                        editor.clear_find_matches();
+                    }
+                    #[cfg(target_os = "windows")]
+                    {
+                        editor.modal_just_dismissed = true;
+                    }
+                    editor.app_state = AppState::Editing;
+
+                    // Then follow normal Ctrl+Q behavior
+                    editor.confirm_quit_from(0);
+                }
+                KeyCode::Char('p') if key.modifiers.contains(event::KeyModifiers::CONTROL) && matches!(prompt.prompt_type, PromptType::ClipboardHistory) => {
+                    prompt.set_clipboard_history_message(!editor.persist_clipboard_history);
+                    editor.toggle_clipboard_persistence();
+                }
+                KeyCode::Tab if matches!(prompt.prompt_type, PromptType::Macros) => {
+                    // Edit the selected macro as text
+                    let selected = prompt.visible_items()
+                        .get(prompt.selected_item)
+                        .and_then(|(register, _)| char::from_u32(*register as u32));
+                    if let Some(register) = selected {
+                        prompt.input = format!("{} = {}", register, format_macro_keys(&editor.macros[&register]));
+                        prompt.cursor_pos = prompt.input.len();
+                        prompt.selection_anchor = None;
+                    }
+                }
+                KeyCode::Tab if matches!(prompt.prompt_type, PromptType::FindReplace) => {
+                    // Switch between find, replace, and buffer
+                    match prompt.active_field {
+                        FindReplaceField::Find => prompt.active_field = FindReplaceField::Replace,
+                        FindReplaceField::Replace => prompt.active_field = FindReplaceField::Buffer,
+                        FindReplaceField::Buffer => prompt.active_field = FindReplaceField::Find,
+                    }
+                }
+                KeyCode::Char('z') if key.modifiers.contains(event::KeyModifiers::CONTROL) && matches!(prompt.prompt_type, PromptType::FindReplace) => {
+                    // Undo in main buffer
+                    editor.undo();
+                    editor.refresh_find_matches_if_active();
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('y') if key.modifiers.contains(event::KeyModifiers::CONTROL) && matches!(prompt.prompt_type, PromptType::FindReplace) => {
+                    // Redo in main buffer
+                    editor.redo();
+                    editor.refresh_find_matches_if_active();
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('f') if key.modifiers.contains(event::KeyModifiers::CONTROL) && matches!(prompt.prompt_type, PromptType::FindReplace) => {
+                    if key.modifiers.contains(event::KeyModifiers::ALT) {
+                        // Find previous (Ctrl+Alt+F)
+                        editor.find_previous();
+                    } else {
+                        // Find next
+                        editor.find_next();
+                    }
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('r') if key.modifiers.contains(event::KeyModifiers::CONTROL) && matches!(prompt.prompt_type, PromptType::FindReplace) => {
+                    if key.modifiers.contains(event::KeyModifiers::ALT) {
+                        // Replace all (Ctrl+Alt+R)
+                        let query = prompt.input.clone();
+                        let replacement = prompt.replace_input.clone();
+                        editor.replace_all(&query, &replacement, viewport_width);
+                        editor.update_viewport(viewport_height, viewport_width);
+                        editor.clear_find_matches();
+                        #[cfg(target_os = "windows")]
+                        {
+                            editor.modal_just_dismissed = true;
+                        }
+                        editor.app_state = AppState::Editing;
+                    } else {
+                        // Replace current and find next
+                        let replacement = prompt.replace_input.clone();
+                        editor.replace_current(&replacement, viewport_width);
+                        editor.update_viewport(viewport_height, viewport_width);
+                    }
+                }
+                KeyCode::Char(ch) => {
+                    match prompt.prompt_type {
+                        PromptType::ConfirmSave => {
+                            // Files open in other panes are asked about in turn
+                            let next_buffer = editor.panes[editor.focused_pane].buffer + 1;
+                            match ch.to_ascii_lowercase() {
+                                'y' => {
+                                    if editor.filename.is_some() {
+                                        if let Err(e) = editor.save() {
+                                            eprintln!("Save failed: {:?}", e);
+                                        }
+                                        editor.confirm_quit_from(next_buffer);
+                                    } else {
+                                        let path = editor.get_save_path_suggestion();
+                                        editor.app_state = AppState::Prompting(Prompt::new_save_as(path));
+                                    }
                                 }
-                            }
-                            MouseEventKind::Drag(MouseButton::Left) => {
-                                if mouse.row == input_y &&
-                                   mouse.column >= inner.x && 
-                                   mouse.column < inner.x + inner.width {
-                                    prompt.handle_drag(mouse.column, inner);
+                                'n' => editor.confirm_quit_from(next_buffer),
+                                'c' => {
+                                    if matches!(prompt.prompt_type, PromptType::FindReplace) {
+                                        editor.clear_find_matches();
+                                    }
+                                    #[cfg(target_os = "windows")]
+                                    {
+                                        editor.modal_just_dismissed = true;
+                                    }
+                                    editor.app_state = AppState::Editing;
                                 }
+                                _ => {}
                             }
-                            _ => {}
                         }
-                    }
-                    AppState::Editing => {
-                        let size = terminal.size().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-                        match mouse.kind {
-                            MouseEventKind::Down(MouseButton::Left) => {
-                                let chunks = Layout::default()
-                                    .direction(Direction::Vertical)
-                                    .constraints([
-                                        Constraint::Min(0),
-                                        Constraint::Length(1),
-                                    ])
-                                    .split(Rect::new(0, 0, size.width, size.height));
-                                
-                                let shift_held = mouse.modifiers.contains(event::KeyModifiers::SHIFT);
-                                editor.handle_click(mouse.column, mouse.row, chunks[0], size.width as usize, shift_held);
-                                
-                                editor.is_dragging = true;
-                                if !shift_held {
-                                    editor.selection_anchor = Some(editor.caret);
-                                }
-                            }
-                            MouseEventKind::Drag(MouseButton::Left) => {
-                                if editor.is_dragging {
-                                    let chunks = Layout::default()
-                                        .direction(Direction::Vertical)
-                                        .constraints([
-                                            Constraint::Min(0),
-                                            Constraint::Length(1),
-                                        ])
-                                        .split(Rect::new(0, 0, size.width, size.height));
-                                    
-                                    let click_row = editor.viewport_offset.0 + mouse.row.saturating_sub(chunks[0].y) as usize;
-                                    let click_col = editor.viewport_offset.1 + mouse.column.saturating_sub(chunks[0].x) as usize;
-                                    
-                                    if click_row >= editor.virtual_lines && 
-                                       click_row < editor.visual_lines.len() - editor.virtual_lines {
-                                        if let Some(Some(vline)) = editor.visual_lines.get(click_row) {
-                                            let actual_col = if vline.is_continuation {
-                                                click_col.max(vline.indent)
-                                            } else {
-                                                click_col
-                                            };
-                                            editor.caret = editor.visual_to_byte(click_row, actual_col, size.width as usize);
-                                            editor.preferred_col = actual_col;
+                        PromptType::ConfirmClose => {
+                            match ch.to_ascii_lowercase() {
+                                'y' => {
+                                    if editor.filename.is_some() {
+                                        if let Err(e) = editor.save() {
+                                            // Stay in the prompt so the pane is not closed unnoticed
+                                            let mut retry = Prompt::new_confirm_close();
+                                            retry.message = format!("Save failed: {}. Retry, close anyway or cancel? (y/n/c)", e);
+                                            editor.app_state = AppState::Prompting(retry);
+                                            return Ok(());
                                         }
+                                        editor.close_pane();
+                                        #[cfg(target_os = "windows")]
+                                        {
+                                            editor.modal_just_dismissed = true;
+                                        }
+                                        editor.app_state = AppState::Editing;
+                                    } else {
+                                        let path = editor.get_save_path_suggestion();
+                                        editor.app_state = AppState::Prompting(Prompt::new_save_as_then_close(path));
+                                    }
+                                }
+                                'n' => {
+                                    editor.close_pane();
+                                    #[cfg(target_os = "windows")]
+                                    {
+                                        editor.modal_just_dismissed = true;
+                                    }
+                                    editor.app_state = AppState::Editing;
+                                }
+                                'c' => {
+                                    #[cfg(target_os = "windows")]
+                                    {
+                                        editor.modal_just_dismissed = true;
                                     }
+                                    editor.app_state = AppState::Editing;
                                 }
+                                _ => {}
                             }
-                            MouseEventKind::Up(MouseButton::Left) => {
-                                editor.is_dragging = false;
+                        }
+                        _ => {
+                            prompt.insert_char(ch);
+                            if matches!(prompt.prompt_type, PromptType::TaskList | PromptType::ClipboardHistory | PromptType::LineCommands | PromptType::Transforms) {
+                                prompt.selected_item = 0;
                             }
-                            MouseEventKind::ScrollUp => {
-                                editor.viewport_offset.0 = editor.viewport_offset.0.saturating_sub(3);
-                                editor.viewport_follows_caret = false;
+                            if matches!(prompt.prompt_type, PromptType::FindReplace) && prompt.active_field == FindReplaceField::Find {
+                                let query = prompt.input.clone();
+                                editor.update_find_matches(&query);
                             }
-                            MouseEventKind::ScrollDown => {
-                                let max = editor.visual_lines.len().saturating_sub(size.height as usize - 1);
-                                editor.viewport_offset.0 = (editor.viewport_offset.0 + 3).min(max);
-                                editor.viewport_follows_caret = false;
+                        }
+                    }
+                }
+                KeyCode::Backspace => {
+                    if key.modifiers.intersects(event::KeyModifiers::CONTROL | event::KeyModifiers::ALT) {
+                        prompt.delete_word_backward();
+                    } else {
+                        prompt.backspace();
+                    }
+                    if matches!(prompt.prompt_type, PromptType::FindReplace) && prompt.active_field == FindReplaceField::Find {
+                        let query = prompt.input.clone();
+                        editor.update_find_matches(&query);
+                    }
+                }
+                KeyCode::Delete => {
+                    if key.modifiers.intersects(event::KeyModifiers::CONTROL | event::KeyModifiers::ALT) {
+                        prompt.delete_word_forward();
+                    } else {
+                        prompt.delete();
+                    }
+                    if matches!(prompt.prompt_type, PromptType::FindReplace) && prompt.active_field == FindReplaceField::Find {
+                        let query = prompt.input.clone();
+                        editor.update_find_matches(&query);
+                    }
+                }
+                KeyCode::Left => {
+                    let extend = key.modifiers.contains(event::KeyModifiers::SHIFT);
+                    if key.modifiers.contains(event::KeyModifiers::CONTROL) {
+                        prompt.move_word_left(extend, key.modifiers.contains(event::KeyModifiers::ALT));
+                    } else {
+                        prompt.move_cursor_left(extend);
+                    }
+                }
+                KeyCode::Right => {
+                    let extend = key.modifiers.contains(event::KeyModifiers::SHIFT);
+                    if key.modifiers.contains(event::KeyModifiers::CONTROL) {
+                        prompt.move_word_right(extend, key.modifiers.contains(event::KeyModifiers::ALT));
+                    } else {
+                        prompt.move_cursor_right(extend);
+                    }
+                }
+                KeyCode::Up | KeyCode::Down if matches!(prompt.prompt_type, PromptType::TaskList | PromptType::UndoTree | PromptType::Macros | PromptType::ClipboardHistory | PromptType::LineCommands | PromptType::Transforms | PromptType::ReflowColumn) => {
+                    prompt.move_item_selection(key.code == KeyCode::Down);
+                    if matches!(prompt.prompt_type, PromptType::UndoTree) {
+                        // Preview the selected state in the buffer
+                        let target = prompt.visible_items()
+                            .get(prompt.selected_item)
+                            .map(|(state, _)| *state);
+                        if let Some(state) = target {
+                            editor.goto_undo_state(state);
+                            editor.update_viewport(viewport_height, viewport_width);
+                        }
+                    }
+                }
+                KeyCode::Home => {
+                    prompt.move_cursor_home(key.modifiers.contains(event::KeyModifiers::SHIFT));
+                }
+                KeyCode::End => {
+                    prompt.move_cursor_end(key.modifiers.contains(event::KeyModifiers::SHIFT));
+                }
+                _ => {}
+            }
+            }
+        }
+        AppState::Editing => {
+            match key.code {
+                KeyCode::Char('q') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    editor.confirm_quit_from(0);
+                }
+                KeyCode::Char('s') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    if key.modifiers.contains(event::KeyModifiers::SHIFT) || key.modifiers.contains(event::KeyModifiers::ALT) {
+                        // Save As (Ctrl+Shift+S or Ctrl+Alt+S)
+                        let path = editor.get_save_path_suggestion();
+                        editor.app_state = AppState::Prompting(Prompt::new_save_as(path));
+                    } else {
+                        // Save (Ctrl+S)
+                        if editor.filename.is_some() {
+                            if let Err(e) = editor.save() {
+                                eprintln!("Save failed: {:?}", e);
+                            } else {
+                                execute!(io::stdout(), SetTitle(&editor.get_display_name()))?;
                             }
-                            _ => {}
+                        } else {
+                            let path = editor.get_save_path_suggestion();
+                            editor.app_state = AppState::Prompting(Prompt::new_save_as(path));
                         }
                     }
-                    AppState::Exiting => {}
                 }
+                KeyCode::F(12) => {
+                    // Save As (F12) - Alternative to Ctrl+Shift+S
+                    let path = editor.get_save_path_suggestion();
+                    editor.app_state = AppState::Prompting(Prompt::new_save_as(path));
+                }
+                KeyCode::Char('a') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    editor.select_all();
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('c') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    editor.copy();
+                }
+                KeyCode::Char('x') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    if editor.cut() {
+                        editor.update_viewport(viewport_height, viewport_width);
+                    }
+                }
+                KeyCode::Char('v') if key.modifiers.contains(event::KeyModifiers::CONTROL)
+                    && (key.modifiers.contains(event::KeyModifiers::SHIFT) || key.modifiers.contains(event::KeyModifiers::ALT)) => {
+                    // Clipboard history (Ctrl+Shift+V or Ctrl+Alt+V)
+                    editor.app_state = AppState::Prompting(Prompt::new_clipboard_history(&editor.clipboard_history, editor.persist_clipboard_history));
+                }
+                KeyCode::Char('v') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    editor.paste(viewport_width);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('v') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.cycle_paste(viewport_width);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('w') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    editor.word_wrap = !editor.word_wrap;
+                    editor.invalidate_views();
+                }
+                KeyCode::Char('t') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    editor.use_hard_tabs = !editor.use_hard_tabs;
+                }
+                KeyCode::Char('t') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.tab_width = match editor.tab_width {
+                        2 => 4,
+                        4 => 8,
+                        _ => 2,
+                    };
+                    editor.invalidate_views();
+                }
+                KeyCode::Char('z') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    editor.undo();
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('y') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    editor.redo();
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('z') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.step_undo_state(false);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('y') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.step_undo_state(true);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('m') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.app_state = AppState::Prompting(Prompt::new_macros(macro_entries(&editor.macros)));
+                }
+                KeyCode::Char('u') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.finalize_undo_group();
+                    let entries = editor.undo_tree_entries();
+                    editor.app_state = AppState::Prompting(Prompt::new_undo_tree(entries, editor.undo_tree.current));
+                }
+                KeyCode::Char('f') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    editor.app_state = AppState::Prompting(Prompt::new_find_replace());
+                }
+                KeyCode::Char('d') if key.modifiers.contains(event::KeyModifiers::CONTROL)
+                    && (key.modifiers.contains(event::KeyModifiers::SHIFT) || key.modifiers.contains(event::KeyModifiers::ALT)) => {
+                    // Duplicate lines (Ctrl+Shift+D or Ctrl+Alt+D)
+                    editor.line_command_at_cursors(LineCommand::Duplicate, viewport_width);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('d') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    editor.add_next_occurrence(viewport_width);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('b') if key.modifiers.contains(event::KeyModifiers::CONTROL)
+                    && (key.modifiers.contains(event::KeyModifiers::SHIFT) || key.modifiers.contains(event::KeyModifiers::ALT)) => {
+                    // Select to matching bracket (Ctrl+Shift+B or Ctrl+Alt+B)
+                    editor.select_to_matching_bracket(viewport_width);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('b') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    editor.jump_to_matching_bracket(viewport_width);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('l') if key.modifiers.contains(event::KeyModifiers::CONTROL)
+                    && (key.modifiers.contains(event::KeyModifiers::SHIFT) || key.modifiers.contains(event::KeyModifiers::ALT)) => {
+                    // Select all occurrences (Ctrl+Shift+L or Ctrl+Alt+L)
+                    editor.select_all_occurrences(viewport_width);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('k') if key.modifiers.contains(event::KeyModifiers::CONTROL)
+                    && (key.modifiers.contains(event::KeyModifiers::SHIFT) || key.modifiers.contains(event::KeyModifiers::ALT)) => {
+                    // Task overview (Ctrl+Shift+K or Ctrl+Alt+K)
+                    let tasks = editor.open_tasks();
+                    editor.app_state = AppState::Prompting(Prompt::new_task_list(tasks));
+                }
+                KeyCode::Char('k') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    editor.cycle_task_checkboxes_at_cursors(viewport_width);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('/') | KeyCode::Char('7') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    // Most terminals send Ctrl+/ as Ctrl+7
+                    editor.toggle_comments_at_cursors(viewport_width, false);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('/') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.toggle_comments_at_cursors(viewport_width, true);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('i') | KeyCode::Char('I') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.split_selection_into_lines(viewport_width);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Esc => {
+                    editor.clear_extra_cursors();
+                }
+                KeyCode::Tab => {
+                    if key.modifiers.contains(event::KeyModifiers::SHIFT) {
+                        editor.indent_at_cursors(viewport_width, true);
+                    } else {
+                        editor.indent_at_cursors(viewport_width, false);
+                    }
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::BackTab => {
+                    editor.indent_at_cursors(viewport_width, true);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('p') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.auto_pair = !editor.auto_pair;
+                }
+                KeyCode::Char('d') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.line_command_at_cursors(LineCommand::Delete, viewport_width);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('j') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                    editor.line_command_at_cursors(LineCommand::Join, viewport_width);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('l') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.app_state = AppState::Prompting(Prompt::new_line_commands());
+                }
+                KeyCode::Char('x') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.app_state = AppState::Prompting(Prompt::new_transforms());
+                }
+                KeyCode::Char('F') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.toggle_all_folds(viewport_width);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('f') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.toggle_fold(viewport_width);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('h') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.show_whitespace = !editor.show_whitespace;
+                }
+                KeyCode::Char('W') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.app_state = AppState::Prompting(Prompt::new_reflow_column(editor.wrap_column));
+                }
+                KeyCode::Char('w') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.reflow(viewport_width);
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Char('\\') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.split_pane(Direction::Horizontal);
+                }
+                KeyCode::Char('-') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.split_pane(Direction::Vertical);
+                }
+                KeyCode::Char('0') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    if editor.closing_pane_loses_changes() {
+                        editor.app_state = AppState::Prompting(Prompt::new_confirm_close());
+                    } else {
+                        editor.close_pane();
+                    }
+                }
+                KeyCode::Char('.') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.resize_pane(5);
+                }
+                KeyCode::Char(',') if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                    editor.resize_pane(-5);
+                }
+                KeyCode::F(6) => {
+                    editor.focus_next_pane(!key.modifiers.contains(event::KeyModifiers::SHIFT));
+                }
+                KeyCode::Char(c) => {
+                    // Typing over or around selections is a step of its own
+                    editor.begin_undo_step(!editor.all_selection_ranges().is_empty());
+                    editor.for_each_cursor(|ed| ed.type_char(c, viewport_width));
+                    editor.end_undo_step();
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Enter => {
+                    editor.for_each_cursor(|ed| ed.insert_newline(viewport_width));
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Backspace => {
+                    if key.modifiers.intersects(event::KeyModifiers::CONTROL | event::KeyModifiers::ALT) {
+                        editor.for_each_cursor(|ed| ed.delete_word_backward(viewport_width));
+                    } else {
+                        editor.for_each_cursor(|ed| ed.backspace(viewport_width));
+                    }
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Delete => {
+                    if key.modifiers.intersects(event::KeyModifiers::CONTROL | event::KeyModifiers::ALT) {
+                        editor.for_each_cursor(|ed| ed.delete_word_forward(viewport_width));
+                    } else {
+                        editor.for_each_cursor(|ed| ed.delete(viewport_width));
+                    }
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Left => {
+                    let extend = key.modifiers.contains(event::KeyModifiers::SHIFT);
+                    if extend && key.modifiers.contains(event::KeyModifiers::ALT) && !key.modifiers.contains(event::KeyModifiers::CONTROL) {
+                        editor.extend_block_selection(viewport_width, 0, -1);
+                    } else if key.modifiers.contains(event::KeyModifiers::CONTROL) {
+                        editor.for_each_cursor(|ed| ed.move_word_left(viewport_width, extend, key.modifiers.contains(event::KeyModifiers::ALT)));
+                    } else {
+                        editor.for_each_cursor(|ed| ed.move_left(viewport_width, extend));
+                    }
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Right => {
+                    let extend = key.modifiers.contains(event::KeyModifiers::SHIFT);
+                    if extend && key.modifiers.contains(event::KeyModifiers::ALT) && !key.modifiers.contains(event::KeyModifiers::CONTROL) {
+                        editor.extend_block_selection(viewport_width, 0, 1);
+                    } else if key.modifiers.contains(event::KeyModifiers::CONTROL) {
+                        editor.for_each_cursor(|ed| ed.move_word_right(viewport_width, extend, key.modifiers.contains(event::KeyModifiers::ALT)));
+                    } else {
+                        editor.for_each_cursor(|ed| ed.move_right(viewport_width, extend));
+                    }
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::Up => {
+                    if key.modifiers.contains(event::KeyModifiers::CONTROL) && key.modifiers.contains(event::KeyModifiers::ALT) {
+                        editor.add_cursor_vertical(viewport_width, false);
+                        editor.update_viewport(viewport_height, viewport_width);
+                    } else if key.modifiers.contains(event::KeyModifiers::ALT) && key.modifiers.contains(event::KeyModifiers::SHIFT) {
+                        editor.extend_block_selection(viewport_width, -1, 0);
+                        editor.update_viewport(viewport_height, viewport_width);
+                    } else if key.modifiers.contains(event::KeyModifiers::CONTROL) && key.modifiers.contains(event::KeyModifiers::SHIFT) {
+                        editor.move_lines_up(viewport_width);
+                        editor.update_viewport(viewport_height, viewport_width);
+                    } else {
+                        editor.for_each_cursor(|ed| ed.move_up(viewport_width, key.modifiers.contains(event::KeyModifiers::SHIFT)));
+                        editor.update_viewport(viewport_height, viewport_width);
+                    }
+                }
+                KeyCode::Down => {
+                    if key.modifiers.contains(event::KeyModifiers::CONTROL) && key.modifiers.contains(event::KeyModifiers::ALT) {
+                        editor.add_cursor_vertical(viewport_width, true);
+                        editor.update_viewport(viewport_height, viewport_width);
+                    } else if key.modifiers.contains(event::KeyModifiers::ALT) && key.modifiers.contains(event::KeyModifiers::SHIFT) {
+                        editor.extend_block_selection(viewport_width, 1, 0);
+                        editor.update_viewport(viewport_height, viewport_width);
+                    } else if key.modifiers.contains(event::KeyModifiers::CONTROL) && key.modifiers.contains(event::KeyModifiers::SHIFT) {
+                        editor.move_lines_down(viewport_width);
+                        editor.update_viewport(viewport_height, viewport_width);
+                    } else {
+                        editor.for_each_cursor(|ed| ed.move_down(viewport_width, key.modifiers.contains(event::KeyModifiers::SHIFT)));
+                        editor.update_viewport(viewport_height, viewport_width);
+                    }
+                }
+                KeyCode::PageUp => {
+                    editor.page_up(viewport_width, viewport_height, key.modifiers.contains(event::KeyModifiers::SHIFT));
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                KeyCode::PageDown => {
+                    editor.page_down(viewport_width, viewport_height, key.modifiers.contains(event::KeyModifiers::SHIFT));
+                    editor.update_viewport(viewport_height, viewport_width);
+                }
+                _ => {}
             }
-            Event::Resize(_, _) => {
-                let size = terminal.size().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-                editor.invalidate_visual_lines();
-                editor.logical_line_map.clear();
-                editor.update_viewport(size.height as usize - 1, size.width as usize);
+        }
+        AppState::Exiting => {}
+    }
+
+    execute!(io::stdout(), SetTitle(&editor.get_display_name()))?;
+    Ok(())
+}
+
+/// Inserts text pasted through the terminal in one go: into the focused
+/// prompt field, or into the buffer as a single edit.
+fn handle_paste(editor: &mut Editor, text: &str, viewport_width: usize, viewport_height: usize) {
+    // Terminals send line breaks in pastes as carriage returns
+    let text = text.replace("\r\n", "\n").replace('\r', "\n");
+    if editor.recording.is_some() {
+        // Replaying the text as typed keys would auto-indent and auto-pair
+        // it, so the paste is left out of the macro and flagged instead
+        editor.recording_missed_paste = true;
+    }
+    match &mut editor.app_state {
+        AppState::Prompting(prompt) if !(matches!(prompt.prompt_type, PromptType::FindReplace) && prompt.active_field == FindReplaceField::Buffer) => {
+            prompt.insert_str(&text);
+            if matches!(prompt.prompt_type, PromptType::TaskList | PromptType::ClipboardHistory | PromptType::LineCommands | PromptType::Transforms) {
+                prompt.selected_item = 0;
+            }
+            if matches!(prompt.prompt_type, PromptType::FindReplace) && prompt.active_field == FindReplaceField::Find {
+                let query = prompt.input.clone();
+                editor.update_find_matches(&query);
+            }
+        }
+        AppState::Exiting => {}
+        _ => {
+            editor.begin_undo_step(true);
+            editor.paste_text(&text, viewport_width);
+            editor.end_undo_step();
+            editor.refresh_find_matches_if_active();
+            editor.update_viewport(viewport_height, viewport_width);
+        }
+    }
+}
+
+/// Replays macro keys `repeat` times, or until a find fails when `repeat`
+/// is `None`, as a single undo step. A failed find also ends a counted
+/// replay early.
+fn replay_macro(editor: &mut Editor, keys: &[event::KeyEvent], repeat: Option<usize>, viewport_width: usize, viewport_height: usize) -> io::Result<()> {
+    editor.replaying = true;
+    editor.begin_undo_step(true);
+    let mut result = Ok(());
+    'replay: for _ in 0..repeat.unwrap_or(MACRO_MAX_REPEAT) {
+        editor.find_failed = false;
+        for key in keys {
+            result = handle_key(editor, *key, viewport_width, viewport_height);
+            if result.is_err() || editor.find_failed || matches!(editor.app_state, AppState::Exiting) {
+                break 'replay;
             }
-            _ => {}
         }
     }
+    editor.end_undo_step();
+    editor.replaying = false;
+    result
 }
 
 fn handle_editor_key(editor: &mut Editor, key: event::KeyEvent, viewport_width: usize, viewport_height: usize) -> io::Result<()> {
@@ -2862,40 +7955,59 @@ fn handle_editor_key(editor: &mut Editor, key: event::KeyEvent, viewport_width:
             editor.update_viewport(viewport_height, viewport_width);
         }
         KeyCode::Char(c) => {
-            editor.insert_char(c, viewport_width);
+            editor.begin_undo_step(!editor.all_selection_ranges().is_empty());
+            editor.for_each_cursor(|ed| ed.type_char(c, viewport_width));
+            editor.end_undo_step();
             editor.refresh_find_matches_if_active();
             editor.update_viewport(viewport_height, viewport_width);
         }
         KeyCode::Enter => {
-            editor.insert_char('\n', viewport_width);
-            editor.preferred_col = 0;
+            editor.for_each_cursor(|ed| ed.insert_newline(viewport_width));
             editor.refresh_find_matches_if_active();
             editor.update_viewport(viewport_height, viewport_width);
         }
         KeyCode::Backspace => {
-            editor.backspace(viewport_width);
+            if key.modifiers.intersects(event::KeyModifiers::CONTROL | event::KeyModifiers::ALT) {
+                editor.for_each_cursor(|ed| ed.delete_word_backward(viewport_width));
+            } else {
+                editor.for_each_cursor(|ed| ed.backspace(viewport_width));
+            }
             editor.refresh_find_matches_if_active();
             editor.update_viewport(viewport_height, viewport_width);
         }
         KeyCode::Delete => {
-            editor.delete(viewport_width);
+            if key.modifiers.intersects(event::KeyModifiers::CONTROL | event::KeyModifiers::ALT) {
+                editor.for_each_cursor(|ed| ed.delete_word_forward(viewport_width));
+            } else {
+                editor.for_each_cursor(|ed| ed.delete(viewport_width));
+            }
             editor.refresh_find_matches_if_active();
             editor.update_viewport(viewport_height, viewport_width);
         }
         KeyCode::Left => {
-            editor.move_left(viewport_width, key.modifiers.contains(event::KeyModifiers::SHIFT));
+            let extend = key.modifiers.contains(event::KeyModifiers::SHIFT);
+            if key.modifiers.contains(event::KeyModifiers::CONTROL) {
+                editor.for_each_cursor(|ed| ed.move_word_left(viewport_width, extend, key.modifiers.contains(event::KeyModifiers::ALT)));
+            } else {
+                editor.for_each_cursor(|ed| ed.move_left(viewport_width, extend));
+            }
             editor.update_viewport(viewport_height, viewport_width);
         }
         KeyCode::Right => {
-            editor.move_right(viewport_width, key.modifiers.contains(event::KeyModifiers::SHIFT));
+            let extend = key.modifiers.contains(event::KeyModifiers::SHIFT);
+            if key.modifiers.contains(event::KeyModifiers::CONTROL) {
+                editor.for_each_cursor(|ed| ed.move_word_right(viewport_width, extend, key.modifiers.contains(event::KeyModifiers::ALT)));
+            } else {
+                editor.for_each_cursor(|ed| ed.move_right(viewport_width, extend));
+            }
             editor.update_viewport(viewport_height, viewport_width);
         }
         KeyCode::Up => {
-            editor.move_up(viewport_width, key.modifiers.contains(event::KeyModifiers::SHIFT));
+            editor.for_each_cursor(|ed| ed.move_up(viewport_width, key.modifiers.contains(event::KeyModifiers::SHIFT)));
             editor.update_viewport(viewport_height, viewport_width);
         }
         KeyCode::Down => {
-            editor.move_down(viewport_width, key.modifiers.contains(event::KeyModifiers::SHIFT));
+            editor.for_each_cursor(|ed| ed.move_down(viewport_width, key.modifiers.contains(event::KeyModifiers::SHIFT)));
             editor.update_viewport(viewport_height, viewport_width);
         }
         KeyCode::PageUp => {
@@ -2915,60 +8027,82 @@ fn handle_editor_key(editor: &mut Editor, key: event::KeyEvent, viewport_width:
 fn draw_ui(f: &mut Frame, editor: &mut Editor) {
     #[cfg(not(target_os = "windows"))]
     {
-        let viewport_height = f.area().height as usize - 1;
-        let viewport_width = f.area().width as usize;
+        let area = focused_viewport(editor, f.area().width, f.area().height);
+        let viewport_height = area.height as usize;
+        let viewport_width = area.width as usize;
         editor.ensure_visual_lines(viewport_width);
         editor.update_viewport(viewport_height, viewport_width);
     }
     draw_ui_with_cursor(f, editor, true);
 }
 
-fn draw_ui_with_cursor(f: &mut Frame, editor: &mut Editor, show_cursor: bool) {
-    let chunks = Layout::default()
-        .direction(Direction::Vertical)
-        .constraints([
-            Constraint::Min(0),
-            Constraint::Length(1),
-        ])
-        .split(f.area());
-    
-    let viewport_height = chunks[0].height as usize;
-    let viewport_width = chunks[0].width as usize;
-    
-    // Windows-specific: Check if viewport has changed or modal was just dismissed for more aggressive clearing
-    #[cfg(target_os = "windows")]
-    let viewport_changed = editor.viewport_offset != editor.previous_viewport_offset;
-    
-    #[cfg(target_os = "windows")]
-    let modal_dismissed = editor.modal_just_dismissed;
-    
-    // Viewport updating is now done before draw_ui is called
-    editor.ensure_visual_lines(viewport_width);
-    
-    #[cfg(target_os = "windows")]
-    {
-        // Only clear if viewport changed AND we're not showing a modal
-        // OR if a modal was just dismissed
-        let should_clear = modal_dismissed || (viewport_changed && matches!(editor.app_state, AppState::Editing));
-        
-        if should_clear {
-            // Clear each line in the editor area directly
-            for y in 0..viewport_height {
-                let _ = execute!(
-                    io::stdout(),
-                    MoveTo(chunks[0].x, chunks[0].y + y as u16),
-                    ClearType(CrosstermClearType::UntilNewLine)
-                );
-            }
-        }
-        editor.previous_viewport_offset = editor.viewport_offset;
-        editor.modal_just_dismissed = false;
+/// Splits the screen into the text area the panes share, the
+/// find/replace bar while it is open, and the status bar.
+fn screen_areas(editor: &Editor, area: Rect) -> (Rect, Option<Rect>, Rect) {
+    let find_bar = matches!(
+        &editor.app_state,
+        AppState::Prompting(prompt) if matches!(prompt.prompt_type, PromptType::FindReplace)
+    );
+    if find_bar {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Min(0),
+                Constraint::Length(3),
+                Constraint::Length(1),
+            ])
+            .split(area);
+        (chunks[0], Some(chunks[1]), chunks[2])
+    } else {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Min(0),
+                Constraint::Length(1),
+            ])
+            .split(area);
+        (chunks[0], None, chunks[1])
+    }
+}
+
+/// Splits a pane's area into its text and the scrollbar on its right
+/// edge. Panes too narrow for both get no scrollbar.
+fn split_scrollbar(area: Rect) -> (Rect, Rect) {
+    if area.width < 2 {
+        return (area, Rect::new(area.x + area.width, area.y, 0, area.height));
     }
+    (
+        Rect::new(area.x, area.y, area.width - 1, area.height),
+        Rect::new(area.x + area.width - 1, area.y, 1, area.height),
+    )
+}
+
+/// The text area of the focused pane on a terminal of the given size.
+fn focused_viewport(editor: &Editor, width: u16, height: u16) -> Rect {
+    let (text_area, _, _) = screen_areas(editor, Rect::new(0, 0, width, height));
+    editor.focused_pane_area(text_area)
+}
+
+/// Renders the text of the view in the editor's fields into `area`.
+fn draw_pane(f: &mut Frame, editor: &mut Editor, area: Rect) {
+    let viewport_height = area.height as usize;
+    let viewport_width = area.width as usize;
+    editor.ensure_visual_lines(viewport_width);
     
-    let selection_range = editor.get_selection_range();
+    let selection_ranges = editor.all_selection_ranges();
+    let secondary_carets: Vec<usize> = editor.extra_cursors.iter().map(|c| c.caret).collect();
+    let bracket_pair: Vec<usize> = editor.bracket_at_caret()
+        .and_then(|mark| mark.partner.map(|partner| vec![mark.pos, partner]))
+        .unwrap_or_default();
+    // Marks come in text order, so these are sorted
+    let unbalanced_brackets: Vec<usize> = editor.bracket_marks()
+        .iter()
+        .filter(|mark| mark.partner.is_none() && mark.structural)
+        .map(|mark| mark.pos)
+        .collect();
     
     let mut lines = Vec::new();
-    let (caret_row, caret_col) = editor.get_visual_position(editor.caret, viewport_width);
+    let mut overflow_rows = Vec::new();
     
     let start = editor.viewport_offset.0;
     let end = (start + viewport_height).min(editor.visual_lines.len());
@@ -2978,53 +8112,111 @@ fn draw_ui_with_cursor(f: &mut Frame, editor: &mut Editor, show_cursor: bool) {
             if let Some(vline) = vline_opt {
                 let text = editor.rope.byte_slice(vline.start_byte..vline.end_byte).to_string();
                 
-                let (display_text, display_start_offset) = if editor.word_wrap || editor.viewport_offset.1 == 0 {
-                    (text, 0)
+                // Text beyond either edge in no-wrap mode gets a marker there
+                let (hidden_left, hidden_right) = if editor.word_wrap {
+                    (false, false)
+                } else {
+                    (
+                        editor.viewport_offset.1 > 0 && !text.is_empty(),
+                        editor.display_width_from(&text, 0) > editor.viewport_offset.1 + viewport_width,
+                    )
+                };
+                if hidden_left || hidden_right {
+                    overflow_rows.push((lines.len(), hidden_left, hidden_right));
+                }
+                
+                let mut leading_pad = 0;
+                let (display_text, display_start_offset, display_start_col) = if editor.word_wrap || editor.viewport_offset.1 == 0 {
+                    (text, 0, vline.indent)
                 } else {
                     let mut result = String::new();
                     let mut width = 0;
                     let mut byte_offset = 0;
-                    let mut display_start_offset = 0;
+                    let mut display_start_offset = text.len();
+                    let mut display_start_col = 0;
                     let mut found_start = false;
                     
-                    for ch in text.chars() {
-                        let ch_width = ch.to_string().width();
-                        width += ch_width;
+                    for g in text.graphemes(true) {
+                        let g_width = grapheme_display_width(g, width, editor.tab_width);
+                        let g_start = width;
+                        width += g_width;
+                        byte_offset += g.len();
                         
                         if width > editor.viewport_offset.1 {
                             if !found_start {
-                                display_start_offset = byte_offset;
                                 found_start = true;
+                                if g_start < editor.viewport_offset.1 {
+                                    // A tab or wide character straddles the left edge: only
+                                    // pad its visible part so the columns stay aligned
+                                    leading_pad = width - editor.viewport_offset.1;
+                                    display_start_offset = byte_offset;
+                                    display_start_col = width;
+                                    continue;
+                                }
+                                display_start_offset = byte_offset - g.len();
+                                display_start_col = g_start;
                             }
-                            result.push(ch);
+                            result.push_str(g);
                         }
-                        
-                        byte_offset += ch.len_utf8();
                     }
-                    (result, display_start_offset)
+                    (result, display_start_offset, display_start_col)
                 };
                 
                 let mut spans = vec![];
                 if vline.indent > 0 {
                     spans.push(Span::raw(" ".repeat(vline.indent)));
                 }
+                if leading_pad > 0 {
+                    spans.push(Span::raw(" ".repeat(leading_pad)));
+                }
                 
-                // Check for find matches in this line
+                // Styles are tracked per grapheme cluster
                 let mut char_styles = vec![Style::default(); display_text.len()];
                 
-                // Apply selection highlighting
-                if let Some((sel_start, sel_end)) = selection_range {
+                // Flag trailing whitespace, except where the caret is typing
+                // it, and invisible or look-alike characters
+                let (trailing_start, trailing_end) = editor.trailing_whitespace(vline.logical_line);
+                let typing_trailing = editor.caret >= trailing_start && editor.caret <= trailing_end;
+                {
+                    let mut byte_pos = display_start_offset;
+                    for (i, g) in display_text.graphemes(true).enumerate() {
+                        let global_pos = vline.start_byte + byte_pos;
+                        if invisible_char_symbol(g).is_some() || is_confusable_space(g) {
+                            char_styles[i] = Style::default().bg(Color::Magenta).fg(Color::White);
+                        } else if global_pos >= trailing_start && !typing_trailing {
+                            char_styles[i] = Style::default().bg(Color::Red);
+                        }
+                        byte_pos += g.len();
+                    }
+                }
+                
+                // Mark the bracket pair at the caret and brackets without a partner
+                if !bracket_pair.is_empty() || !unbalanced_brackets.is_empty() {
+                    let mut byte_pos = display_start_offset;
+                    for (i, g) in display_text.graphemes(true).enumerate() {
+                        let global_pos = vline.start_byte + byte_pos;
+                        if bracket_pair.contains(&global_pos) {
+                            char_styles[i] = Style::default().bg(Color::DarkGray).add_modifier(Modifier::BOLD);
+                        } else if unbalanced_brackets.binary_search(&global_pos).is_ok() {
+                            char_styles[i] = Style::default().fg(Color::Red).add_modifier(Modifier::UNDERLINED);
+                        }
+                        byte_pos += g.len();
+                    }
+                }
+                
+                // Apply selection highlighting for every cursor
+                for &(sel_start, sel_end) in &selection_ranges {
                     let line_start = vline.start_byte;
                     let line_end = vline.end_byte;
                     
                     if sel_end > line_start && sel_start < line_end {
                         let mut byte_pos = display_start_offset;
-                        for (i, ch) in display_text.chars().enumerate() {
+                        for (i, g) in display_text.graphemes(true).enumerate() {
                             let global_pos = line_start + byte_pos;
                             if global_pos >= sel_start && global_pos < sel_end {
                                 char_styles[i] = Style::default().bg(Color::Blue).fg(Color::White);
                             }
-                            byte_pos += ch.len_utf8();
+                            byte_pos += g.len();
                         }
                     }
                 }
@@ -3034,7 +8226,7 @@ fn draw_ui_with_cursor(f: &mut Frame, editor: &mut Editor, show_cursor: bool) {
                 for &(match_start, match_end) in &editor.find_matches {
                     if match_end > line_start && match_start < vline.end_byte {
                         let mut byte_pos = display_start_offset;
-                        for (i, ch) in display_text.chars().enumerate() {
+                        for (i, g) in display_text.graphemes(true).enumerate() {
                             let global_pos = line_start + byte_pos;
                             if global_pos >= match_start && global_pos < match_end {
                                 // Current match gets a different color
@@ -3048,14 +8240,63 @@ fn draw_ui_with_cursor(f: &mut Frame, editor: &mut Editor, show_cursor: bool) {
                                     char_styles[i] = Style::default().bg(Color::Green).fg(Color::Black);
                                 }
                             }
-                            byte_pos += ch.len_utf8();
+                            byte_pos += g.len();
+                        }
+                    }
+                }
+                
+                // Draw secondary carets as reversed cells
+                if !secondary_carets.is_empty() {
+                    let mut byte_pos = display_start_offset;
+                    for (i, g) in display_text.graphemes(true).enumerate() {
+                        if secondary_carets.contains(&(vline.start_byte + byte_pos)) {
+                            char_styles[i] = char_styles[i].add_modifier(Modifier::REVERSED);
+                        }
+                        byte_pos += g.len();
+                    }
+                }
+                
+                // Build spans with styles, expanding tabs to the next tab stop
+                let mut col = display_start_col;
+                for (i, g) in display_text.graphemes(true).enumerate() {
+                    let g_width = grapheme_display_width(g, col, editor.tab_width);
+                    let symbol = match g {
+                        "\t" if editor.show_whitespace => Some(format!("→{}", " ".repeat(g_width - 1))),
+                        "\t" => Some(" ".repeat(g_width)),
+                        " " if editor.show_whitespace => Some("·".to_string()),
+                        _ => invisible_char_symbol(g).map(str::to_string),
+                    };
+                    match symbol {
+                        Some(symbol) if char_styles[i].fg.is_none() => {
+                            spans.push(Span::styled(symbol, char_styles[i].fg(Color::DarkGray)));
                         }
+                        Some(symbol) => spans.push(Span::styled(symbol, char_styles[i])),
+                        None => spans.push(Span::styled(g.to_string(), char_styles[i])),
                     }
+                    col += g_width;
+                }
+                
+                // Mark line breaks at the end of the last row of each line
+                let ends_line = !matches!(
+                    editor.visual_lines.get(row + 1),
+                    Some(Some(next)) if next.is_continuation
+                );
+                if vline.folded_lines > 0 {
+                    spans.push(Span::styled(
+                        format!(" ⋯ {} lines", vline.folded_lines),
+                        Style::default().fg(Color::DarkGray).bg(Color::Black),
+                    ));
+                } else if editor.show_whitespace && ends_line && vline.logical_line + 1 < editor.rope.len_lines() {
+                    spans.push(Span::styled("↵", Style::default().fg(Color::DarkGray)));
                 }
                 
-                // Build spans with styles
-                for (i, ch) in display_text.chars().enumerate() {
-                    spans.push(Span::styled(ch.to_string(), char_styles[i]));
+                // A secondary caret at the end of a line sits past the last character
+                let wraps_into_next = matches!(
+                    editor.visual_lines.get(row + 1),
+                    Some(Some(next)) if next.is_continuation && next.start_byte == vline.end_byte
+                );
+                if !wraps_into_next && secondary_carets.contains(&vline.end_byte) {
+                    spans.push(Span::styled(" ", Style::default().add_modifier(Modifier::REVERSED)));
                 }
                 
                 // Windows-specific: Pad line to full width to ensure clearing
@@ -3097,43 +8338,245 @@ fn draw_ui_with_cursor(f: &mut Frame, editor: &mut Editor, show_cursor: bool) {
         }
     }
     
-    let paragraph = Paragraph::new(lines.clone());
+    let paragraph = Paragraph::new(lines);
+    
+    // Always clear before rendering on Windows
+    #[cfg(target_os = "windows")]
+    {
+        f.render_widget(Clear, area);
+    }
+    
+    f.render_widget(paragraph, area);
+    
+    let marker_style = Style::default().fg(Color::DarkGray);
+    for (row, hidden_left, hidden_right) in overflow_rows {
+        let y = area.y + row as u16;
+        let buffer = f.buffer_mut();
+        if hidden_left {
+            buffer[(area.x, y)].set_symbol("‹").set_style(marker_style);
+        }
+        if hidden_right && area.width > 1 {
+            let x = area.x + area.width - 1;
+            // The marker cannot sit in the second half of a wide character
+            if buffer[(x - 1, y)].symbol().width() > 1 {
+                buffer[(x - 1, y)].set_symbol(" ");
+            }
+            buffer[(x, y)].set_symbol("›").set_style(marker_style);
+        }
+    }
+}
+
+/// Draws the scrollbar of the view in the editor's fields into the
+/// one-column `area`. The thumb covers the rows in view; marks show where
+/// search matches, selections, unbalanced brackets and flagged characters
+/// are.
+fn draw_scrollbar(f: &mut Frame, editor: &mut Editor, area: Rect) {
+    if area.width == 0 || area.height == 0 {
+        return;
+    }
+    let height = area.height as usize;
+    let total = editor.visual_lines.len().max(1);
+    let bar_row = |row: usize| (row * height / total).min(height - 1);
+    let thumb_start = bar_row(editor.viewport_offset.0);
+    let thumb_end = (thumb_start + (height * height / total).clamp(1, height)).min(height);
+    
+    // Later marks win where several share a row
+    let mut marks: Vec<Option<Color>> = vec![None; height];
+    let unbalanced_brackets: Vec<usize> = editor.bracket_marks()
+        .iter()
+        .filter(|mark| mark.partner.is_none() && mark.structural)
+        .map(|mark| mark.pos)
+        .collect();
+    for pos in unbalanced_brackets {
+        marks[bar_row(editor.visual_row_at(pos))] = Some(Color::Red);
+    }
+    let flagged_chars = editor.flagged_chars().to_vec();
+    for pos in flagged_chars {
+        marks[bar_row(editor.visual_row_at(pos))] = Some(Color::Magenta);
+    }
+    for (start, end) in editor.all_selection_ranges() {
+        if start < end {
+            for mark in &mut marks[bar_row(editor.visual_row_at(start))..=bar_row(editor.visual_row_at(end))] {
+                *mark = Some(Color::Blue);
+            }
+        }
+    }
+    for (index, &(start, _)) in editor.find_matches.iter().enumerate() {
+        let color = if editor.current_match_index == Some(index) { Color::Yellow } else { Color::Green };
+        marks[bar_row(editor.visual_row_at(start))] = Some(color);
+    }
+    
+    let lines: Vec<Line> = marks
+        .iter()
+        .enumerate()
+        .map(|(row, mark)| {
+            let style = if row >= thumb_start && row < thumb_end {
+                Style::default().bg(Color::Gray)
+            } else {
+                Style::default()
+            };
+            match mark {
+                Some(color) => Line::from(Span::styled("━", style.fg(*color))),
+                None if style.bg.is_some() => Line::from(Span::styled(" ", style)),
+                None => Line::from(Span::styled("│", Style::default().fg(Color::DarkGray))),
+            }
+        })
+        .collect();
+    f.render_widget(Paragraph::new(lines), area);
+}
+
+/// Draws a list panel: the prompt message, the filter input and the
+/// visible items, scrolled to keep the selected one in view. `item_line`
+/// lays out one item in the style for its selection state.
+fn draw_list_panel<'a>(
+    f: &mut Frame,
+    prompt: &'a Prompt,
+    area: Rect,
+    title: &str,
+    empty_text: &str,
+    show_cursor: bool,
+    item_line: impl Fn(&'a (usize, String), Style) -> Line<'a>,
+) {
+    f.render_widget(Clear, area);
+    
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(title)
+        .style(Style::default().bg(Color::Black));
+    
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+    
+    let panel_area = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(1),
+            Constraint::Length(1),
+            Constraint::Min(1),
+        ])
+        .split(inner);
+    
+    f.render_widget(Paragraph::new(prompt.message.as_str()), panel_area[0]);
+    let input = Paragraph::new(prompt.input.as_str())
+        .style(Style::default().add_modifier(Modifier::UNDERLINED));
+    f.render_widget(input, panel_area[1]);
+    
+    let entries = prompt.visible_items();
+    let list_height = panel_area[2].height as usize;
+    let selected = prompt.selected_item.min(entries.len().saturating_sub(1));
+    let scroll = (selected + 1).saturating_sub(list_height);
+    let entry_lines: Vec<Line> = if entries.is_empty() {
+        vec![Line::from(Span::styled(empty_text.to_string(), Style::default().fg(Color::DarkGray)))]
+    } else {
+        entries
+            .into_iter()
+            .enumerate()
+            .skip(scroll)
+            .take(list_height)
+            .map(|(idx, entry)| {
+                let style = if idx == selected {
+                    Style::default().bg(Color::Blue).fg(Color::White)
+                } else {
+                    Style::default()
+                };
+                item_line(entry, style)
+            })
+            .collect()
+    };
+    f.render_widget(Paragraph::new(entry_lines), panel_area[2]);
+    
+    let cursor_x = panel_area[1].x + prompt.input[..prompt.cursor_pos].width().min(panel_area[1].width as usize - 1) as u16;
+    if show_cursor {
+        f.set_cursor_position((cursor_x, panel_area[1].y));
+    }
+}
+
+/// A list panel item showing just its label.
+fn list_item((_, label): &(usize, String), style: Style) -> Line<'_> {
+    Line::from(Span::styled(label.as_str(), style))
+}
+
+fn draw_ui_with_cursor(f: &mut Frame, editor: &mut Editor, show_cursor: bool) {
+    let (text_area, find_bar_area, status_area) = screen_areas(editor, f.area());
     
-    // Windows-specific: More aggressive clearing on viewport changes or modal dismissal
+    // Windows-specific: Check if viewport has changed or modal was just dismissed for more aggressive clearing
     #[cfg(target_os = "windows")]
     {
+        let viewport_changed = editor.viewport_offset != editor.previous_viewport_offset;
+        let modal_dismissed = editor.modal_just_dismissed;
+        
+        // Only clear if viewport changed AND we're not showing a modal
+        // OR if a modal was just dismissed
         let should_clear = modal_dismissed || (viewport_changed && matches!(editor.app_state, AppState::Editing));
+        
         if should_clear {
+            // Clear each line in the editor area directly
+            for y in 0..text_area.height {
+                let _ = execute!(
+                    io::stdout(),
+                    MoveTo(text_area.x, text_area.y + y),
+                    ClearType(CrosstermClearType::UntilNewLine)
+                );
+            }
+            
             // Method 1: Clear widget
-            f.render_widget(Clear, chunks[0]);
+            f.render_widget(Clear, text_area);
             
             // Method 2: Fill with spaces using a styled block
             let empty_block = Block::default()
                 .style(Style::default().bg(Color::Black))
                 .borders(Borders::NONE);
-            f.render_widget(empty_block, chunks[0]);
+            f.render_widget(empty_block, text_area);
             
             // Method 3: Render empty paragraph with explicit spaces
-            let mut empty_lines: Vec<Line> = Vec::with_capacity(viewport_height);
-            for _ in 0..viewport_height {
-                let mut spans = Vec::with_capacity(viewport_width);
-                for _ in 0..viewport_width {
+            let mut empty_lines: Vec<Line> = Vec::with_capacity(text_area.height as usize);
+            for _ in 0..text_area.height {
+                let mut spans = Vec::with_capacity(text_area.width as usize);
+                for _ in 0..text_area.width {
                     spans.push(Span::styled(" ", Style::default().bg(Color::Black)));
                 }
                 empty_lines.push(Line::from(spans));
             }
             let clear_paragraph = Paragraph::new(empty_lines);
-            f.render_widget(clear_paragraph, chunks[0]);
+            f.render_widget(clear_paragraph, text_area);
         }
+        editor.previous_viewport_offset = editor.viewport_offset;
+        editor.modal_just_dismissed = false;
     }
     
-    // Always clear before rendering on Windows
-    #[cfg(target_os = "windows")]
-    {
-        f.render_widget(Clear, chunks[0]);
+    // Draw the other panes as they would look with focus, minus the caret
+    let mut pane_areas = Vec::new();
+    let mut dividers = Vec::new();
+    editor.pane_layout.arrange(text_area, &mut pane_areas, &mut dividers);
+    for (direction, divider) in dividers {
+        let line = match direction {
+            Direction::Horizontal => vec![Line::from("│"); divider.height as usize],
+            Direction::Vertical => vec![Line::from("─".repeat(divider.width as usize))],
+        };
+        f.render_widget(Paragraph::new(line).style(Style::default().fg(Color::DarkGray)), divider);
+    }
+    let mut area = text_area;
+    for (pane, pane_area) in pane_areas {
+        let (pane_text_area, scrollbar_area) = split_scrollbar(pane_area);
+        if pane == editor.focused_pane {
+            area = pane_text_area;
+            draw_pane(f, editor, area);
+            draw_scrollbar(f, editor, scrollbar_area);
+        } else if pane_text_area.width > 0 && pane_text_area.height > 0 {
+            editor.with_pane(pane, |ed| {
+                ed.update_viewport(pane_text_area.height as usize, pane_text_area.width as usize);
+                draw_pane(f, ed, pane_text_area);
+                draw_scrollbar(f, ed, scrollbar_area);
+            });
+        }
     }
     
-    f.render_widget(paragraph, chunks[0]);
+    let viewport_height = area.height as usize;
+    let viewport_width = area.width as usize;
+    let selection_range = editor.get_selection_range();
+    let (caret_row, caret_col) = editor.get_visual_position(editor.caret, viewport_width);
+    let start = editor.viewport_offset.0;
+    let end = (start + viewport_height).min(editor.visual_lines.len());
     
     // Draw prompt if active
     if let AppState::Prompting(prompt) = &mut editor.app_state {
@@ -3172,11 +8615,11 @@ fn draw_ui_with_cursor(f: &mut Frame, editor: &mut Editor, show_cursor: bool) {
                 let mut display_width = 0;
                 
                 // Build the visible text with proper scrolling
-                for (idx, ch) in prompt.input.char_indices() {
-                    let ch_width = ch.to_string().width();
+                for (idx, g) in prompt.input.grapheme_indices(true) {
+                    let ch_width = g.width();
                     
                     if visual_pos >= prompt.save_as_scroll_offset && display_width < field_width {
-                        let ch_str = ch.to_string();
+                        let ch_str = g.to_string();
                         let style = if let Some((sel_start, sel_end)) = prompt.get_selection_range() {
                             if idx >= sel_start && idx < sel_end {
                                 Style::default().bg(Color::Blue).fg(Color::White)
@@ -3199,11 +8642,11 @@ fn draw_ui_with_cursor(f: &mut Frame, editor: &mut Editor, show_cursor: bool) {
                 
                 // Set cursor position in prompt
                 let mut visual_cursor_pos = 0;
-                for (idx, ch) in prompt.input.char_indices() {
+                for (idx, g) in prompt.input.grapheme_indices(true) {
                     if idx >= prompt.cursor_pos {
                         break;
                     }
-                    visual_cursor_pos += ch.to_string().width();
+                    visual_cursor_pos += g.width();
                 }
                 let screen_pos = visual_cursor_pos.saturating_sub(prompt.save_as_scroll_offset);
                 let cursor_x = input_area[1].x + screen_pos.min(input_area[1].width as usize - 1) as u16;
@@ -3211,7 +8654,7 @@ fn draw_ui_with_cursor(f: &mut Frame, editor: &mut Editor, show_cursor: bool) {
                     f.set_cursor_position((cursor_x, input_area[1].y));
                 }
             }
-            PromptType::ConfirmSave => {
+            PromptType::ConfirmSave | PromptType::ConfirmClose => {
                 let area = centered_rect(60, 20, f.area());
                 f.render_widget(Clear, area);
                 
@@ -3226,18 +8669,66 @@ fn draw_ui_with_cursor(f: &mut Frame, editor: &mut Editor, show_cursor: bool) {
                 let message = Paragraph::new(prompt.message.as_str());
                 f.render_widget(message, inner);
             }
-            PromptType::FindReplace => {
-                // Render find/replace as a bar at the bottom above the status bar
-                let find_replace_chunks = Layout::default()
+            PromptType::UndoTree => {
+                // Docked on the right so the previewed state stays visible
+                let panel_width = (f.area().width / 3).max(36).min(f.area().width);
+                let area = Rect::new(
+                    f.area().width - panel_width,
+                    0,
+                    panel_width,
+                    f.area().height.saturating_sub(1),
+                );
+                let origin_state = prompt.origin_state;
+                draw_list_panel(f, prompt, area, " Undo Tree (↑↓ preview, Enter keep, Esc cancel) ", "", show_cursor, |(state, label), style| {
+                    let style = if *state == origin_state { style.add_modifier(Modifier::BOLD) } else { style };
+                    Line::from(Span::styled(label.as_str(), style))
+                });
+            }
+            PromptType::ClipboardHistory => {
+                let area = centered_rect(60, 60, f.area());
+                let history_area = Layout::default()
                     .direction(Direction::Vertical)
-                    .constraints([
-                        Constraint::Min(0),
-                        Constraint::Length(3),
-                        Constraint::Length(1),
-                    ])
-                    .split(f.area());
+                    .constraints([Constraint::Percentage(60), Constraint::Percentage(40)])
+                    .split(area);
+                draw_list_panel(f, prompt, history_area[0], " Clipboard History ", "Nothing copied yet", show_cursor, list_item);
                 
-                let find_replace_area = find_replace_chunks[1];
+                // Show the selected entry as it will be pasted
+                let entries = prompt.visible_items();
+                let preview_text = entries
+                    .get(prompt.selected_item.min(entries.len().saturating_sub(1)))
+                    .and_then(|(index, _)| editor.clipboard_history.get(*index))
+                    .map_or(String::new(), |text| text.replace('\t', "    "));
+                let preview = Paragraph::new(preview_text)
+                    .block(Block::default().borders(Borders::ALL).title(" Preview "))
+                    .style(Style::default().fg(Color::Gray).bg(Color::Black));
+                f.render_widget(Clear, history_area[1]);
+                f.render_widget(preview, history_area[1]);
+            }
+            PromptType::Macros => {
+                let area = centered_rect(60, 60, f.area());
+                draw_list_panel(f, prompt, area, " Macros (Enter run, Tab edit, Esc close) ", "No macros, record one with Alt+Q", show_cursor, list_item);
+            }
+            PromptType::LineCommands | PromptType::Transforms | PromptType::ReflowColumn => {
+                let area = centered_rect(60, 60, f.area());
+                let title = match prompt.prompt_type {
+                    PromptType::Transforms => " Transform ",
+                    PromptType::ReflowColumn => " Reflow ",
+                    _ => " Line Commands ",
+                };
+                draw_list_panel(f, prompt, area, title, "No matching command", show_cursor, list_item);
+            }
+            PromptType::TaskList => {
+                let area = centered_rect(60, 60, f.area());
+                draw_list_panel(f, prompt, area, " Tasks ", "No open tasks", show_cursor, |(line_idx, text), style| {
+                    Line::from(vec![
+                        Span::styled(format!("{:>5} ", line_idx + 1), style.fg(Color::DarkGray)),
+                        Span::styled(text.as_str(), style),
+                    ])
+                });
+            }
+            PromptType::FindReplace => {
+                // Render find/replace as a bar at the bottom above the status bar
+                let find_replace_area = find_bar_area.unwrap_or(status_area);
                 f.render_widget(Clear, find_replace_area);
                 
                 let block_style = if prompt.active_field == FindReplaceField::Buffer {
@@ -3279,11 +8770,11 @@ fn draw_ui_with_cursor(f: &mut Frame, editor: &mut Editor, show_cursor: bool) {
                 let mut display_width = 0;
                 
                 // Build the visible text with proper scrolling
-                for (idx, ch) in prompt.input.char_indices() {
-                    let ch_width = ch.to_string().width();
+                for (idx, g) in prompt.input.grapheme_indices(true) {
+                    let ch_width = g.width();
                     
                     if visual_pos >= prompt.find_scroll_offset && display_width < field_width {
-                        let ch_str = ch.to_string();
+                        let ch_str = g.to_string();
                         let style = if prompt.active_field == FindReplaceField::Find {
                             if let Some((sel_start, sel_end)) = prompt.get_selection_range() {
                                 if idx >= sel_start && idx < sel_end {
@@ -3324,11 +8815,11 @@ fn draw_ui_with_cursor(f: &mut Frame, editor: &mut Editor, show_cursor: bool) {
                 let replace_field_width = fields[3].width as usize;
                 
                 // Build the visible text with proper scrolling
-                for (idx, ch) in prompt.replace_input.char_indices() {
-                    let ch_width = ch.to_string().width();
+                for (idx, g) in prompt.replace_input.grapheme_indices(true) {
+                    let ch_width = g.width();
                     
                     if visual_pos >= prompt.replace_scroll_offset && display_width < replace_field_width {
-                        let ch_str = ch.to_string();
+                        let ch_str = g.to_string();
                         let style = if prompt.active_field == FindReplaceField::Replace {
                             if let Some((sel_start, sel_end)) = prompt.get_selection_range() {
                                 if idx >= sel_start && idx < sel_end {
@@ -3363,22 +8854,22 @@ fn draw_ui_with_cursor(f: &mut Frame, editor: &mut Editor, show_cursor: bool) {
                     let cursor_field = match prompt.active_field {
                         FindReplaceField::Find => {
                             let mut visual_cursor_pos = 0;
-                            for (idx, ch) in prompt.input.char_indices() {
+                            for (idx, g) in prompt.input.grapheme_indices(true) {
                                 if idx >= prompt.cursor_pos {
                                     break;
                                 }
-                                visual_cursor_pos += ch.to_string().width();
+                                visual_cursor_pos += g.width();
                             }
                             let screen_pos = visual_cursor_pos.saturating_sub(prompt.find_scroll_offset);
                             (fields[1].x + screen_pos.min(fields[1].width as usize - 1) as u16, fields[1].y)
                         }
                         FindReplaceField::Replace => {
                             let mut visual_cursor_pos = 0;
-                            for (idx, ch) in prompt.replace_input.char_indices() {
+                            for (idx, g) in prompt.replace_input.grapheme_indices(true) {
                                 if idx >= prompt.replace_cursor_pos {
                                     break;
                                 }
-                                visual_cursor_pos += ch.to_string().width();
+                                visual_cursor_pos += g.width();
                             }
                             let screen_pos = visual_cursor_pos.saturating_sub(prompt.replace_scroll_offset);
                             (fields[3].x + screen_pos.min(fields[3].width as usize - 1) as u16, fields[3].y)
@@ -3402,19 +8893,14 @@ fn draw_ui_with_cursor(f: &mut Frame, editor: &mut Editor, show_cursor: bool) {
                         if screen_col < viewport_width {
                             if show_cursor {
                                 f.set_cursor_position((
-                                    find_replace_chunks[0].x + screen_col as u16,
-                                    find_replace_chunks[0].y + screen_row as u16,
+                                    area.x + screen_col as u16,
+                                    area.y + screen_row as u16,
                                 ));
                             }
                         }
                     }
                 }
                 
-                // Still render the main editor area above the find/replace bar
-                let editor_paragraph = Paragraph::new(lines.clone());
-                f.render_widget(Clear, find_replace_chunks[0]);
-                f.render_widget(editor_paragraph, find_replace_chunks[0]);
-                
                 // Render status bar below find/replace
                 let (line, col) = editor.get_position();
                 let selection_info = if editor.has_selection() {
@@ -3436,13 +8922,17 @@ fn draw_ui_with_cursor(f: &mut Frame, editor: &mut Editor, show_cursor: bool) {
                     format!("{} matches", editor.find_matches.len())
                 };
                 let status_text_fr = format!(
-                    " {} | {} | {}/{}:{}{} | {} ",
+                    " {} | {} | {} | {}/{}:{}{}{}{}{} | {} ",
                     editor.get_display_name(),
                     if editor.word_wrap { "Wrap" } else { "No-Wrap" },
+                    editor.get_tab_display(),
                     line,
                     total_lines,
                     col,
                     selection_info,
+                    editor.get_cursor_info(),
+                    editor.get_task_info(),
+                    editor.get_macro_info(),
                     match_info
                 );
                 
@@ -3450,7 +8940,7 @@ fn draw_ui_with_cursor(f: &mut Frame, editor: &mut Editor, show_cursor: bool) {
                     .style(Style::default().bg(Color::DarkGray).fg(Color::White))
                     .alignment(Alignment::Left);
                 
-                f.render_widget(status_fr, find_replace_chunks[2]);
+                f.render_widget(status_fr, status_area);
                 
                 // Early return to avoid rendering the normal editor UI
                 return;
@@ -3469,8 +8959,8 @@ fn draw_ui_with_cursor(f: &mut Frame, editor: &mut Editor, show_cursor: bool) {
             if screen_col < viewport_width {
                 if show_cursor {
                     f.set_cursor_position((
-                        chunks[0].x + screen_col as u16,
-                        chunks[0].y + screen_row as u16,
+                        area.x + screen_col as u16,
+                        area.y + screen_row as u16,
                     ));
                 }
             }
@@ -3498,20 +8988,24 @@ fn draw_ui_with_cursor(f: &mut Frame, editor: &mut Editor, show_cursor: bool) {
     
     let total_lines = editor.rope.len_lines();
     let status_text = format!(
-        " {} | {} | {}/{}:{}{} ",
+        " {} | {} | {} | {}/{}:{}{}{}{}{} ",
         editor.get_display_name(),
         if editor.word_wrap { "Wrap" } else { "No-Wrap" },
+        editor.get_tab_display(),
         line,
         total_lines,
         col,
-        selection_info
+        selection_info,
+        editor.get_cursor_info(),
+        editor.get_task_info(),
+        editor.get_macro_info()
     );
     
     let status = Paragraph::new(Line::from(vec![Span::raw(status_text)]))
         .style(Style::default().bg(Color::DarkGray).fg(Color::White))
         .alignment(Alignment::Left);
     
-    f.render_widget(status, chunks[1]);
+    f.render_widget(status, status_area);
 }
 
 fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
@@ -3532,4 +9026,1012 @@ fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
             Constraint::Percentage((100 - percent_x) / 2),
         ])
         .split(popup_layout[1])[1]
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree_with_steps(count: usize) -> UndoTree {
+        let mut tree = UndoTree::new();
+        for i in 0..count {
+            tree.push(UndoGroup {
+                ops: vec![(EditOp::Insert { pos: i, text: "x".repeat(50) }, i, i + 1)],
+                timestamp: UNIX_EPOCH + Duration::from_millis(i as u64),
+            });
+        }
+        tree
+    }
+
+    fn editor_with(text: &str) -> Editor {
+        let mut editor = Editor::new();
+        editor.rope = Rope::from_str(text);
+        editor.invalidate_visual_lines();
+        editor
+    }
+
+    #[test]
+    fn line_comments_toggle_at_the_smallest_indentation() {
+        let mut editor = editor_with("fn f() {\n    a();\n\n\tb();\n}");
+        editor.filename = Some(PathBuf::from("a.rs"));
+        editor.tab_width = 4;
+        editor.selection_anchor = Some(9);
+        editor.caret = editor.rope.line_to_byte(4);
+        editor.toggle_comments_at_cursors(80, false);
+        assert_eq!(editor.rope.to_string(), "fn f() {\n    // a();\n\n\t// b();\n}");
+        editor.toggle_comments_at_cursors(80, false);
+        assert_eq!(editor.rope.to_string(), "fn f() {\n    a();\n\n\tb();\n}");
+        
+        // Narrower indentation puts the tokens before a tab
+        let mut editor = editor_with("  a\n\tb");
+        editor.filename = Some(PathBuf::from("a.py"));
+        editor.tab_width = 4;
+        editor.selection_anchor = Some(0);
+        editor.caret = 6;
+        editor.toggle_comments_at_cursors(80, false);
+        assert_eq!(editor.rope.to_string(), "  # a\n# \tb");
+    }
+
+    #[test]
+    fn commenting_keeps_a_column_zero_selection_over_the_tokens() {
+        let mut editor = editor_with("a\nb\nc");
+        editor.filename = Some(PathBuf::from("a.c"));
+        editor.selection_anchor = Some(0);
+        editor.caret = 4;
+        editor.toggle_comments_at_cursors(80, false);
+        assert_eq!(editor.rope.to_string(), "// a\n// b\nc");
+        assert_eq!((editor.selection_anchor, editor.caret), (Some(0), 10));
+        
+        // Selected backwards
+        editor.toggle_comments_at_cursors(80, false);
+        editor.selection_anchor = Some(4);
+        editor.caret = 0;
+        editor.toggle_comments_at_cursors(80, false);
+        assert_eq!((editor.selection_anchor, editor.caret), (Some(10), 0));
+        
+        // Block comments line up at the smallest indentation too
+        let mut editor = editor_with("\ta\n  b");
+        editor.filename = Some(PathBuf::from("a.c"));
+        editor.tab_width = 4;
+        editor.selection_anchor = Some(0);
+        editor.caret = 6;
+        editor.toggle_comments_at_cursors(80, true);
+        assert_eq!(editor.rope.to_string(), "/* \ta\n  b */");
+    }
+
+    #[test]
+    fn visual_rows_are_found_past_the_virtual_lines() {
+        let mut editor = editor_with("one\ntwo two two\nthree");
+        editor.ensure_visual_lines(6);
+        let first = editor.virtual_lines;
+        assert_eq!(editor.visual_row_at(0), first);
+        assert_eq!(editor.visual_row_at(3), first);
+        assert_eq!(editor.visual_row_at(4), first + 1);
+        // "two two two" wraps after each word
+        assert_eq!(editor.visual_row_at(12), first + 3);
+        assert_eq!(editor.visual_row_at(editor.rope.len_bytes()), first + 4);
+        
+        editor.virtual_lines = 0;
+        editor.invalidate_visual_lines();
+        editor.ensure_visual_lines(80);
+        assert_eq!(editor.visual_row_at(0), 0);
+        assert_eq!(editor.visual_row_at(editor.rope.len_bytes()), 2);
+    }
+
+    #[test]
+    fn scrollbar_takes_the_last_column() {
+        assert_eq!(split_scrollbar(Rect::new(2, 1, 10, 5)), (Rect::new(2, 1, 9, 5), Rect::new(11, 1, 1, 5)));
+        assert_eq!(split_scrollbar(Rect::new(2, 1, 1, 5)), (Rect::new(2, 1, 1, 5), Rect::new(3, 1, 0, 5)));
+    }
+
+    #[test]
+    fn scrollbar_maps_the_viewport_and_marks_onto_its_rows() {
+        let text = (0..100).map(|idx| format!("line {}", idx)).collect::<Vec<_>>().join("\n");
+        let mut editor = editor_with(&text);
+        editor.virtual_lines = 0;
+        editor.ensure_visual_lines(80);
+        editor.viewport_offset.0 = 50;
+        editor.find_matches = vec![(editor.rope.line_to_byte(90), editor.rope.line_to_byte(90) + 4)];
+        editor.current_match_index = Some(0);
+        editor.selection_anchor = Some(editor.rope.line_to_byte(10));
+        editor.caret = editor.rope.line_to_byte(25);
+        
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(1, 10)).unwrap();
+        terminal.draw(|f| draw_scrollbar(f, &mut editor, f.area())).unwrap();
+        let buffer = terminal.backend().buffer();
+        let rows: String = (0..10).map(|y| buffer[(0, y)].symbol().to_string()).collect();
+        assert_eq!(rows, "│━━││ │││━");
+        assert_eq!(buffer[(0, 5)].bg, Color::Gray);
+        assert_eq!(buffer[(0, 1)].fg, Color::Blue);
+        assert_eq!(buffer[(0, 9)].fg, Color::Yellow);
+    }
+
+    #[test]
+    fn shift_wheel_scrolls_sideways_only_without_wrapping() {
+        let text = format!("{}\n", "x".repeat(100)).repeat(50);
+        let mut editor = editor_with(&text);
+        editor.word_wrap = false;
+        editor.scroll_wheel(MouseEventKind::ScrollDown, true, 20, 10);
+        assert_eq!(editor.viewport_offset, (0, HORIZONTAL_SCROLL_STEP));
+        editor.scroll_wheel(MouseEventKind::ScrollUp, true, 20, 10);
+        assert_eq!(editor.viewport_offset, (0, 0));
+        
+        editor.word_wrap = true;
+        editor.invalidate_visual_lines();
+        editor.scroll_wheel(MouseEventKind::ScrollDown, true, 20, 10);
+        assert_eq!(editor.viewport_offset, (3, 0));
+        // A horizontal wheel has nothing to do
+        editor.scroll_wheel(MouseEventKind::ScrollRight, false, 20, 10);
+        assert_eq!(editor.viewport_offset, (3, 0));
+    }
+
+    #[test]
+    fn no_wrap_view_fits_the_whole_character_at_the_caret() {
+        let mut editor = editor_with("abcdefgh\tx\nabcdefgh中x");
+        editor.word_wrap = false;
+        editor.scrolloff = 0;
+        editor.tab_width = 4;
+        // The tab at column 8 reaches column 12
+        editor.caret = 8;
+        editor.update_viewport(5, 10);
+        assert_eq!(editor.viewport_offset.1, 2);
+        // A wide character takes two columns
+        editor.viewport_offset.1 = 0;
+        editor.caret = editor.rope.line_to_byte(1) + 8;
+        editor.update_viewport(5, 9);
+        assert_eq!(editor.viewport_offset.1, 1);
+        editor.caret = editor.rope.line_to_byte(1) + 7;
+        editor.viewport_offset.1 = 0;
+        editor.update_viewport(5, 9);
+        assert_eq!(editor.viewport_offset.1, 0);
+    }
+
+    #[test]
+    fn no_wrap_view_marks_text_beyond_its_edges() {
+        let mut editor = editor_with("short\n0123456789abcdef\n\nxy");
+        editor.word_wrap = false;
+        editor.virtual_lines = 0;
+        editor.viewport_follows_caret = false;
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(8, 4)).unwrap();
+        let mut rows = |editor: &mut Editor| {
+            terminal.draw(|f| draw_pane(f, editor, f.area())).unwrap();
+            let buffer = terminal.backend().buffer().clone();
+            (0..4).map(|y| (0..8).map(|x| buffer[(x, y)].symbol().to_string()).collect::<String>()).collect::<Vec<_>>()
+        };
+        assert_eq!(rows(&mut editor), ["short   ", "0123456›", "        ", "xy      "]);
+        // The marker takes the first column even over text
+        editor.viewport_offset.1 = 4;
+        assert_eq!(rows(&mut editor), ["‹       ", "‹56789a›", "        ", "‹       "]);
+        editor.viewport_offset.1 = 8;
+        assert_eq!(rows(&mut editor), ["‹       ", "‹9abcdef", "        ", "‹       "]);
+    }
+
+    #[test]
+    fn checkboxes_cycle_on_task_and_list_lines_only() {
+        let mut editor = editor_with("Intro\n- item\n▢ open\n  - [x] done\n\n1. first\nprose");
+        editor.selection_anchor = Some(0);
+        editor.caret = editor.rope.len_bytes();
+        editor.cycle_task_checkboxes(80);
+        assert_eq!(editor.rope.to_string(), "Intro\n- [ ] item\n◪ open\n  - [ ] done\n\n▢ 1. first\nprose");
+        editor.cycle_task_checkboxes(80);
+        assert_eq!(editor.rope.to_string(), "Intro\n- [x] item\n■ open\n  - [x] done\n\n◪ 1. first\nprose");
+        
+        // The caret line alone gets a checkbox whatever it holds
+        let mut editor = editor_with("  prose");
+        editor.caret = 3;
+        editor.cycle_task_checkboxes(80);
+        assert_eq!((editor.rope.to_string().as_str(), editor.caret), ("  ▢ prose", 7));
+    }
+
+    #[test]
+    fn task_checkboxes_are_found_after_the_indentation() {
+        assert_eq!(task_checkbox("  ▢ open"), Some((2, 5, TaskState::Open)));
+        assert_eq!(task_checkbox("■"), Some((0, 3, TaskState::Done)));
+        assert_eq!(task_checkbox("* [X] done"), Some((2, 5, TaskState::Done)));
+        assert_eq!(task_checkbox("- [ ]"), Some((2, 5, TaskState::Open)));
+        assert_eq!(task_checkbox("- [x]done"), None);
+        assert_eq!(task_checkbox("- [link](url)"), None);
+        assert_eq!(task_checkbox("▢x"), None);
+    }
+
+    #[test]
+    fn undo_groups_follow_words_and_edit_direction() {
+        use EditClass::*;
+        assert!(!continues_undo_group(None, (true, Word)));
+        assert!(continues_undo_group(Some((true, Word)), (true, Word)));
+        assert!(continues_undo_group(Some((true, Word)), (true, Space)));
+        assert!(continues_undo_group(Some((true, Space)), (true, Space)));
+        // A word after whitespace starts a new group
+        assert!(!continues_undo_group(Some((true, Space)), (true, Word)));
+        assert!(!continues_undo_group(Some((true, Word)), (false, Word)));
+        assert!(!continues_undo_group(Some((false, Word)), (true, Word)));
+        assert!(continues_undo_group(Some((false, Word)), (false, Space)));
+        assert!(!continues_undo_group(Some((true, Word)), (true, Newline)));
+        assert!(!continues_undo_group(Some((true, Newline)), (true, Word)));
+        assert!(!continues_undo_group(Some((true, Word)), (true, Bulk)));
+        
+        assert_eq!(EditClass::of("a"), Word);
+        assert_eq!(EditClass::of("e\u{301}"), Word);
+        assert_eq!(EditClass::of("\t"), Space);
+        assert_eq!(EditClass::of("\r\n"), Newline);
+        assert_eq!(EditClass::of("ab"), Bulk);
+    }
+
+    #[test]
+    fn adjacent_edits_merge_into_one_op() {
+        let insert = |pos, text: &str| EditOp::Insert { pos, text: text.to_string() };
+        let delete = |pos, text: &str| EditOp::Delete { pos, text: text.to_string() };
+        let describe = |ops: &[(EditOp, usize, usize)]| {
+            ops.iter().map(|(op, before, after)| match op {
+                EditOp::Insert { pos, text } => format!("+{}{} {}>{}", pos, text, before, after),
+                EditOp::Delete { pos, text } => format!("-{}{} {}>{}", pos, text, before, after),
+            }).collect::<Vec<_>>()
+        };
+        
+        let mut ops = vec![(insert(0, "a"), 0, 1)];
+        assert!(Editor::merge_into_last_op(&mut ops, &insert(1, "b"), 1, 2));
+        // The caret jumped away
+        assert!(!Editor::merge_into_last_op(&mut ops, &insert(5, "c"), 5, 6));
+        // Not where the last insert ended
+        assert!(!Editor::merge_into_last_op(&mut ops, &insert(0, "c"), 2, 3));
+        // Pastes stay on their own
+        assert!(!Editor::merge_into_last_op(&mut ops, &insert(2, "cd"), 2, 4));
+        assert_eq!(describe(&ops), ["+0ab 0>2"]);
+        
+        // Backspace grows the deletion backwards, Delete forwards
+        let mut ops = vec![(delete(4, "d"), 5, 4)];
+        assert!(Editor::merge_into_last_op(&mut ops, &delete(3, "c"), 4, 3));
+        assert_eq!(describe(&ops), ["-3cd 5>3"]);
+        let mut ops = vec![(delete(3, "c"), 3, 3)];
+        assert!(Editor::merge_into_last_op(&mut ops, &delete(3, "d"), 3, 3));
+        assert_eq!(describe(&ops), ["-3cd 3>3"]);
+        assert!(!Editor::merge_into_last_op(&mut ops, &insert(3, "x"), 3, 4));
+        assert!(!Editor::merge_into_last_op(&mut [], &insert(0, "x"), 0, 1));
+    }
+
+    #[test]
+    fn typing_undoes_a_word_at_a_time() {
+        let mut editor = editor_with("");
+        for ch in "ab cd".chars() {
+            handle_key(&mut editor, event::KeyEvent::new(KeyCode::Char(ch), event::KeyModifiers::NONE), 80, 24).unwrap();
+        }
+        editor.undo();
+        assert_eq!(editor.rope.to_string(), "ab ");
+        editor.undo();
+        assert_eq!(editor.rope.to_string(), "");
+        editor.redo();
+        
+        // Moving the caret ends the group
+        editor.caret = 0;
+        for ch in "xy".chars() {
+            handle_key(&mut editor, event::KeyEvent::new(KeyCode::Char(ch), event::KeyModifiers::NONE), 80, 24).unwrap();
+        }
+        editor.caret = editor.rope.len_bytes();
+        handle_key(&mut editor, event::KeyEvent::new(KeyCode::Char('z'), event::KeyModifiers::NONE), 80, 24).unwrap();
+        assert_eq!(editor.rope.to_string(), "xyab z");
+        editor.undo();
+        assert_eq!(editor.rope.to_string(), "xyab ");
+        
+        // Backspacing groups like typing, a word at a time
+        for _ in 0..3 {
+            handle_key(&mut editor, event::KeyEvent::new(KeyCode::Backspace, event::KeyModifiers::NONE), 80, 24).unwrap();
+        }
+        assert_eq!(editor.rope.to_string(), "xy");
+        editor.undo();
+        assert_eq!(editor.rope.to_string(), "xyab");
+        editor.undo();
+        assert_eq!(editor.rope.to_string(), "xyab ");
+    }
+
+    #[test]
+    fn folds_move_with_edits_and_open_when_touched() {
+        let folded = |editor: &Editor| editor.folds.iter().map(|fold| (fold.start, fold.header_end, fold.end)).collect::<Vec<_>>();
+        let mut editor = editor_with("head {\n  body\n}\n");
+        editor.folds = vec![Fold { start: 0, header_end: 6, end: 14 }];
+        editor.shift_folds(true, 0, "ab");
+        assert_eq!(folded(&editor), [(2, 8, 16)]);
+        // Typing on the header line keeps the fold
+        editor.shift_folds(true, 5, "x");
+        assert_eq!(folded(&editor), [(2, 9, 17)]);
+        editor.shift_folds(false, 5, "x");
+        assert_eq!(folded(&editor), [(2, 8, 16)]);
+        editor.shift_folds(false, 0, "ab");
+        assert_eq!(folded(&editor), [(0, 6, 14)]);
+        // Edits after the fold leave it be
+        editor.shift_folds(true, 15, "tail");
+        assert_eq!(folded(&editor), [(0, 6, 14)]);
+        
+        // Splitting the header or editing the body opens it
+        for (insert, pos, text) in [(true, 3, "\n"), (true, 10, "x"), (false, 5, "d {"), (false, 13, "}")] {
+            editor.folds = vec![Fold { start: 0, header_end: 6, end: 14 }];
+            editor.shift_folds(insert, pos, text);
+            assert!(editor.folds.is_empty(), "{} {:?}", pos, text);
+        }
+    }
+
+    #[test]
+    fn folds_open_once_the_view_follows_a_caret_into_them() {
+        let mut editor = editor_with("head {\n  body\n}\n");
+        editor.folds = vec![Fold { start: 0, header_end: 6, end: 14 }];
+        editor.caret = 9;
+        editor.ensure_visual_lines(80);
+        assert_eq!(editor.folds.len(), 1);
+        editor.update_viewport(10, 80);
+        assert!(editor.folds.is_empty());
+        
+        // A caret on the header row leaves it closed
+        editor.folds = vec![Fold { start: 0, header_end: 6, end: 14 }];
+        editor.caret = 6;
+        editor.update_viewport(10, 80);
+        assert_eq!(editor.folds.len(), 1);
+    }
+
+    #[test]
+    fn fold_regions_end_at_markers_brackets_or_indentation() {
+        let mut editor = editor_with("a {{{\n  b {{{\n  }}}\n}}}\nc {{{\nd");
+        assert_eq!(editor.fold_region_end(0), Some(3));
+        assert_eq!(editor.fold_region_end(1), Some(2));
+        assert_eq!(editor.fold_region_end(4), Some(5));
+        assert_eq!(editor.fold_region_end(5), None);
+        
+        // The closing bracket stays in view when it starts its line
+        let mut editor = editor_with("fn f() {\n    a\n}\nlet x = [\n    1];\n");
+        editor.filename = Some(PathBuf::from("a.rs"));
+        assert_eq!(editor.fold_region_end(0), Some(1));
+        assert_eq!(editor.fold_region_end(3), Some(4));
+        assert_eq!(editor.fold_region_end(1), None);
+        
+        let mut editor = editor_with("section\n  one\n\n    two\nnext\n");
+        assert_eq!(editor.fold_region_end(0), Some(3));
+        assert_eq!(editor.fold_region_end(2), None);
+        assert_eq!(editor.fold_region_end(4), None);
+    }
+
+    #[test]
+    fn graphemes_take_their_display_width() {
+        assert_eq!(grapheme_display_width("a", 0, 4), 1);
+        assert_eq!(grapheme_display_width("中", 0, 4), 2);
+        assert_eq!(grapheme_display_width("e\u{301}", 0, 4), 1);
+        assert_eq!(grapheme_display_width("👨\u{200d}👩\u{200d}👧", 0, 4), 2);
+        assert_eq!(grapheme_display_width("🇩🇪", 0, 4), 2);
+        // Tabs run to the next stop
+        assert_eq!(grapheme_display_width("\t", 0, 4), 4);
+        assert_eq!(grapheme_display_width("\t", 5, 4), 3);
+        assert_eq!(grapheme_display_width("\t", 8, 8), 8);
+        assert_eq!(grapheme_display_width("\t", 3, 0), 1);
+        // Invisible characters take the width of their marker
+        assert_eq!(grapheme_display_width("\u{200b}", 0, 4), 1);
+        assert_eq!(grapheme_display_width("\u{202e}", 0, 4), 1);
+    }
+
+    #[test]
+    fn grapheme_boundaries_keep_clusters_whole() {
+        let text = "ae\u{301}👨\u{200d}👩\u{200d}👧\r\nz";
+        let family = 4;
+        let crlf = family + "👨\u{200d}👩\u{200d}👧".len();
+        assert_eq!(next_grapheme_boundary(text, 0), 1);
+        assert_eq!(next_grapheme_boundary(text, 1), family);
+        assert_eq!(next_grapheme_boundary(text, family), crlf);
+        assert_eq!(next_grapheme_boundary(text, crlf), crlf + 2);
+        assert_eq!(next_grapheme_boundary(text, text.len()), text.len());
+        assert_eq!(prev_grapheme_boundary(text, text.len()), crlf + 2);
+        assert_eq!(prev_grapheme_boundary(text, crlf + 2), crlf);
+        assert_eq!(prev_grapheme_boundary(text, crlf), family);
+        assert_eq!(prev_grapheme_boundary(text, family), 1);
+        assert_eq!(prev_grapheme_boundary(text, 0), 0);
+    }
+
+    #[test]
+    fn word_motion_stops_at_class_changes_and_subwords() {
+        let text = "let parseHTMLString = foo_bar.baz();";
+        let stops = |subword: bool| {
+            let mut stops = Vec::new();
+            let mut pos = 0;
+            while pos < text.len() {
+                pos = next_word_boundary(text, pos, subword);
+                stops.push(pos);
+            }
+            stops
+        };
+        assert_eq!(stops(false), [3, 19, 21, 29, 30, 33, 36]);
+        assert_eq!(stops(true), [3, 9, 13, 19, 21, 25, 29, 30, 33, 36]);
+        
+        assert_eq!(prev_word_boundary(text, 19, false), 4);
+        assert_eq!(prev_word_boundary(text, 19, true), 13);
+        assert_eq!(prev_word_boundary(text, 13, true), 9);
+        assert_eq!(prev_word_boundary(text, 9, true), 4);
+        assert_eq!(prev_word_boundary(text, 29, true), 26);
+        assert_eq!(prev_word_boundary(text, 26, true), 22);
+        assert_eq!(prev_word_boundary(text, 36, false), 33);
+        assert_eq!(prev_word_boundary(text, 2, false), 0);
+        assert_eq!(prev_word_boundary("   ", 3, false), 0);
+        assert_eq!(next_word_boundary("   ", 0, false), 3);
+        
+        // Letters outside ASCII and combining marks stay in the word
+        let text = "für café\u{301} 中文。";
+        assert_eq!(next_word_boundary(text, 0, false), "für".len());
+        assert_eq!(next_word_boundary(text, "für".len(), false), "für café\u{301}".len());
+        assert_eq!(prev_word_boundary(text, text.len(), false), "für café\u{301} 中文".len());
+        assert_eq!(next_word_boundary("ÄrgerÜber", 0, true), "Ärger".len());
+    }
+
+    #[test]
+    fn word_ranges_cover_runs_of_one_class() {
+        let text = "foo_bar  (x)";
+        assert_eq!(word_range_at(text, 0), (0, 7));
+        assert_eq!(word_range_at(text, 5), (0, 7));
+        assert_eq!(word_range_at(text, 7), (7, 9));
+        assert_eq!(word_range_at(text, 9), (9, 10));
+        // Past the end picks the last run
+        assert_eq!(word_range_at(text, 12), (11, 12));
+        assert_eq!(word_range_at("", 0), (0, 0));
+        assert_eq!(word_range_at("naïve é\u{301}", 1), (0, "naïve".len()));
+    }
+
+    #[test]
+    fn natural_order_compares_digit_runs_by_value() {
+        use std::cmp::Ordering;
+        assert_eq!(natural_cmp("file2", "file10"), Ordering::Less);
+        assert_eq!(natural_cmp("File10", "file9"), Ordering::Greater);
+        assert_eq!(natural_cmp("a", "a1"), Ordering::Less);
+        assert_ne!(natural_cmp("file02", "file2"), Ordering::Equal);
+        let mut names = vec!["x10", "x9", "X1", "x100"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, ["X1", "x9", "x10", "x100"]);
+    }
+
+    #[test]
+    fn leading_numbers_parse_after_the_indentation() {
+        assert_eq!(leading_number("  42 apples"), Some(42.0));
+        assert_eq!(leading_number("-3.5e"), Some(-3.5));
+        assert_eq!(leading_number("+7."), Some(7.0));
+        assert_eq!(leading_number("1.2.3"), Some(1.2));
+        assert_eq!(leading_number("- item"), None);
+        assert_eq!(leading_number("v2"), None);
+        assert_eq!(leading_number(""), None);
+    }
+
+    #[test]
+    fn lines_reorder_stably() {
+        let lines = |text: &str| text.split(' ').map(str::to_string).collect::<Vec<_>>();
+        let reordered = |text: &str, order| {
+            let mut lines = lines(text);
+            reorder_lines(&mut lines, order);
+            lines.join(" ")
+        };
+        assert_eq!(reordered("b a B A", LineOrder::Sorted), "A B a b");
+        assert_eq!(reordered("b a B A", LineOrder::CaseInsensitive), "a A b B");
+        assert_eq!(reordered("10 x 9 -1 y 9.5", LineOrder::Numeric), "-1 9 9.5 10 x y");
+        assert_eq!(reordered("f10 f9 F1", LineOrder::Natural), "F1 f9 f10");
+        assert_eq!(reordered("a b a c b", LineOrder::Unique), "a b c");
+        assert_eq!(reordered("a b c", LineOrder::Reversed), "c b a");
+        
+        let mut shuffled = lines("a b c d e f");
+        reorder_lines(&mut shuffled, LineOrder::Shuffled);
+        shuffled.sort();
+        assert_eq!(shuffled, lines("a b c d e f"));
+    }
+
+    #[test]
+    fn joined_lines_are_separated_by_single_spaces() {
+        let lines = |text: &str| text.split('|').map(str::to_string).collect::<Vec<_>>();
+        assert_eq!(join_line_texts(&lines("  one  |    two|\tthree ")), "  one two three");
+        assert_eq!(join_line_texts(&lines("one|   |two")), "one two");
+        assert_eq!(join_line_texts(&lines("|two")), "two");
+        assert_eq!(join_line_texts(&lines("one")), "one");
+    }
+
+    #[test]
+    fn selections_ending_at_a_line_start_leave_that_line_out() {
+        let mut editor = editor_with("a\nb\nc");
+        editor.selection_anchor = Some(0);
+        editor.caret = 2;
+        assert_eq!(editor.selected_line_range(), (0, 0));
+        editor.caret = 3;
+        assert_eq!(editor.selected_line_range(), (0, 1));
+        // An empty selection at a line start is still that line
+        editor.selection_anchor = Some(2);
+        editor.caret = 2;
+        assert_eq!(editor.selected_line_range(), (1, 1));
+        
+        // The selection end moves down with the line before it
+        editor.selection_anchor = Some(0);
+        editor.move_lines_down(80);
+        assert_eq!(editor.rope.to_string(), "b\na\nc");
+        assert_eq!((editor.selection_anchor, editor.caret), (Some(2), 4));
+    }
+
+    #[test]
+    fn duplicated_lines_keep_the_caret_on_the_original() {
+        let mut editor = editor_with("one\ntwo");
+        editor.caret = 5;
+        editor.duplicate_lines(80);
+        assert_eq!((editor.rope.to_string().as_str(), editor.caret), ("one\ntwo\ntwo", 9));
+        
+        editor.selection_anchor = Some(0);
+        editor.caret = 2;
+        editor.duplicate_lines(80);
+        assert_eq!(editor.rope.to_string(), "onone\ntwo\ntwo");
+        assert_eq!((editor.selection_anchor, editor.caret), (Some(2), 4));
+    }
+
+    #[test]
+    fn list_markers_continue_with_the_next_label() {
+        assert_eq!(list_marker("- item"), Some(("- ", "- ".to_string())));
+        assert_eq!(list_marker("* [x] done"), Some(("* [x] ", "* [ ] ".to_string())));
+        assert_eq!(list_marker("◪ doing"), Some(("◪ ", "▢ ".to_string())));
+        assert_eq!(list_marker("9. nine"), Some(("9. ", "10. ".to_string())));
+        assert_eq!(list_marker("b) bee"), Some(("b) ", "c) ".to_string())));
+        assert_eq!(list_marker("Z. last"), Some(("Z. ", "Z. ".to_string())));
+        assert_eq!(list_marker("Note. text"), None);
+        assert_eq!(list_marker("1234567890. big"), None);
+        assert_eq!(list_marker("3.14 pi"), None);
+        assert_eq!(list_marker("-item"), None);
+        
+        // Longer labels still get a hanging indent
+        assert_eq!(Editor::calculate_indent("  Note. text", 4), 6);
+        assert_eq!(Editor::calculate_indent("  b) bee", 4), 6);
+        assert_eq!(Editor::calculate_indent("\t3.14 pi", 4), 4);
+    }
+
+    #[test]
+    fn enter_continues_lists_and_indents_after_openers() {
+        let mut editor = editor_with("  3. item");
+        editor.caret = editor.rope.len_bytes();
+        editor.insert_newline(80);
+        assert_eq!(editor.rope.to_string(), "  3. item\n  4. ");
+        editor.insert_newline(80);
+        assert_eq!(editor.rope.to_string(), "  3. item\n");
+        
+        let mut editor = editor_with("if x:");
+        editor.filename = Some(PathBuf::from("a.py"));
+        editor.caret = editor.rope.len_bytes();
+        editor.insert_newline(80);
+        assert_eq!(editor.rope.to_string(), "if x:\n    ");
+        
+        // Between a pair the closer moves to its own line
+        let mut editor = editor_with("f() {}");
+        editor.filename = Some(PathBuf::from("a.c"));
+        editor.caret = 5;
+        editor.insert_newline(80);
+        assert_eq!(editor.rope.to_string(), "f() {\n    \n}");
+        
+        // Words only open blocks when whole
+        let mut editor = editor_with("undo");
+        editor.filename = Some(PathBuf::from("a.sh"));
+        editor.caret = 4;
+        editor.insert_newline(80);
+        assert_eq!(editor.rope.to_string(), "undo\n");
+    }
+
+    #[test]
+    fn typed_closers_step_over_automatic_ones() {
+        let mut editor = editor_with("");
+        for ch in "f(x".chars() {
+            editor.type_char(ch, 80);
+        }
+        assert_eq!(editor.rope.to_string(), "f(x)");
+        editor.type_char(')', 80);
+        assert_eq!((editor.rope.to_string().as_str(), editor.caret), ("f(x)", 4));
+        
+        // A closer that was already there is typed, not stepped over
+        editor.type_char(')', 80);
+        editor.caret = 4;
+        editor.type_char(')', 80);
+        assert_eq!(editor.rope.to_string(), "f(x)))");
+        
+        // Quotes stay single next to words
+        let mut editor = editor_with("don");
+        editor.caret = 3;
+        editor.type_char('\'', 80);
+        assert_eq!(editor.rope.to_string(), "don'");
+    }
+
+    #[test]
+    fn typed_openers_wrap_the_selection() {
+        let mut editor = editor_with("a word here");
+        editor.selection_anchor = Some(2);
+        editor.caret = 6;
+        editor.type_char('[', 80);
+        assert_eq!(editor.rope.to_string(), "a [word] here");
+        assert_eq!((editor.selection_anchor, editor.caret), (Some(3), 7));
+        editor.type_char('"', 80);
+        assert_eq!(editor.rope.to_string(), "a [\"word\"] here");
+        assert_eq!((editor.selection_anchor, editor.caret), (Some(4), 8));
+        
+        // Rust leaves single quotes to lifetimes
+        editor.filename = Some(PathBuf::from("lib.rs"));
+        editor.type_char('\'', 80);
+        assert_eq!(editor.rope.to_string(), "a [\"'\"] here");
+    }
+
+    #[test]
+    fn languages_describe_pairs_and_openers() {
+        assert_eq!(auto_pairs("rs"), CODE_AUTO_PAIRS);
+        assert!(auto_pairs("js").contains(&('`', '`')));
+        assert!(!auto_pairs("md").contains(&('\'', '\'')));
+        assert_eq!(auto_pairs("unknown"), ALL_AUTO_PAIRS);
+        assert_eq!(indent_openers("py"), [":", "(", "[", "{"]);
+        assert_eq!(indent_openers("txt"), [] as [&str; 0]);
+        assert_eq!(indent_openers("c"), BRACKET_OPENERS);
+        assert_eq!(indent_openers(""), BRACKET_OPENERS);
+    }
+
+    #[test]
+    fn backspace_removes_only_automatic_closers() {
+        let mut editor = editor_with("");
+        editor.type_char('(', 80);
+        assert_eq!(editor.rope.to_string(), "()");
+        editor.backspace(80);
+        assert_eq!(editor.rope.to_string(), "");
+        
+        // A pair that was already there, or typed by hand, keeps its closer
+        let mut editor = editor_with("f()");
+        editor.caret = 2;
+        editor.backspace(80);
+        assert_eq!(editor.rope.to_string(), "f)");
+        let mut editor = editor_with("");
+        editor.auto_pair = false;
+        editor.type_char('"', 80);
+        editor.type_char('"', 80);
+        editor.auto_pair = true;
+        editor.caret = 1;
+        editor.backspace(80);
+        assert_eq!(editor.rope.to_string(), "\"");
+    }
+
+    #[test]
+    fn cycling_a_paste_follows_the_pasted_text() {
+        let mut editor = editor_with("");
+        editor.clipboard = None;
+        editor.clipboard_history = vec!["one".to_string(), "two".to_string(), "three".to_string()];
+        editor.paste(80);
+        assert_eq!(editor.rope.to_string(), "one");
+        
+        // Copying moves "one" down the history without touching the text
+        editor.remember_clipboard("new".to_string());
+        editor.cycle_paste(80);
+        assert_eq!(editor.rope.to_string(), "two");
+        editor.cycle_paste(80);
+        assert_eq!(editor.rope.to_string(), "three");
+        editor.cycle_paste(80);
+        assert_eq!(editor.rope.to_string(), "new");
+    }
+
+    #[test]
+    fn pastes_while_recording_are_flagged() {
+        let mut editor = editor_with("");
+        editor.recording = Some(('a', Vec::new()));
+        handle_paste(&mut editor, "pasted", 80, 24);
+        assert_eq!(editor.rope.to_string(), "pasted");
+        assert_eq!(editor.recording, Some(('a', Vec::new())));
+        assert_eq!(editor.get_macro_info(), " | Recording @a (paste not recorded)");
+        
+        // A new recording starts unflagged
+        let alt_q = event::KeyEvent::new(KeyCode::Char('q'), event::KeyModifiers::ALT);
+        handle_key(&mut editor, alt_q, 80, 24).unwrap();
+        handle_key(&mut editor, alt_q, 80, 24).unwrap();
+        handle_key(&mut editor, event::KeyEvent::new(KeyCode::Char('b'), event::KeyModifiers::NONE), 80, 24).unwrap();
+        assert_eq!(editor.get_macro_info(), " | Recording @b");
+    }
+
+    #[test]
+    fn failed_saves_keep_the_close_prompt_open() {
+        let press = |editor: &mut Editor, code| handle_key(editor, event::KeyEvent::new(code, event::KeyModifiers::NONE), 80, 24).unwrap();
+        let mut editor = editor_with("text");
+        editor.split_pane(Direction::Horizontal);
+        editor.filename = Some(PathBuf::from("/nonexistent/dir/file.txt"));
+        editor.app_state = AppState::Prompting(Prompt::new_confirm_close());
+        press(&mut editor, KeyCode::Char('y'));
+        match &editor.app_state {
+            AppState::Prompting(prompt) => {
+                assert!(matches!(prompt.prompt_type, PromptType::ConfirmClose));
+                assert!(prompt.message.starts_with("Save failed: "));
+            }
+            _ => panic!("the prompt was closed"),
+        }
+        assert_eq!(editor.panes.len(), 2);
+        
+        editor.filename = None;
+        editor.app_state = AppState::Prompting(Prompt::new_save_as_then_close("/nonexistent/dir/other.txt".to_string()));
+        press(&mut editor, KeyCode::Enter);
+        match &editor.app_state {
+            AppState::Prompting(prompt) => {
+                assert!(prompt.closes_pane);
+                assert_eq!(prompt.input, "/nonexistent/dir/other.txt");
+                assert!(prompt.message.starts_with("Save failed: "));
+            }
+            _ => panic!("the prompt was closed"),
+        }
+        assert_eq!(editor.panes.len(), 2);
+    }
+
+    #[test]
+    fn base64_round_trips_and_rejects_bad_input() {
+        assert_eq!(base64_decode("TWE=").unwrap(), b"Ma");
+        assert_eq!(base64_decode("TWE").unwrap(), b"Ma");
+        assert_eq!(base64_decode("TW Fu\n").unwrap(), b"Man");
+        assert_eq!(base64_decode("-_8=").unwrap(), base64_decode("+/8=").unwrap());
+        assert!(base64_decode("TWFuT").is_err());
+        assert!(base64_decode("TW*u").is_err());
+        for text in ["", "M", "Ma", "Man", "héllo wörld"] {
+            assert_eq!(base64_decode(&base64_encode(text.as_bytes())).unwrap(), text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn url_decoding_round_trips_and_rejects_bad_escapes() {
+        assert_eq!(url_decode("a%20b%2Fc").unwrap(), "a b/c");
+        assert!(url_decode("%zz").is_err());
+        assert!(url_decode("100%").is_err());
+        assert!(url_decode("%4").is_err());
+        assert!(url_decode("%ff").is_err());
+        let text = "key=välue & more/~";
+        assert_eq!(url_decode(&url_encode(text)).unwrap(), text);
+    }
+
+    #[test]
+    fn json_unescaping_handles_surrogate_pairs() {
+        assert_eq!(json_unescape(r"\ud83d\ude00").unwrap(), "😀");
+        assert_eq!(json_unescape(r"tab\there é \/").unwrap(), "tab\there é /");
+        assert!(json_unescape(r"\ud800").is_err());
+        assert!(json_unescape(r"\ud800x").is_err());
+        assert!(json_unescape(r"\ud800A").is_err());
+        assert!(json_unescape(r"\udc00").is_err());
+        assert!(json_unescape(r"\u12").is_err());
+        assert!(json_unescape(r"\q").is_err());
+        assert!(json_unescape("dangling\\").is_err());
+        let text = "quote \" backslash \\ newline \n bell \u{7}";
+        assert_eq!(json_unescape(&json_escape(text)).unwrap(), text);
+    }
+
+    #[test]
+    fn integers_parse_and_format_in_every_base() {
+        assert_eq!(parse_integer("-0x10").unwrap(), -16);
+        assert_eq!(parse_integer(" 0B1010 ").unwrap(), 10);
+        assert_eq!(parse_integer("0o17").unwrap(), 15);
+        assert_eq!(parse_integer("+1_000").unwrap(), 1000);
+        assert!(parse_integer("0x").is_err());
+        assert!(parse_integer("12ab").is_err());
+        assert!(parse_integer("").is_err());
+        assert_eq!(format_integer(-16, 16), "-0x10");
+        assert_eq!(format_integer(10, 2), "0b1010");
+        assert_eq!(format_integer(15, 8), "0o17");
+        assert_eq!(format_integer(-7, 10), "-7");
+        for value in [0, 1, -1, 255, -4096, i128::MAX] {
+            for radix in [2, 8, 10, 16] {
+                assert_eq!(parse_integer(&format_integer(value, radix)).unwrap(), value);
+            }
+        }
+    }
+
+    #[test]
+    fn words_split_at_humps_and_punctuation() {
+        assert_eq!(split_words("parseHTTPRequest2"), ["parse", "http", "request2"]);
+        assert_eq!(split_words("snake_case-and kebab"), ["snake", "case", "and", "kebab"]);
+        assert_eq!(split_words("XMLHttp2Request"), ["xml", "http2", "request"]);
+        assert!(split_words("  --  ").is_empty());
+    }
+
+    #[test]
+    fn undo_tree_round_trips_through_its_file_format() {
+        let mut tree = tree_with_steps(2);
+        tree.current = 1;
+        tree.push(UndoGroup {
+            ops: vec![(EditOp::Delete { pos: 3, text: "line\nbreak \\ here\r".to_string() }, 7, 3)],
+            timestamp: UNIX_EPOCH + Duration::from_millis(5),
+        });
+        let path = Path::new("/tmp/a b.txt");
+        let data = tree.serialize(path, 0xabc);
+        let (restored, stored_path, hash) = UndoTree::deserialize(&data).unwrap();
+        assert_eq!((stored_path.as_str(), hash), ("/tmp/a b.txt", 0xabc));
+        assert_eq!(restored.current, 3);
+        assert_eq!(restored.nodes[1].children, [2, 3]);
+        assert_eq!(restored.nodes[1].redo_child, Some(3));
+        assert_eq!(restored.serialize(path, 0xabc), data);
+        match &restored.nodes[3].group.ops[0] {
+            (EditOp::Delete { pos: 3, text }, 7, 3) => assert_eq!(text, "line\nbreak \\ here\r"),
+            other => panic!("unexpected op {:?}", other.0),
+        }
+    }
+
+    #[test]
+    fn undo_tree_files_that_do_not_parse_are_rejected() {
+        let data = tree_with_steps(2).serialize(Path::new("a"), 1);
+        assert!(UndoTree::deserialize(&data.replace("texteditor-undo 1", "texteditor-undo 2")).is_none());
+        assert!(UndoTree::deserialize(&data.replace("current 2", "current 9")).is_none());
+        assert!(UndoTree::deserialize(&data.replace("op I", "op X")).is_none());
+        assert!(UndoTree::deserialize(&format!("{}node 7 0 -\n", data)).is_none());
+        assert!(UndoTree::deserialize(&format!("{}bogus\n", data)).is_none());
+        assert!(UndoTree::deserialize("").is_none());
+    }
+
+    #[test]
+    fn rebasing_drops_everything_outside_the_new_root() {
+        let mut tree = tree_with_steps(3);
+        tree.current = 1;
+        tree.push(UndoGroup { ops: Vec::new(), timestamp: UNIX_EPOCH });
+        tree.current = 3;
+        // 0 - 1 - 2 - 3, with 4 branching off 1; keep from 2 on
+        tree.rebase(2);
+        assert_eq!(tree.nodes.len(), 2);
+        assert_eq!(tree.current, 1);
+        assert!(tree.nodes[0].group.ops.is_empty());
+        assert_eq!(tree.nodes[0].children, [1]);
+        assert_eq!(tree.nodes[0].redo_child, Some(1));
+        assert_eq!(tree.nodes[1].parent, 0);
+        assert_eq!(tree.nodes[1].group.ops.len(), 1);
+    }
+
+    #[test]
+    fn macro_keys_parse_and_round_trip() {
+        use event::{KeyEvent, KeyModifiers};
+        let ctrl = KeyModifiers::CONTROL;
+        assert_eq!(parse_macro_key("x"), Some(KeyEvent::new(KeyCode::Char('x'), KeyModifiers::NONE)));
+        assert_eq!(parse_macro_key("X"), Some(KeyEvent::new(KeyCode::Char('X'), KeyModifiers::SHIFT)));
+        assert_eq!(parse_macro_key("Ctrl+F"), Some(KeyEvent::new(KeyCode::Char('f'), ctrl)));
+        assert_eq!(parse_macro_key("Ctrl+Shift+K"), Some(KeyEvent::new(KeyCode::Char('K'), ctrl | KeyModifiers::SHIFT)));
+        assert_eq!(parse_macro_key("Ctrl++"), Some(KeyEvent::new(KeyCode::Char('+'), ctrl)));
+        assert_eq!(parse_macro_key("Alt+Shift+Up"), Some(KeyEvent::new(KeyCode::Up, KeyModifiers::ALT | KeyModifiers::SHIFT)));
+        assert_eq!(parse_macro_key("F12"), Some(KeyEvent::new(KeyCode::F(12), KeyModifiers::NONE)));
+        assert_eq!(parse_macro_key("Space"), Some(KeyEvent::new(KeyCode::Char(' '), KeyModifiers::NONE)));
+        assert_eq!(parse_macro_key("Ctrl+"), None);
+        assert_eq!(parse_macro_key("Enterr"), None);
+        assert!(parse_macro_keys("Ctrl+f foo Enter").is_err());
+        
+        let text = "Ctrl+f f o o Enter Esc Delete Shift+Tab Alt+. F5 Space X";
+        let keys = parse_macro_keys(text).unwrap();
+        assert_eq!(format_macro_keys(&keys), text);
+    }
+
+    #[test]
+    fn capped_undo_history_leaves_the_tree_alone() {
+        let tree = tree_with_steps(100);
+        let path = Path::new("/tmp/file.txt");
+        let full = tree.serialize(path, 7);
+        let data = tree.serialize_capped(path, 7, full.len() / 4).unwrap();
+        assert!(data.len() <= full.len() / 4);
+        assert_eq!(tree.nodes.len(), 101);
+        assert_eq!(tree.current, 100);
+        assert_eq!(tree.serialize(path, 7), full);
+
+        // Only the oldest steps were dropped, and the newest is current
+        let (trimmed, _, _) = UndoTree::deserialize(&data).unwrap();
+        assert_eq!(trimmed.current, trimmed.nodes.len() - 1);
+        let new_root = 101 - trimmed.nodes.len();
+        assert_eq!(trimmed.nodes[1].group.timestamp, UNIX_EPOCH + Duration::from_millis(new_root as u64));
+        assert_eq!(trimmed.nodes[trimmed.current].group.timestamp, UNIX_EPOCH + Duration::from_millis(99));
+        // Keeping one more step would not have fit
+        let mut longer = tree.clone();
+        longer.rebase(new_root - 1);
+        assert!(longer.serialize(path, 7).len() > full.len() / 4);
+    }
+
+    #[test]
+    fn brackets_in_strings_comments_and_char_literals_are_skipped() {
+        let partners = |text: &str, extension: &str| {
+            scan_brackets(text, language_for(extension).as_ref())
+                .iter()
+                .map(|mark| (mark.pos, mark.partner))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(partners("(a [b] c)", "c"), [(0, Some(8)), (3, Some(5)), (5, Some(3)), (8, Some(0))]);
+        assert_eq!(partners("f(\"(\", '[') // {", "c"), [(1, Some(10)), (10, Some(1))]);
+        assert_eq!(partners("a /* ( */ )", "c"), [(10, None)]);
+        
+        // Rust char literals hide their bracket, lifetimes do not swallow text
+        assert_eq!(partners("if c == '{' { x }", "rs"), [(12, Some(16)), (16, Some(12))]);
+        assert_eq!(partners("'\\u{7b}' '\\'' ('\\\\')", "rs"), [(14, Some(19)), (19, Some(14))]);
+        assert_eq!(partners("fn f<'a>(x: &'a str) {}", "rs"), [(4, Some(7)), (7, Some(4)), (8, Some(19)), (19, Some(8)), (21, Some(22)), (22, Some(21))]);
+        assert!(scan_brackets("if c == '{' {}", language_for("rs").as_ref()).iter().all(|mark| mark.partner.is_some() || !mark.structural));
+        
+        // Unknown files have no strings or comments, and mark nothing structural
+        let marks = scan_brackets("\"(\"", None);
+        assert_eq!(marks.len(), 1);
+        assert!(!marks[0].structural);
+    }
+
+    #[test]
+    fn bracket_partners_pair_openers_with_closers() {
+        for (open, close) in [('(', ')'), ('[', ']'), ('{', '}'), ('<', '>')] {
+            assert_eq!(bracket_partner(open), Some((close, true)));
+            assert_eq!(bracket_partner(close), Some((open, false)));
+        }
+        assert_eq!(bracket_partner('a'), None);
+        assert_eq!(bracket_partner('"'), None);
+    }
+
+    #[test]
+    fn reflow_prefixes_follow_the_file_type() {
+        let rust = reflow_markers("rs");
+        assert_eq!(reflow_prefix("    /// doc text", &rust), ("    /// ", "doc text"));
+        assert_eq!(reflow_prefix("#[derive(Debug)]", &rust), ("", "#[derive(Debug)]"));
+        assert_eq!(reflow_prefix("#include <stdio.h>", &reflow_markers("c")), ("", "#include <stdio.h>"));
+        assert_eq!(reflow_prefix("## comment", &reflow_markers("py")), ("## ", "comment"));
+        assert_eq!(reflow_prefix("-- comment", &reflow_markers("sql")), ("-- ", "comment"));
+        assert_eq!(reflow_prefix("> > quoted", &reflow_markers("md")), ("> > ", "quoted"));
+        assert_eq!(reflow_prefix("# Heading", &reflow_markers("md")), ("", "# Heading"));
+        assert_eq!(reflow_prefix("// text", &reflow_markers("html")), ("", "// text"));
+        assert_eq!(reflow_prefix("// text", &reflow_markers("md")), ("// ", "text"));
+        
+        // Unknown files keep every common marker
+        let unknown = reflow_markers("");
+        for (line, prefix) in [("/// doc", "/// "), ("//! doc", "//! "), ("// c", "// "), ("# c", "# "), ("> q", "> ")] {
+            assert_eq!(reflow_prefix(line, &unknown).0, prefix);
+        }
+    }
+
+    #[test]
+    fn fenced_code_blocks_are_marked() {
+        let lines = ["text", "```rust", "code", "~~~", "```", "after", "~~~~", "```", "~~~", "~~~~", "end"];
+        let fenced = fenced_code_lines(lines);
+        assert_eq!(fenced, [false, true, true, true, true, false, true, true, true, true, false]);
+        assert_eq!(fenced_code_lines(["``inline``", "```", "open to the end"]), [false, true, true]);
+    }
+
+    #[test]
+    fn reflow_wraps_words_under_the_prefix() {
+        let lines = |text: &str| text.lines().map(str::to_string).collect::<Vec<_>>();
+        let markers = reflow_markers("rs");
+        assert_eq!(
+            reflow_lines(&lines("    // one two three\n    // four five"), 18, 4, &markers),
+            ["    // one two", "    // three four", "    // five"],
+        );
+        // A single list item hangs under its text
+        assert_eq!(reflow_lines(&lines("- one two three"), 10, 4, &[]), ["- one two", "    three"]);
+        // Over-long words get a line of their own
+        assert_eq!(reflow_lines(&lines("a verylongword b"), 6, 4, &[]), ["a", "verylongword", "b"]);
+    }
+
+    #[test]
+    fn paragraphs_stop_at_blank_lines_list_items_and_fences() {
+        let mut editor = editor_with("one\ntwo\n\n- item\n    more\n- next\n```\ncode\n```\n> a\n> b\nplain");
+        let markers = reflow_markers("md");
+        let fenced = fenced_code_lines((0..editor.rope.len_lines()).map(|idx| editor.line_text(idx).1));
+        assert_eq!(editor.paragraph_at(1, &markers, &fenced), Some((0, 1)));
+        assert_eq!(editor.paragraph_at(2, &markers, &fenced), None);
+        assert_eq!(editor.paragraph_at(4, &markers, &fenced), Some((3, 4)));
+        assert_eq!(editor.paragraph_at(5, &markers, &fenced), Some((5, 5)));
+        assert_eq!(editor.paragraph_at(7, &markers, &fenced), None);
+        assert_eq!(editor.paragraph_at(9, &markers, &fenced), Some((9, 10)));
+        assert_eq!(editor.paragraph_at(11, &markers, &fenced), Some((11, 11)));
+        
+        // The fence is left as it is
+        editor.filename = Some(PathBuf::from("notes.md"));
+        editor.wrap_column = 80;
+        editor.caret = editor.rope.line_to_byte(7);
+        editor.reflow(80);
+        assert_eq!(editor.line_text(7).1, "code");
+        editor.caret = 0;
+        editor.reflow(80);
+        assert_eq!(editor.line_text(0).1, "one two");
+    }
+
+    #[test]
+    fn undo_history_that_cannot_fit_is_not_stored() {
+        let path = Path::new("a");
+        let mut tree = UndoTree::new();
+        tree.push(UndoGroup {
+            ops: vec![(EditOp::Insert { pos: 0, text: "x".repeat(UNDO_FILE_CAP) }, 0, UNDO_FILE_CAP)],
+            timestamp: UNIX_EPOCH,
+        });
+        assert!(tree.serialize_capped(path, 0, UNDO_FILE_CAP).is_none());
+        
+        // Only redo history, too large as well
+        tree.current = 0;
+        assert!(tree.serialize_capped(path, 0, UNDO_FILE_CAP).is_none());
+        
+        // A small later step still fits once the huge one is dropped
+        tree.current = 1;
+        tree.push(UndoGroup { ops: vec![(EditOp::Insert { pos: 0, text: "y".to_string() }, 0, 1)], timestamp: UNIX_EPOCH });
+        let data = tree.serialize_capped(path, 0, UNDO_FILE_CAP).unwrap();
+        assert!(data.len() <= UNDO_FILE_CAP);
+        assert_eq!(UndoTree::deserialize(&data).unwrap().0.nodes.len(), 2);
+    }
+
+    #[test]
+    fn capped_undo_history_without_edits_is_not_stored() {
+        let tree = UndoTree::new();
+        assert!(tree.serialize_capped(Path::new("a"), 0, UNDO_FILE_CAP).is_none());
+        assert!(tree_with_steps(3).serialize_capped(Path::new("a"), 0, UNDO_FILE_CAP).is_some());
+    }
+}